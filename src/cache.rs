@@ -0,0 +1,418 @@
+//! On-disk cache of refiner responses, so identical runs skip the provider
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use directories::ProjectDirs;
+use ring::digest;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::config::{CacheConfig, ResolvedProvider};
+use crate::providers::{ChatMessage, GenerationParams, ProviderKind};
+use crate::refiner::RefinerResponse;
+
+/// Bumped when the key material or stored format changes, orphaning old
+/// entries instead of misreading them
+const KEY_VERSION: u32 = 1;
+
+/// Everything that shapes a provider's answer. The messages carry the system
+/// prompt, the context file's content and the prompt after any template.
+#[derive(Serialize)]
+struct KeyMaterial<'a> {
+    version: u32,
+    provider: &'a str,
+    kind: ProviderKind,
+    endpoint: &'a str,
+    model: &'a str,
+    template: Option<&'a str>,
+    params: &'a GenerationParams,
+    messages: &'a [ChatMessage],
+}
+
+/// Cache key for sending `messages` to `resolved`: a SHA-256 of the provider,
+/// model, generation settings, template name and the whole conversation
+pub fn cache_key(
+    resolved: &ResolvedProvider,
+    template: Option<&str>,
+    messages: &[ChatMessage],
+) -> String {
+    let material = KeyMaterial {
+        version: KEY_VERSION,
+        provider: &resolved.name,
+        kind: resolved.kind,
+        endpoint: &resolved.endpoint,
+        model: &resolved.model,
+        template,
+        params: &resolved.params,
+        messages,
+    };
+    // Serializing plain structs and strings can't fail
+    let json = serde_json::to_vec(&material).unwrap_or_default();
+    sha256_hex(&json)
+}
+
+/// Whether responses from `resolved` may be cached. The mock provider's are
+/// not, since its fixture can change without the key changing.
+pub fn is_cacheable(resolved: &ResolvedProvider) -> bool {
+    resolved.kind != ProviderKind::Mock
+}
+
+/// Lowercase hex SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    digest::digest(&digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A response served from the cache. It carries no token usage, since
+/// answering from the cache spends none; the original request's counts are
+/// kept only for `prep cache stats`.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub response: RefinerResponse,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Totals for `prep cache stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub path: PathBuf,
+    pub entries: u64,
+    pub size_bytes: u64,
+    /// Requests answered from the cache
+    pub hits: u64,
+    /// Tokens those requests would have used
+    pub tokens_saved: u64,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+}
+
+/// Response cache database
+pub struct ResponseCache {
+    conn: Connection,
+    settings: CacheConfig,
+}
+
+impl ResponseCache {
+    /// Get the database path
+    pub fn db_path() -> Result<PathBuf> {
+        let dirs = ProjectDirs::from("com", "prep", "prep")
+            .context("Could not determine cache directory")?;
+        let cache_dir = dirs.cache_dir();
+        std::fs::create_dir_all(cache_dir).with_context(|| {
+            format!("Failed to create cache directory: {}", cache_dir.display())
+        })?;
+        Ok(cache_dir.join("responses.db"))
+    }
+
+    /// Open or create the cache database
+    pub fn open(settings: &CacheConfig) -> Result<Self> {
+        let path = Self::db_path()?;
+        let conn = Connection::open(&path)
+            .with_context(|| format!("Failed to open cache database: {}", path.display()))?;
+        Self::with_connection(conn, settings)
+    }
+
+    /// Use `conn` as the cache database, creating the table if needed
+    fn with_connection(conn: Connection, settings: &CacheConfig) -> Result<Self> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS responses (
+                key TEXT PRIMARY KEY,
+                provider TEXT NOT NULL,
+                model TEXT NOT NULL,
+                response TEXT NOT NULL,
+                prompt_tokens INTEGER,
+                completion_tokens INTEGER,
+                size INTEGER NOT NULL,
+                hits INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                last_used INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        Ok(Self {
+            conn,
+            settings: settings.clone(),
+        })
+    }
+
+    /// Look up a response, counting the hit. Entries older than the TTL are
+    /// treated as missing.
+    pub fn get(&self, key: &str) -> Result<Option<CachedResponse>> {
+        let now = Utc::now().timestamp();
+        let row = self
+            .conn
+            .query_row(
+                "SELECT response, created_at FROM responses WHERE key = ?1 AND created_at > ?2",
+                params![key, now - self.settings.ttl_secs as i64],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()?;
+
+        let Some((json, created_at)) = row else {
+            return Ok(None);
+        };
+        let response: RefinerResponse =
+            serde_json::from_str(&json).context("Corrupt cache entry")?;
+
+        self.conn.execute(
+            "UPDATE responses SET hits = hits + 1, last_used = ?2 WHERE key = ?1",
+            params![key, now],
+        )?;
+
+        Ok(Some(CachedResponse {
+            response,
+            created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_else(Utc::now),
+        }))
+    }
+
+    /// Store a response, then drop expired entries and the least recently
+    /// used ones beyond the size limits
+    pub fn put(
+        &self,
+        key: &str,
+        resolved: &ResolvedProvider,
+        response: &RefinerResponse,
+    ) -> Result<()> {
+        let json = serde_json::to_string(response)?;
+        let now = Utc::now().timestamp();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO responses (key, provider, model, response, prompt_tokens, \
+             completion_tokens, size, created_at, last_used) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)",
+            params![
+                key,
+                resolved.name,
+                resolved.model,
+                json,
+                response.usage.map(|u| u.prompt_tokens as i64),
+                response.usage.map(|u| u.completion_tokens as i64),
+                json.len() as i64,
+                now,
+            ],
+        )?;
+
+        self.evict()
+    }
+
+    /// Enforce the TTL, `max_entries` and `max_size_mb`
+    fn evict(&self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        self.conn.execute(
+            "DELETE FROM responses WHERE created_at <= ?1",
+            params![now - self.settings.ttl_secs as i64],
+        )?;
+        self.conn.execute(
+            "DELETE FROM responses WHERE key IN (
+                SELECT key FROM responses ORDER BY last_used DESC LIMIT -1 OFFSET ?1
+            )",
+            params![self.settings.max_entries as i64],
+        )?;
+        self.conn.execute(
+            "DELETE FROM responses WHERE key IN (
+                SELECT key FROM (
+                    SELECT key, SUM(size) OVER (ORDER BY last_used DESC, key) AS total
+                    FROM responses
+                ) WHERE total > ?1
+            )",
+            params![(self.settings.max_size_mb * 1024 * 1024) as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Entry counts and sizes
+    pub fn stats(&self) -> Result<CacheStats> {
+        let (entries, size_bytes, hits, tokens_saved, oldest, newest) = self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0), COALESCE(SUM(hits), 0), \
+             COALESCE(SUM(hits * (COALESCE(prompt_tokens, 0) + COALESCE(completion_tokens, 0))), 0), \
+             MIN(created_at), MAX(created_at) FROM responses",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, Option<i64>>(4)?,
+                    row.get::<_, Option<i64>>(5)?,
+                ))
+            },
+        )?;
+
+        Ok(CacheStats {
+            path: Self::db_path()?,
+            entries: entries as u64,
+            size_bytes: size_bytes as u64,
+            hits: hits as u64,
+            tokens_saved: tokens_saved as u64,
+            oldest: oldest.and_then(|t| DateTime::from_timestamp(t, 0)),
+            newest: newest.and_then(|t| DateTime::from_timestamp(t, 0)),
+        })
+    }
+
+    /// Remove every entry
+    pub fn clear(&self) -> Result<usize> {
+        let count = self.conn.execute("DELETE FROM responses", [])?;
+        self.conn.execute("VACUUM", [])?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn ollama() -> ResolvedProvider {
+        Config::default()
+            .resolve_provider(Some("ollama"), None, None)
+            .unwrap()
+    }
+
+    fn conversation(prompt: &str) -> Vec<ChatMessage> {
+        vec![ChatMessage::system("Refine it"), ChatMessage::user(prompt)]
+    }
+
+    fn response(prompt: &str) -> RefinerResponse {
+        RefinerResponse {
+            refined_prompt: prompt.to_string(),
+            needs_clarification: false,
+            questions: Vec::new(),
+            usage: None,
+        }
+    }
+
+    fn memory_cache(settings: CacheConfig) -> ResponseCache {
+        ResponseCache::with_connection(Connection::open_in_memory().unwrap(), &settings).unwrap()
+    }
+
+    fn keys(cache: &ResponseCache) -> Vec<String> {
+        let mut query = cache
+            .conn
+            .prepare("SELECT key FROM responses ORDER BY key")
+            .unwrap();
+        query
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    /// Pretend `key` was last used `secs` seconds ago
+    fn age(cache: &ResponseCache, key: &str, secs: i64) {
+        let then = Utc::now().timestamp() - secs;
+        cache
+            .conn
+            .execute(
+                "UPDATE responses SET created_at = ?2, last_used = ?2 WHERE key = ?1",
+                params![key, then],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn cache_key_ignores_the_order_settings_were_written_in() {
+        let mut a = ollama();
+        a.params = toml::from_str("temperature = 0.2\nseed = 7\nstop = [\"END\"]").unwrap();
+        let mut b = ollama();
+        b.params = toml::from_str("stop = [\"END\"]\nseed = 7\ntemperature = 0.2").unwrap();
+        let messages = conversation("write a poem");
+
+        assert_eq!(
+            cache_key(&a, Some("code"), &messages),
+            cache_key(&b, Some("code"), &messages)
+        );
+    }
+
+    #[test]
+    fn cache_key_changes_with_what_shapes_the_answer() {
+        let base = ollama();
+        let messages = conversation("write a poem");
+        let key = cache_key(&base, None, &messages);
+
+        let mut endpoint = ollama();
+        endpoint.endpoint = "http://gpu-box:11434".to_string();
+        let mut model = ollama();
+        model.model = "qwen2.5".to_string();
+        let mut params = ollama();
+        params.params.temperature = Some(0.9);
+        let mut name = ollama();
+        name.name = "ollama-gpu".to_string();
+
+        let variants = [
+            ("endpoint", cache_key(&endpoint, None, &messages)),
+            ("model", cache_key(&model, None, &messages)),
+            ("params", cache_key(&params, None, &messages)),
+            ("provider name", cache_key(&name, None, &messages)),
+            ("template", cache_key(&base, Some("code"), &messages)),
+            ("other template", cache_key(&base, Some("debug"), &messages)),
+            (
+                "prompt",
+                cache_key(&base, None, &conversation("write a song")),
+            ),
+        ];
+        for (what, variant) in &variants {
+            assert_ne!(variant, &key, "{} did not change the key", what);
+        }
+        assert_ne!(variants[4].1, variants[5].1);
+    }
+
+    #[test]
+    fn expired_entries_are_missing_and_evicted() {
+        let cache = memory_cache(CacheConfig {
+            ttl_secs: 60,
+            ..CacheConfig::default()
+        });
+        let resolved = ollama();
+        cache.put("old", &resolved, &response("Old")).unwrap();
+        cache.put("new", &resolved, &response("New")).unwrap();
+        age(&cache, "old", 61);
+
+        assert!(cache.get("old").unwrap().is_none());
+        let hit = cache.get("new").unwrap().unwrap();
+        assert_eq!(hit.response.refined_prompt, "New");
+
+        cache.put("newer", &resolved, &response("Newer")).unwrap();
+        assert_eq!(keys(&cache), ["new", "newer"]);
+    }
+
+    #[test]
+    fn least_recently_used_entries_go_beyond_max_entries() {
+        let cache = memory_cache(CacheConfig {
+            max_entries: 2,
+            ..CacheConfig::default()
+        });
+        let resolved = ollama();
+        cache.put("a", &resolved, &response("A")).unwrap();
+        cache.put("b", &resolved, &response("B")).unwrap();
+        age(&cache, "a", 20);
+        age(&cache, "b", 30);
+        // A hit makes "b" the most recently used of the two
+        cache.get("b").unwrap();
+
+        cache.put("c", &resolved, &response("C")).unwrap();
+        assert_eq!(keys(&cache), ["b", "c"]);
+    }
+
+    #[test]
+    fn least_recently_used_entries_go_beyond_max_size() {
+        let cache = memory_cache(CacheConfig {
+            max_size_mb: 1,
+            ..CacheConfig::default()
+        });
+        let resolved = ollama();
+        let large = "x".repeat(400 * 1024);
+        cache.put("a", &resolved, &response(&large)).unwrap();
+        cache.put("b", &resolved, &response(&large)).unwrap();
+        age(&cache, "a", 20);
+        age(&cache, "b", 10);
+        assert_eq!(keys(&cache), ["a", "b"]);
+
+        cache.put("c", &resolved, &response(&large)).unwrap();
+        assert_eq!(keys(&cache), ["b", "c"]);
+    }
+}
@@ -0,0 +1,118 @@
+//! Answering the refiner's clarifying questions
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::ui::UI;
+
+/// Whether the handler answered the questions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClarificationReply {
+    /// The answers were pushed and the prompt should be refined again
+    Answered,
+    /// Refine with the answers pushed so far, treating the rest as skipped,
+    /// and ask no further questions
+    Stop,
+    /// Keep the current refinement without answering
+    Skipped,
+}
+
+/// Supplies answers when the model asks clarifying questions
+#[async_trait]
+pub trait ClarificationHandler: Send + Sync {
+    /// Answer the questions of clarification `round` (from 1), pushing each
+    /// answer onto `answers` as soon as it is given so that answers collected
+    /// before an interruption can be saved. An empty answer skips that
+    /// question, leaving it to the model's judgement.
+    ///
+    /// Return [`Interrupted`](crate::ui::Interrupted) to abandon the
+    /// refinement; it is saved to history as a draft.
+    async fn answer(
+        &self,
+        round: u32,
+        questions: &[String],
+        answers: &mut Vec<String>,
+    ) -> Result<ClarificationReply>;
+}
+
+/// Asks the user in the terminal
+pub struct InteractiveClarifier {
+    ui: Arc<UI>,
+}
+
+impl InteractiveClarifier {
+    pub fn new(ui: Arc<UI>) -> Self {
+        Self { ui }
+    }
+}
+
+#[async_trait]
+impl ClarificationHandler for InteractiveClarifier {
+    async fn answer(
+        &self,
+        round: u32,
+        questions: &[String],
+        answers: &mut Vec<String>,
+    ) -> Result<ClarificationReply> {
+        if self.ui.ask_questions(round, questions, answers)? {
+            Ok(ClarificationReply::Answered)
+        } else {
+            Ok(ClarificationReply::Stop)
+        }
+    }
+}
+
+/// Answers from a fixed list, in order across rounds, for tools and tests.
+/// Once the list runs out the remaining questions are skipped, and later
+/// rounds keep the refinement as it is.
+pub struct ScriptedClarifier {
+    answers: Mutex<VecDeque<String>>,
+}
+
+impl ScriptedClarifier {
+    pub fn new(answers: Vec<String>) -> Self {
+        Self {
+            answers: Mutex::new(answers.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ClarificationHandler for ScriptedClarifier {
+    async fn answer(
+        &self,
+        _round: u32,
+        questions: &[String],
+        answers: &mut Vec<String>,
+    ) -> Result<ClarificationReply> {
+        let mut remaining = self
+            .answers
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Scripted answers are poisoned"))?;
+        if remaining.is_empty() {
+            return Ok(ClarificationReply::Skipped);
+        }
+        for _ in questions {
+            answers.push(remaining.pop_front().unwrap_or_default());
+        }
+        Ok(ClarificationReply::Answered)
+    }
+}
+
+/// Never answers, keeping the initial refinement. The default, and what the
+/// CLI uses when stdin isn't a terminal.
+pub struct AutoSkip;
+
+#[async_trait]
+impl ClarificationHandler for AutoSkip {
+    async fn answer(
+        &self,
+        _round: u32,
+        _questions: &[String],
+        _answers: &mut Vec<String>,
+    ) -> Result<ClarificationReply> {
+        Ok(ClarificationReply::Skipped)
+    }
+}
@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 use std::path::PathBuf;
 
@@ -26,9 +27,29 @@ pub struct Cli {
     #[arg(trailing_var_arg = true)]
     pub prompt: Vec<String>,
 
-    /// AI provider to use
-    #[arg(short, long, value_enum, env = "PREP_PROVIDER")]
-    pub provider: Option<ProviderChoice>,
+    /// AI provider to use: a built-in (ollama, ollama-cloud, openai, anthropic,
+    /// gemini, bedrock, llamacpp, openai-compatible, azure-openai) or any
+    /// [providers.<name>] entry from the config
+    #[arg(short, long, env = "PREP_PROVIDER", value_name = "NAME")]
+    pub provider: Option<String>,
+
+    /// Providers to try in order if the primary one is unavailable
+    /// (overrides default.fallback)
+    #[arg(long, value_name = "NAMES", value_delimiter = ',')]
+    pub fallback: Vec<String>,
+
+    /// Refine with several providers at once and compare the results side by
+    /// side, e.g. `ollama,openai:gpt-4o-mini` (PROVIDER or PROVIDER:MODEL);
+    /// ignores --provider and can't be combined with the other options that
+    /// pick or configure a single provider
+    #[arg(
+        long,
+        value_name = "TARGETS",
+        value_delimiter = ',',
+        num_args = 1,
+        conflicts_with_all = ["fallback", "api_key", "stream", "no_cache"]
+    )]
+    pub compare: Vec<String>,
 
     /// Model name to use (overrides provider default)
     #[arg(short, long, env = "PREP_MODEL")]
@@ -38,6 +59,14 @@ pub struct Cli {
     #[arg(long, hide = true)]
     pub api_key: Option<String>,
 
+    /// Sampling temperature (overrides the provider's config)
+    #[arg(long, value_name = "T")]
+    pub temperature: Option<f32>,
+
+    /// Seed for reproducible sampling (overrides the provider's config)
+    #[arg(long, value_name = "N")]
+    pub seed: Option<i64>,
+
     /// Output format
     #[arg(short, long, value_enum, default_value = "text")]
     pub output: OutputFormat,
@@ -50,6 +79,11 @@ pub struct Cli {
     #[arg(long, value_name = "FILE")]
     pub context: Option<PathBuf>,
 
+    /// System prompt to refine with: a [system_prompts] entry, "default" or a
+    /// file (overrides default.system_prompt)
+    #[arg(long, value_name = "NAME|FILE")]
+    pub system_prompt: Option<String>,
+
     /// Use a prompt template
     #[arg(short, long)]
     pub template: Option<String>,
@@ -70,6 +104,46 @@ pub struct Cli {
     #[arg(long)]
     pub no_history: bool,
 
+    /// Stream the refined prompt as it is generated
+    #[arg(short, long)]
+    pub stream: bool,
+
+    /// Don't read or write the response cache
+    #[arg(long, conflicts_with = "refresh")]
+    pub no_cache: bool,
+
+    /// Skip cached responses but cache the new one
+    #[arg(long)]
+    pub refresh: bool,
+
+    /// Most rounds of clarifying questions before refining with what is known
+    /// (overrides clarification.max_rounds; 0 never asks)
+    #[arg(long, value_name = "N")]
+    pub max_rounds: Option<u32>,
+
+    /// Have a judge model score the refinement against the original prompt
+    /// and save the scores to history
+    #[arg(long, conflicts_with = "compare")]
+    pub score: bool,
+
+    /// Provider that scores refinements, as PROVIDER or PROVIDER:MODEL
+    /// (overrides the [judge] config)
+    #[arg(long, value_name = "PROVIDER")]
+    pub judge: Option<String>,
+
+    /// Record provider HTTP exchanges to a cassette file
+    #[arg(
+        long,
+        env = "PREP_RECORD",
+        value_name = "FILE",
+        conflicts_with = "replay"
+    )]
+    pub record: Option<PathBuf>,
+
+    /// Serve provider responses from a recorded cassette instead of the network
+    #[arg(long, env = "PREP_REPLAY", value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -91,6 +165,28 @@ pub enum Commands {
         #[command(subcommand)]
         action: TemplateAction,
     },
+    /// List the models a provider offers
+    Models {
+        /// Provider to query (defaults to the configured provider)
+        #[arg(short, long, value_name = "NAME")]
+        provider: Option<String>,
+    },
+    /// Diagnose configuration, API keys, endpoints and models
+    Doctor {
+        /// Probe every registered provider, not just the default and fallbacks
+        #[arg(long)]
+        all: bool,
+    },
+    /// Inspect or clear the response cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Score a refinement from history with the judge model
+    Score {
+        /// History entry ID
+        id: i64,
+    },
     /// Generate shell completions
     Completions {
         /// Shell to generate completions for
@@ -151,6 +247,14 @@ pub enum HistoryAction {
     },
 }
 
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    /// Show the number, size and hits of cached responses
+    Stats,
+    /// Remove every cached response
+    Clear,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum TemplateAction {
     /// List available templates
@@ -167,30 +271,25 @@ pub enum TemplateAction {
     },
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
-pub enum ProviderChoice {
-    /// Local Ollama instance
-    #[value(name = "ollama", alias = "local")]
-    OllamaLocal,
-    /// Ollama Cloud API
-    #[value(name = "ollama-cloud", alias = "cloud")]
-    OllamaCloud,
-    /// OpenAI API
-    #[value(name = "openai", alias = "gpt")]
-    OpenAI,
-    /// Anthropic API
-    #[value(name = "anthropic", alias = "claude")]
-    Anthropic,
-}
-
-impl std::fmt::Display for ProviderChoice {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::OllamaLocal => write!(f, "ollama"),
-            Self::OllamaCloud => write!(f, "ollama-cloud"),
-            Self::OpenAI => write!(f, "openai"),
-            Self::Anthropic => write!(f, "anthropic"),
+impl Cli {
+    /// Parse the command line, exiting on errors like [`Parser::parse`].
+    ///
+    /// `--model` conflicts with `--compare` only when it is passed as a flag,
+    /// so a `PREP_MODEL` in the environment doesn't rule out comparisons.
+    pub fn parse_args() -> Self {
+        let mut command = Self::command();
+        let matches = command.get_matches_mut();
+        if matches.value_source("model") == Some(ValueSource::CommandLine)
+            && matches.contains_id("compare")
+        {
+            command
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "the argument '--compare <TARGETS>' cannot be used with '--model <MODEL>'",
+                )
+                .exit();
         }
+        Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
     }
 }
 
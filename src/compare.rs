@@ -0,0 +1,252 @@
+//! `--compare`: refine one prompt with several providers side by side
+
+use crate::config::{Config, ResolvedProvider};
+use crate::history::Candidate;
+use crate::providers::{build_provider, ChatMessage};
+use crate::refiner::{RefineStats, RefinerResponse};
+use colored::*;
+use console::{measure_text_width, pad_str, Alignment};
+use futures_util::future::join_all;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Instant;
+
+/// Columns narrower than this are stacked vertically instead
+const MIN_COLUMN_WIDTH: usize = 28;
+
+/// Separator between columns
+const GUTTER: &str = " │ ";
+
+/// One provider's answer
+#[derive(Debug, Serialize)]
+pub struct CompareResult {
+    pub provider: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub response: Option<RefinerResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub usage: RefineStats,
+}
+
+impl CompareResult {
+    /// Column title: the registry name and model
+    pub fn label(&self) -> String {
+        format!("{} ({})", self.provider, self.model)
+    }
+
+    /// The history record for this result
+    pub fn to_candidate(&self, chosen: bool) -> Candidate {
+        Candidate {
+            provider: self.provider.clone(),
+            model: self.model.clone(),
+            refined_prompt: self.response.as_ref().map(|r| r.refined_prompt.clone()),
+            error: self.error.clone(),
+            stats: self.usage.clone(),
+            chosen,
+        }
+    }
+}
+
+/// Split a `--compare` entry into a provider name and an optional model, as
+/// in `ollama:llama3.2:3b`. Only the first `:` separates them, since model
+/// names may contain more.
+pub fn parse_target(spec: &str) -> (&str, Option<&str>) {
+    match spec.split_once(':') {
+        Some((name, model)) if !model.is_empty() => (name, Some(model)),
+        Some((name, _)) => (name, None),
+        None => (spec, None),
+    }
+}
+
+/// Refine `messages` with every provider at once. Failures are kept in the
+/// results rather than aborting the others, and there is no fallback.
+pub async fn run(
+    targets: &[ResolvedProvider],
+    messages: &[ChatMessage],
+    config: &Config,
+) -> Vec<CompareResult> {
+    join_all(targets.iter().map(|resolved| async move {
+        let started = Instant::now();
+        let result = match build_provider(resolved) {
+            Ok(provider) => provider.refine(messages).await,
+            Err(e) => Err(e),
+        };
+        let latency = started.elapsed();
+
+        match result {
+            Ok(response) => {
+                let cost = response
+                    .usage
+                    .zip(config.price_for(&resolved.model))
+                    .map(|(usage, price)| price.cost(&usage));
+                CompareResult {
+                    provider: resolved.name.clone(),
+                    model: resolved.model.clone(),
+                    usage: RefineStats::new(response.usage, latency, cost),
+                    response: Some(response),
+                    error: None,
+                }
+            }
+            Err(e) => CompareResult {
+                provider: resolved.name.clone(),
+                model: resolved.model.clone(),
+                response: None,
+                error: Some(format!("{:#}", e)),
+                usage: RefineStats::new(None, latency, None),
+            },
+        }
+    }))
+    .await
+}
+
+/// Lay the results out side by side in `width` columns, or one after another
+/// when the terminal is too narrow. Words only one provider used are
+/// highlighted when `color` is on.
+pub fn render(results: &[CompareResult], width: usize, color: bool) -> String {
+    let vocabularies: Vec<HashSet<String>> = results
+        .iter()
+        .map(|result| {
+            result
+                .response
+                .as_ref()
+                .map(|r| r.refined_prompt.split_whitespace().map(normalize).collect())
+                .unwrap_or_default()
+        })
+        .collect();
+    let answered = results.iter().filter(|r| r.response.is_some()).count();
+
+    let gutters = measure_text_width(GUTTER) * results.len().saturating_sub(1);
+    let column_width = width.saturating_sub(gutters) / results.len().max(1);
+    let stacked = column_width < MIN_COLUMN_WIDTH;
+    let column_width = if stacked { width } else { column_width };
+
+    let columns: Vec<Vec<String>> = results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            let others: HashSet<&String> = vocabularies
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .flat_map(|(_, words)| words)
+                .collect();
+            let highlight = color && answered > 1;
+            column(result, column_width, color, |word| {
+                let word = normalize(word);
+                highlight && !word.is_empty() && !others.contains(&word)
+            })
+        })
+        .collect();
+
+    if stacked {
+        return columns
+            .iter()
+            .map(|lines| lines.join("\n"))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+    }
+
+    let height = columns.iter().map(Vec::len).max().unwrap_or(0);
+    let gutter = if color {
+        GUTTER.bright_black().to_string()
+    } else {
+        GUTTER.to_string()
+    };
+    (0..height)
+        .map(|row| {
+            columns
+                .iter()
+                .map(|lines| {
+                    let line = lines.get(row).map(String::as_str).unwrap_or_default();
+                    pad_str(line, column_width, Alignment::Left, None).into_owned()
+                })
+                .collect::<Vec<_>>()
+                .join(&gutter)
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The lines of one result's column
+fn column(
+    result: &CompareResult,
+    width: usize,
+    color: bool,
+    unique: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let paint = |text: &str, style: fn(ColoredString) -> ColoredString| {
+        if color {
+            style(text.normal()).to_string()
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut lines = Vec::new();
+    for line in textwrap::wrap(&result.label(), width) {
+        lines.push(paint(&line, |s| s.bright_cyan().bold()));
+    }
+    lines.push(paint(&summary(&result.usage), |s| s.bright_black()));
+    lines.push(paint(&"─".repeat(width), |s| s.bright_black()));
+
+    match (&result.response, &result.error) {
+        (Some(response), _) => {
+            for paragraph in response.refined_prompt.lines() {
+                for line in textwrap::wrap(paragraph, width) {
+                    let words = line
+                        .split(' ')
+                        .map(|word| {
+                            if unique(word) {
+                                paint(word, |s| s.bright_yellow().bold())
+                            } else {
+                                word.to_string()
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    lines.push(words.join(" "));
+                }
+            }
+            if response.needs_clarification && !response.questions.is_empty() {
+                lines.push(String::new());
+                lines.push(paint("Would ask:", |s| s.bright_black()));
+                for question in &response.questions {
+                    let options = textwrap::Options::new(width)
+                        .initial_indent("• ")
+                        .subsequent_indent("  ");
+                    for line in textwrap::wrap(question, options) {
+                        lines.push(line.into_owned());
+                    }
+                }
+            }
+        }
+        (None, error) => {
+            let error = format!("✗ {}", error.as_deref().unwrap_or("No response"));
+            for line in textwrap::wrap(&error, width) {
+                lines.push(paint(&line, |s| s.red()));
+            }
+        }
+    }
+
+    lines
+}
+
+/// Latency, tokens and cost on one line
+fn summary(stats: &RefineStats) -> String {
+    let mut parts = vec![format!("{:.2}s", stats.latency_ms as f64 / 1000.0)];
+    if let Some(total) = stats.total_tokens {
+        parts.push(format!("{} tokens", total));
+    }
+    if let Some(cost) = stats.cost_usd {
+        parts.push(format!("${:.4}", cost));
+    }
+    parts.join(" · ")
+}
+
+/// Compare words without case or surrounding punctuation
+fn normalize(word: &str) -> String {
+    word.trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase()
+}
@@ -1,10 +1,17 @@
 use anyhow::{Context, Result};
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::cli::ProviderChoice;
+use crate::providers::{
+    ChatTemplate, GenerationParams, GrammarMode, HttpSettings, ProviderKind, RetryPolicy,
+    Transport, HTTP_FIELDS, PARAM_FIELDS,
+};
+use crate::refiner::Usage;
+use crate::system_prompt::{SystemPrompt, SystemPromptConfig, BUILTIN_NAME};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Config {
@@ -16,6 +23,39 @@ pub struct Config {
     pub ui: UiConfig,
     #[serde(default)]
     pub history: HistoryConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub clarification: ClarificationConfig,
+    /// Named alternatives to the built-in system prompt
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub system_prompts: BTreeMap<String, SystemPromptConfig>,
+    /// HTTP client settings shared by all providers
+    #[serde(default, skip_serializing_if = "HttpSettings::is_empty")]
+    pub http: HttpSettings,
+    /// Provider that scores refinements for `--score` and `prep score`
+    #[serde(default, skip_serializing_if = "JudgeConfig::is_empty")]
+    pub judge: JudgeConfig,
+    /// Per-model prices for cost estimates, keyed by model name or prefix
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pricing: BTreeMap<String, ModelPrice>,
+}
+
+/// Price of a model in USD per million tokens
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ModelPrice {
+    #[serde(default)]
+    pub input: f64,
+    #[serde(default)]
+    pub output: f64,
+}
+
+impl ModelPrice {
+    /// Estimated cost in USD of the given usage
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.input + usage.completion_tokens as f64 * self.output)
+            / 1_000_000.0
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,6 +68,12 @@ pub struct DefaultConfig {
     pub output_format: String,
     #[serde(default)]
     pub copy_to_clipboard: bool,
+    /// Providers to try, in order, when the primary one is unavailable
+    #[serde(default)]
+    pub fallback: Vec<String>,
+    /// `[system_prompts]` entry or file used instead of the built-in prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
 }
 
 fn default_provider() -> String {
@@ -49,139 +95,380 @@ impl Default for DefaultConfig {
             model: default_model(),
             output_format: default_output_format(),
             copy_to_clipboard: false,
+            fallback: Vec::new(),
+            system_prompt: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct ProvidersConfig {
-    #[serde(default, rename = "ollama-local")]
-    pub ollama_local: OllamaLocalConfig,
-    #[serde(default, rename = "ollama-cloud")]
-    pub ollama_cloud: OllamaCloudConfig,
-    #[serde(default)]
-    pub openai: OpenAIConfig,
-    #[serde(default)]
-    pub anthropic: AnthropicConfig,
-}
-
+/// Registry of named providers, keyed by the name passed to `--provider`.
+///
+/// Built-in entries are always present; `[providers.<name>]` tables in the
+/// config file override them or add new ones. Only those overrides and
+/// additions are written back.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct OllamaLocalConfig {
-    #[serde(default = "default_ollama_local_endpoint")]
-    pub endpoint: String,
+#[serde(
+    from = "BTreeMap<String, ProviderConfig>",
+    into = "BTreeMap<String, ProviderConfig>"
+)]
+pub struct ProvidersConfig(BTreeMap<String, ProviderConfig>);
+
+/// A single `[providers.<name>]` table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct ProviderConfig {
+    /// Backend implementation; inferred from the table name for built-ins
+    pub kind: Option<ProviderKind>,
+    pub endpoint: Option<String>,
     pub model: Option<String>,
+    pub api_key: Option<String>,
+    /// Environment variable to read the API key from
+    pub api_key_env: Option<String>,
+    /// Display name used in output and error messages
+    pub name: Option<String>,
+    /// Send `response_format: json_object` (openai-compatible and
+    /// azure-openai)
+    pub json_mode: Option<bool>,
+    /// Use the provider's native structured output (response schema or forced
+    /// tool call); on by default except for openai-compatible
+    pub structured_output: Option<bool>,
+    /// Extra query parameters appended to every request
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+    /// Total request attempts on transient failures (1 disables retries)
+    pub max_attempts: Option<u32>,
+    /// Stop retrying once this many seconds have passed
+    pub retry_deadline_secs: Option<u64>,
+    /// Scripted responses file (mock only)
+    pub fixture: Option<PathBuf>,
+    /// Azure resource name, filling `{resource}` in the default endpoint
+    /// (azure-openai only)
+    pub resource: Option<String>,
+    /// Azure deployment to route requests to; used as the model name
+    /// (azure-openai only)
+    pub deployment: Option<String>,
+    /// Azure `api-version` query parameter (azure-openai only)
+    pub api_version: Option<String>,
+    /// AWS region, filling `{region}` in the default endpoint and used for
+    /// request signing; defaults to `AWS_REGION` (bedrock only)
+    pub region: Option<String>,
+    /// Shared credentials file profile to sign requests with, instead of the
+    /// AWS environment variables (bedrock only)
+    pub profile: Option<String>,
+    /// How the chat is rendered into a prompt: `server` (the model's own
+    /// template), `chatml`, `llama3` or `gemma` (llamacpp only)
+    pub chat_template: Option<ChatTemplate>,
+    /// Constrain output with a generated GBNF `gbnf` grammar or the
+    /// `json_schema` (llamacpp only)
+    pub grammar: Option<GrammarMode>,
+    /// Reuse the server's KV cache for the shared prompt prefix; on by
+    /// default (llamacpp only)
+    pub cache_prompt: Option<bool>,
+    /// Server slot to pin requests to (llamacpp only)
+    pub slot_id: Option<i64>,
+    /// Sampling and length settings
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    /// HTTP client settings, overriding `[http]`
+    #[serde(flatten)]
+    pub http: HttpSettings,
 }
 
-fn default_ollama_local_endpoint() -> String {
-    "http://localhost:11434".to_string()
-}
+impl ProviderConfig {
+    fn builtin(kind: ProviderKind) -> Self {
+        Self {
+            kind: Some(kind),
+            endpoint: Some(kind.default_endpoint().to_string()),
+            model: kind.default_model().map(str::to_string),
+            ..Self::default()
+        }
+    }
 
-impl Default for OllamaLocalConfig {
-    fn default() -> Self {
+    /// Fill unset fields from `base`, as long as both use the same kind
+    fn merged_over(self, base: &ProviderConfig) -> Self {
+        if self.kind.is_some() && self.kind != base.kind {
+            return self;
+        }
+        let mut query = base.query.clone();
+        query.extend(self.query);
         Self {
-            endpoint: default_ollama_local_endpoint(),
-            model: None,
+            kind: base.kind,
+            endpoint: self.endpoint.or_else(|| base.endpoint.clone()),
+            model: self.model.or_else(|| base.model.clone()),
+            api_key: self.api_key.or_else(|| base.api_key.clone()),
+            api_key_env: self.api_key_env.or_else(|| base.api_key_env.clone()),
+            name: self.name.or_else(|| base.name.clone()),
+            json_mode: self.json_mode.or(base.json_mode),
+            structured_output: self.structured_output.or(base.structured_output),
+            query,
+            max_attempts: self.max_attempts.or(base.max_attempts),
+            retry_deadline_secs: self.retry_deadline_secs.or(base.retry_deadline_secs),
+            fixture: self.fixture.or_else(|| base.fixture.clone()),
+            resource: self.resource.or_else(|| base.resource.clone()),
+            deployment: self.deployment.or_else(|| base.deployment.clone()),
+            api_version: self.api_version.or_else(|| base.api_version.clone()),
+            region: self.region.or_else(|| base.region.clone()),
+            profile: self.profile.or_else(|| base.profile.clone()),
+            chat_template: self.chat_template.or(base.chat_template),
+            grammar: self.grammar.or(base.grammar),
+            cache_prompt: self.cache_prompt.or(base.cache_prompt),
+            slot_id: self.slot_id.or(base.slot_id),
+            params: self.params.or(&base.params),
+            http: self.http.or(&base.http),
+        }
+    }
+
+    /// The fields that differ from the built-in entry `base`, which is all a
+    /// config file needs to hold. Built-ins only set the kind, endpoint and
+    /// model; an entry of another kind replaces the built-in and is kept whole.
+    fn changes_from(mut self, base: &ProviderConfig) -> Self {
+        if self.kind != base.kind {
+            return self;
+        }
+        // Inferred from the table name
+        self.kind = None;
+        if self.endpoint == base.endpoint {
+            self.endpoint = None;
         }
+        if self.model == base.model {
+            self.model = None;
+        }
+        self
+    }
+
+    /// The configured AWS region, or `AWS_REGION`/`AWS_DEFAULT_REGION`
+    pub fn aws_region(&self) -> Option<String> {
+        self.region.clone().or_else(|| {
+            ["AWS_REGION", "AWS_DEFAULT_REGION"]
+                .iter()
+                .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+        })
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct OllamaCloudConfig {
-    #[serde(default = "default_ollama_cloud_endpoint")]
-    pub endpoint: String,
-    pub model: Option<String>,
-    pub api_key: Option<String>,
+/// Short names accepted by `--provider` for the built-in entries
+const PROVIDER_ALIASES: &[(&str, &str)] = &[
+    ("local", "ollama"),
+    ("ollama-local", "ollama"),
+    ("cloud", "ollama-cloud"),
+    ("gpt", "openai"),
+    ("claude", "anthropic"),
+    ("google", "gemini"),
+    ("compat", "openai-compatible"),
+    ("azure", "azure-openai"),
+    ("aws", "bedrock"),
+    ("llama.cpp", "llamacpp"),
+];
+
+fn builtin_providers() -> BTreeMap<String, ProviderConfig> {
+    ProviderKind::ALL
+        .iter()
+        .map(|kind| (kind.to_string(), ProviderConfig::builtin(*kind)))
+        .collect()
+}
+
+impl From<BTreeMap<String, ProviderConfig>> for ProvidersConfig {
+    fn from(entries: BTreeMap<String, ProviderConfig>) -> Self {
+        let mut providers = builtin_providers();
+
+        for (name, mut entry) in entries {
+            // Older configs used `[providers.ollama-local]`
+            let name = if name == "ollama-local" {
+                "ollama".to_string()
+            } else {
+                name
+            };
+
+            let entry = match providers.get(&name) {
+                Some(base) => entry.merged_over(base),
+                None => {
+                    entry.kind = entry.kind.or_else(|| ProviderKind::from_name(&name));
+                    entry
+                }
+            };
+            providers.insert(name, entry);
+        }
+
+        Self(providers)
+    }
 }
 
-fn default_ollama_cloud_endpoint() -> String {
-    "https://api.ollama.com".to_string()
+impl From<ProvidersConfig> for BTreeMap<String, ProviderConfig> {
+    fn from(providers: ProvidersConfig) -> Self {
+        let builtins = builtin_providers();
+        providers
+            .0
+            .into_iter()
+            .filter_map(|(name, entry)| match builtins.get(&name) {
+                Some(base) => {
+                    let changes = entry.changes_from(base);
+                    (changes != ProviderConfig::default()).then_some((name, changes))
+                }
+                None => Some((name, entry)),
+            })
+            .collect()
+    }
 }
 
-impl Default for OllamaCloudConfig {
+impl Default for ProvidersConfig {
     fn default() -> Self {
-        Self {
-            endpoint: default_ollama_cloud_endpoint(),
-            model: None,
-            api_key: None,
+        Self(builtin_providers())
+    }
+}
+
+impl ProvidersConfig {
+    /// Look up a provider by name or built-in alias, returning its canonical name
+    pub fn resolve(&self, name: &str) -> Option<(&str, &ProviderConfig)> {
+        if let Some((key, entry)) = self.0.get_key_value(name) {
+            return Some((key.as_str(), entry));
         }
+        let target = PROVIDER_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map(|(_, target)| *target)?;
+        self.0
+            .get_key_value(target)
+            .map(|(key, entry)| (key.as_str(), entry))
+    }
+
+    /// Registered provider names
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// Iterate over all registered providers
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ProviderConfig)> {
+        self.0.iter().map(|(name, entry)| (name.as_str(), entry))
+    }
+
+    /// Get or create the entry for a provider name
+    pub fn entry_mut(&mut self, name: &str) -> &mut ProviderConfig {
+        let name = self
+            .resolve(name)
+            .map(|(key, _)| key.to_string())
+            .unwrap_or_else(|| name.to_string());
+        self.0
+            .entry(name.clone())
+            .or_insert_with(|| ProviderConfig {
+                kind: ProviderKind::from_name(&name),
+                ..ProviderConfig::default()
+            })
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct OpenAIConfig {
-    #[serde(default = "default_openai_endpoint")]
+/// A registry entry with CLI overrides, defaults and API key applied
+#[derive(Debug, Clone)]
+pub struct ResolvedProvider {
+    /// Canonical registry name
+    pub name: String,
+    pub kind: ProviderKind,
     pub endpoint: String,
-    #[serde(default = "default_openai_model")]
     pub model: String,
     pub api_key: Option<String>,
+    /// Environment variable the key is read from
+    pub api_key_env: Option<String>,
+    /// Retry policy and record/replay settings
+    pub transport: Transport,
+    /// Generation settings from the config; callers layer CLI overrides on top
+    pub params: GenerationParams,
+    /// HTTP client settings, with `[http]` defaults applied
+    pub http: HttpSettings,
+    /// The raw config entry, for kind-specific settings
+    pub settings: ProviderConfig,
 }
 
-fn default_openai_endpoint() -> String {
-    "https://api.openai.com/v1".to_string()
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UiConfig {
+    #[serde(default = "bool_true")]
+    pub color: bool,
+    #[serde(default = "bool_true")]
+    pub spinner: bool,
+    #[serde(default)]
+    pub stream: bool,
 }
 
-fn default_openai_model() -> String {
-    "gpt-4o".to_string()
+fn bool_true() -> bool {
+    true
 }
 
-impl Default for OpenAIConfig {
+impl Default for UiConfig {
     fn default() -> Self {
         Self {
-            endpoint: default_openai_endpoint(),
-            model: default_openai_model(),
-            api_key: None,
+            color: true,
+            spinner: true,
+            stream: false,
         }
     }
 }
 
+/// The `[cache]` table: limits of the on-disk response cache
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AnthropicConfig {
-    #[serde(default = "default_anthropic_endpoint")]
-    pub endpoint: String,
-    #[serde(default = "default_anthropic_model")]
-    pub model: String,
-    pub api_key: Option<String>,
+pub struct CacheConfig {
+    #[serde(default = "bool_true")]
+    pub enabled: bool,
+    /// How long a response may be reused
+    #[serde(default = "default_cache_ttl_secs")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    #[serde(default = "default_cache_max_size_mb")]
+    pub max_size_mb: u64,
 }
 
-fn default_anthropic_endpoint() -> String {
-    "https://api.anthropic.com/v1".to_string()
+fn default_cache_ttl_secs() -> u64 {
+    24 * 60 * 60
 }
 
-fn default_anthropic_model() -> String {
-    "claude-3-5-sonnet".to_string()
+fn default_cache_max_size_mb() -> u64 {
+    50
 }
 
-impl Default for AnthropicConfig {
+impl Default for CacheConfig {
     fn default() -> Self {
         Self {
-            endpoint: default_anthropic_endpoint(),
-            model: default_anthropic_model(),
-            api_key: None,
+            enabled: true,
+            ttl_secs: default_cache_ttl_secs(),
+            max_entries: default_max_entries(),
+            max_size_mb: default_cache_max_size_mb(),
         }
     }
 }
 
+/// The `[clarification]` table
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UiConfig {
-    #[serde(default = "bool_true")]
-    pub color: bool,
-    #[serde(default = "bool_true")]
-    pub spinner: bool,
+pub struct ClarificationConfig {
+    /// Rounds of questions before refining with what is known; 0 never asks
+    #[serde(default = "default_max_rounds")]
+    pub max_rounds: u32,
 }
 
-fn bool_true() -> bool {
-    true
+fn default_max_rounds() -> u32 {
+    3
 }
 
-impl Default for UiConfig {
+impl Default for ClarificationConfig {
     fn default() -> Self {
         Self {
-            color: true,
-            spinner: true,
+            max_rounds: default_max_rounds(),
         }
     }
 }
 
+/// The `[judge]` table. Both fields fall back to the default provider.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct JudgeConfig {
+    /// Registry name of the judge provider
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl JudgeConfig {
+    pub fn is_empty(&self) -> bool {
+        self.provider.is_none() && self.model.is_none()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryConfig {
     #[serde(default = "bool_true")]
@@ -222,20 +509,9 @@ impl Config {
         let contents = fs::read_to_string(&path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
 
-        let mut config: Config = toml::from_str(&contents)
+        let config: Config = toml::from_str(&contents)
             .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
 
-        // Environment variables take precedence over config file values
-        if let Ok(key) = std::env::var("OLLAMA_API_KEY") {
-            config.providers.ollama_cloud.api_key = Some(key);
-        }
-        if let Ok(key) = std::env::var("OPENAI_API_KEY") {
-            config.providers.openai.api_key = Some(key);
-        }
-        if let Ok(key) = std::env::var("ANTHROPIC_API_KEY") {
-            config.providers.anthropic.api_key = Some(key);
-        }
-
         Ok(config)
     }
 
@@ -274,52 +550,205 @@ impl Config {
         Ok(path)
     }
 
-    /// Get the default model for a provider
-    pub fn get_model(&self, provider: ProviderChoice, cli_model: Option<&str>) -> String {
-        if let Some(model) = cli_model {
-            return model.to_string();
+    /// Resolve a provider name (or the configured default) against the
+    /// registry, applying CLI overrides.
+    ///
+    /// API keys come from `cli_key`, then the provider's environment variable,
+    /// then the config file.
+    pub fn resolve_provider(
+        &self,
+        name: Option<&str>,
+        cli_model: Option<&str>,
+        cli_key: Option<&str>,
+    ) -> Result<ResolvedProvider> {
+        let requested = name.unwrap_or(&self.default.provider);
+        let (name, settings) = self.providers.resolve(requested).with_context(|| {
+            format!(
+                "Unknown provider '{}'. Available providers: {}",
+                requested,
+                self.providers.names().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+
+        let kind = settings.kind.with_context(|| {
+            format!(
+                "Provider '{}' has no kind. Set `kind` in [providers.{}] to one of: {}",
+                name,
+                name,
+                ProviderKind::ALL
+                    .iter()
+                    .map(|k| k.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })?;
+
+        let model = cli_model
+            .map(str::to_string)
+            .or_else(|| settings.deployment.clone())
+            .or_else(|| settings.model.clone())
+            .or_else(|| kind.default_model().map(str::to_string));
+        // Azure deployment names are chosen per resource, so default.model
+        // is no use as a guess
+        let model = match model {
+            Some(model) => model,
+            None if kind == ProviderKind::AzureOpenAI => anyhow::bail!(
+                "Provider '{}' needs a deployment. Set `deployment` in [providers.{}] or pass --model",
+                name,
+                name
+            ),
+            None => self.default.model.clone(),
+        };
+
+        let mut endpoint = settings
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| kind.default_endpoint().to_string());
+        if endpoint.contains("{resource}") {
+            let resource = settings.resource.as_deref().with_context(|| {
+                format!(
+                    "Provider '{}' needs a resource name. Set `resource` or `endpoint` in [providers.{}]",
+                    name, name
+                )
+            })?;
+            endpoint = endpoint.replace("{resource}", resource);
         }
+        if endpoint.contains("{region}") {
+            let region = settings.aws_region().with_context(|| {
+                format!(
+                    "Provider '{}' needs an AWS region. Set `region` in [providers.{}] or AWS_REGION",
+                    name, name
+                )
+            })?;
+            endpoint = endpoint.replace("{region}", &region);
+        }
+
+        let api_key_env = settings
+            .api_key_env
+            .clone()
+            .or_else(|| kind.default_api_key_env().map(str::to_string));
 
-        match provider {
-            ProviderChoice::OllamaLocal => self
-                .providers
-                .ollama_local
-                .model
-                .clone()
-                .unwrap_or_else(|| self.default.model.clone()),
-            ProviderChoice::OllamaCloud => self
-                .providers
-                .ollama_cloud
-                .model
-                .clone()
-                .unwrap_or_else(|| self.default.model.clone()),
-            ProviderChoice::OpenAI => self.providers.openai.model.clone(),
-            ProviderChoice::Anthropic => self.providers.anthropic.model.clone(),
+        // Environment variables take precedence over config file values
+        let api_key = cli_key
+            .map(str::to_string)
+            .or_else(|| api_key_env.as_ref().and_then(|var| std::env::var(var).ok()))
+            .or_else(|| settings.api_key.clone());
+
+        let mut retry = RetryPolicy::default();
+        if let Some(max_attempts) = settings.max_attempts {
+            retry.max_attempts = max_attempts.max(1);
+        }
+        if let Some(secs) = settings.retry_deadline_secs {
+            retry.deadline = Some(Duration::from_secs(secs));
         }
+
+        let http = settings.http.clone().or(&self.http);
+
+        Ok(ResolvedProvider {
+            name: name.to_string(),
+            kind,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+            api_key_env,
+            transport: Transport {
+                retry,
+                cassette: None,
+                read_timeout: Some(http.read_timeout()),
+            },
+            params: settings.params.clone(),
+            http,
+            settings: settings.clone(),
+        })
     }
 
-    /// Get API key for a provider
-    pub fn get_api_key(&self, provider: ProviderChoice, cli_key: Option<&str>) -> Option<String> {
-        if let Some(key) = cli_key {
-            return Some(key.to_string());
-        }
+    /// Resolve the primary provider followed by its fallbacks.
+    ///
+    /// `cli_fallback` replaces `default.fallback` when non-empty. CLI model and
+    /// key overrides only apply to the primary provider; duplicates are skipped.
+    pub fn resolve_chain(
+        &self,
+        primary: Option<&str>,
+        cli_fallback: &[String],
+        cli_model: Option<&str>,
+        cli_key: Option<&str>,
+    ) -> Result<Vec<ResolvedProvider>> {
+        let mut chain = vec![self.resolve_provider(primary, cli_model, cli_key)?];
+
+        let fallback = if cli_fallback.is_empty() {
+            &self.default.fallback
+        } else {
+            cli_fallback
+        };
 
-        match provider {
-            ProviderChoice::OllamaLocal => None,
-            ProviderChoice::OllamaCloud => self.providers.ollama_cloud.api_key.clone(),
-            ProviderChoice::OpenAI => self.providers.openai.api_key.clone(),
-            ProviderChoice::Anthropic => self.providers.anthropic.api_key.clone(),
+        for name in fallback {
+            let resolved = self
+                .resolve_provider(Some(name), None, None)
+                .context("Invalid fallback provider")?;
+            if !chain.iter().any(|r| r.name == resolved.name) {
+                chain.push(resolved);
+            }
         }
+
+        Ok(chain)
     }
 
-    /// Get endpoint for a provider
-    pub fn get_endpoint(&self, provider: ProviderChoice) -> String {
-        match provider {
-            ProviderChoice::OllamaLocal => self.providers.ollama_local.endpoint.clone(),
-            ProviderChoice::OllamaCloud => self.providers.ollama_cloud.endpoint.clone(),
-            ProviderChoice::OpenAI => self.providers.openai.endpoint.clone(),
-            ProviderChoice::Anthropic => self.providers.anthropic.endpoint.clone(),
-        }
+    /// The system prompt for `spec` (from `--system-prompt`), else
+    /// `default.system_prompt`, else the built-in one. A spec names a
+    /// `[system_prompts]` entry or, failing that, a file. The prompt is
+    /// validated before it is returned.
+    pub fn resolve_system_prompt(&self, spec: Option<&str>) -> Result<SystemPrompt> {
+        let spec = spec.or(self.default.system_prompt.as_deref());
+        let prompt = match spec {
+            Some(name) => match self.system_prompts.get(name) {
+                Some(entry) => entry.load(name)?,
+                None if name == BUILTIN_NAME => SystemPrompt::builtin(),
+                None if Path::new(name).is_file() => SystemPrompt::from_file(Path::new(name))?,
+                None => {
+                    let known: Vec<&str> = std::iter::once(BUILTIN_NAME)
+                        .chain(self.system_prompts.keys().map(String::as_str))
+                        .collect();
+                    anyhow::bail!(
+                        "Unknown system prompt '{}': not a file or one of: {}",
+                        name,
+                        known.join(", ")
+                    );
+                }
+            },
+            None => SystemPrompt::builtin(),
+        };
+        prompt.validate()?;
+        Ok(prompt)
+    }
+
+    /// Resolve the judge used for scoring: `cli_name` and `cli_model` (from
+    /// `--judge`), then the `[judge]` table, then the default provider
+    pub fn resolve_judge(
+        &self,
+        cli_name: Option<&str>,
+        cli_model: Option<&str>,
+    ) -> Result<ResolvedProvider> {
+        let name = cli_name.or(self.judge.provider.as_deref());
+        // A configured judge model only belongs to the configured judge
+        let model = cli_model.or(self
+            .judge
+            .model
+            .as_deref()
+            .filter(|_| cli_name.is_none() || cli_name == self.judge.provider.as_deref()));
+        self.resolve_provider(name, model, None)
+            .context("Invalid judge provider")
+    }
+
+    /// Price entry for a model: an exact match, or else the longest key the
+    /// model name starts with (so `gpt-4o` covers `gpt-4o-2024-08-06`)
+    pub fn price_for(&self, model: &str) -> Option<&ModelPrice> {
+        self.pricing.get(model).or_else(|| {
+            self.pricing
+                .iter()
+                .filter(|(key, _)| model.starts_with(key.as_str()))
+                .max_by_key(|(key, _)| key.len())
+                .map(|(_, price)| price)
+        })
     }
 
     /// Get a value by dot-notation key
@@ -329,11 +758,83 @@ impl Config {
             "default.model" => Some(self.default.model.clone()),
             "default.output_format" => Some(self.default.output_format.clone()),
             "default.copy_to_clipboard" => Some(self.default.copy_to_clipboard.to_string()),
+            "default.fallback" => Some(self.default.fallback.join(",")),
+            "default.system_prompt" => self.default.system_prompt.clone(),
             "ui.color" => Some(self.ui.color.to_string()),
             "ui.spinner" => Some(self.ui.spinner.to_string()),
+            "ui.stream" => Some(self.ui.stream.to_string()),
+            "cache.enabled" => Some(self.cache.enabled.to_string()),
+            "cache.ttl_secs" => Some(self.cache.ttl_secs.to_string()),
+            "cache.max_entries" => Some(self.cache.max_entries.to_string()),
+            "cache.max_size_mb" => Some(self.cache.max_size_mb.to_string()),
+            "clarification.max_rounds" => Some(self.clarification.max_rounds.to_string()),
+            "judge.provider" => self.judge.provider.clone(),
+            "judge.model" => self.judge.model.clone(),
             "history.enabled" => Some(self.history.enabled.to_string()),
             "history.max_entries" => Some(self.history.max_entries.to_string()),
-            _ => None,
+            _ if key.starts_with("system_prompts.") => {
+                let (name, field) = split_system_prompt_key(key)?;
+                let entry = self.system_prompts.get(name)?;
+                match field {
+                    "content" => entry.content.clone(),
+                    "file" => entry.file.as_ref().map(|p| p.display().to_string()),
+                    _ => None,
+                }
+            }
+            _ if key.starts_with("pricing.") => {
+                let (model, field) = split_pricing_key(key)?;
+                let price = self.pricing.get(model)?;
+                match field {
+                    "input" => Some(price.input.to_string()),
+                    "output" => Some(price.output.to_string()),
+                    _ => None,
+                }
+            }
+            _ if key.starts_with("http.") => {
+                let field = key.strip_prefix("http.")?;
+                match field.strip_prefix("headers.") {
+                    Some(header) => self.http.headers.get(header).cloned(),
+                    None => self.http.get(field),
+                }
+            }
+            _ => {
+                if let Some((name, header)) = split_provider_header_key(key) {
+                    let (_, entry) = self.providers.resolve(name)?;
+                    return entry.http.headers.get(header).cloned();
+                }
+                let (name, field) = split_provider_key(key)?;
+                let (_, entry) = self.providers.resolve(name)?;
+                match field {
+                    "kind" => entry.kind.map(|k| k.to_string()),
+                    "endpoint" => entry.endpoint.clone(),
+                    "model" => entry.model.clone(),
+                    "api_key_env" => entry.api_key_env.clone(),
+                    "name" => entry.name.clone(),
+                    "json_mode" => entry.json_mode.map(|v| v.to_string()),
+                    "structured_output" => entry.structured_output.map(|v| v.to_string()),
+                    "max_attempts" => entry.max_attempts.map(|v| v.to_string()),
+                    "retry_deadline_secs" => entry.retry_deadline_secs.map(|v| v.to_string()),
+                    "fixture" => entry.fixture.as_ref().map(|p| p.display().to_string()),
+                    "resource" => entry.resource.clone(),
+                    "deployment" => entry.deployment.clone(),
+                    "api_version" => entry.api_version.clone(),
+                    "region" => entry.region.clone(),
+                    "profile" => entry.profile.clone(),
+                    "chat_template" => entry.chat_template.map(|t| t.to_string()),
+                    "grammar" => entry.grammar.map(|g| g.to_string()),
+                    "cache_prompt" => entry.cache_prompt.map(|v| v.to_string()),
+                    "slot_id" => entry.slot_id.map(|v| v.to_string()),
+                    "temperature" => entry.params.temperature.map(|v| v.to_string()),
+                    "top_p" => entry.params.top_p.map(|v| v.to_string()),
+                    "max_tokens" => entry.params.max_tokens.map(|v| v.to_string()),
+                    "seed" => entry.params.seed.map(|v| v.to_string()),
+                    "stop" => Some(entry.params.stop.join(",")).filter(|s| !s.is_empty()),
+                    "num_ctx" => entry.params.num_ctx.map(|v| v.to_string()),
+                    "keep_alive" => entry.params.keep_alive.clone(),
+                    _ if HTTP_FIELDS.contains(&field) => entry.http.get(field),
+                    _ => None,
+                }
+            }
         }
     }
 
@@ -346,31 +847,305 @@ impl Config {
             "default.copy_to_clipboard" => {
                 self.default.copy_to_clipboard = value.parse().context("Invalid boolean value")?;
             }
+            "default.fallback" => {
+                self.default.fallback = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            "default.system_prompt" => self.default.system_prompt = Some(value.to_string()),
             "ui.color" => {
                 self.ui.color = value.parse().context("Invalid boolean value")?;
             }
             "ui.spinner" => {
                 self.ui.spinner = value.parse().context("Invalid boolean value")?;
             }
+            "ui.stream" => {
+                self.ui.stream = value.parse().context("Invalid boolean value")?;
+            }
+            "cache.enabled" => {
+                self.cache.enabled = value.parse().context("Invalid boolean value")?;
+            }
+            "cache.ttl_secs" => {
+                self.cache.ttl_secs = value.parse().context("Invalid number")?;
+            }
+            "cache.max_entries" => {
+                self.cache.max_entries = value.parse().context("Invalid number")?;
+            }
+            "cache.max_size_mb" => {
+                self.cache.max_size_mb = value.parse().context("Invalid number")?;
+            }
+            "clarification.max_rounds" => {
+                self.clarification.max_rounds = value.parse().context("Invalid number")?;
+            }
+            "judge.provider" => self.judge.provider = Some(value.to_string()),
+            "judge.model" => self.judge.model = Some(value.to_string()),
             "history.enabled" => {
                 self.history.enabled = value.parse().context("Invalid boolean value")?;
             }
             "history.max_entries" => {
                 self.history.max_entries = value.parse().context("Invalid number")?;
             }
-            _ => anyhow::bail!("Unknown configuration key: {}", key),
+            _ if key.starts_with("system_prompts.") => {
+                let (name, field) = split_system_prompt_key(key)
+                    .filter(|(_, field)| matches!(*field, "content" | "file"))
+                    .with_context(|| format!("Unknown configuration key: {}", key))?;
+                let entry = self.system_prompts.entry(name.to_string()).or_default();
+                // An entry holds one or the other
+                match field {
+                    "content" => {
+                        entry.content = Some(value.to_string());
+                        entry.file = None;
+                    }
+                    _ => {
+                        entry.file = Some(PathBuf::from(value));
+                        entry.content = None;
+                    }
+                }
+            }
+            _ if key.starts_with("pricing.") => {
+                let (model, field) = split_pricing_key(key)
+                    .filter(|(_, field)| matches!(*field, "input" | "output"))
+                    .with_context(|| format!("Unknown configuration key: {}", key))?;
+                let price: f64 = value.parse().context("Invalid number")?;
+                let entry = self.pricing.entry(model.to_string()).or_default();
+                match field {
+                    "input" => entry.input = price,
+                    _ => entry.output = price,
+                }
+            }
+            _ if key.starts_with("http.") => {
+                let field = &key["http.".len()..];
+                match field.strip_prefix("headers.") {
+                    Some(header) => {
+                        self.http
+                            .headers
+                            .insert(header.to_string(), value.to_string());
+                    }
+                    None if HTTP_FIELDS.contains(&field) => self.http.set(field, value)?,
+                    None => anyhow::bail!("Unknown configuration key: {}", key),
+                }
+            }
+            _ if split_provider_header_key(key).is_some() => {
+                let (name, header) = split_provider_header_key(key).unwrap_or_default();
+                self.providers
+                    .entry_mut(name)
+                    .http
+                    .headers
+                    .insert(header.to_string(), value.to_string());
+            }
+            _ => {
+                let (name, field) = split_provider_key(key)
+                    .filter(|(_, field)| {
+                        PROVIDER_FIELDS.contains(field)
+                            || PARAM_FIELDS.contains(field)
+                            || HTTP_FIELDS.contains(field)
+                    })
+                    .with_context(|| format!("Unknown configuration key: {}", key))?;
+                let entry = self.providers.entry_mut(name);
+                match field {
+                    "kind" => {
+                        entry.kind = Some(
+                            ProviderKind::from_name(value)
+                                .with_context(|| format!("Unknown provider kind: {}", value))?,
+                        );
+                    }
+                    "endpoint" => entry.endpoint = Some(value.to_string()),
+                    "model" => entry.model = Some(value.to_string()),
+                    "api_key_env" => entry.api_key_env = Some(value.to_string()),
+                    "name" => entry.name = Some(value.to_string()),
+                    "json_mode" => {
+                        entry.json_mode = Some(value.parse().context("Invalid boolean value")?);
+                    }
+                    "structured_output" => {
+                        entry.structured_output =
+                            Some(value.parse().context("Invalid boolean value")?);
+                    }
+                    "max_attempts" => {
+                        entry.max_attempts = Some(value.parse().context("Invalid number")?);
+                    }
+                    "retry_deadline_secs" => {
+                        entry.retry_deadline_secs = Some(value.parse().context("Invalid number")?);
+                    }
+                    "fixture" => entry.fixture = Some(PathBuf::from(value)),
+                    "resource" => entry.resource = Some(value.to_string()),
+                    "deployment" => entry.deployment = Some(value.to_string()),
+                    "api_version" => entry.api_version = Some(value.to_string()),
+                    "region" => entry.region = Some(value.to_string()),
+                    "profile" => entry.profile = Some(value.to_string()),
+                    "chat_template" => {
+                        entry.chat_template =
+                            Some(ChatTemplate::from_name(value).with_context(|| {
+                                format!(
+                                    "Unknown chat template: {} (expected one of: {})",
+                                    value,
+                                    ChatTemplate::NAMES.join(", ")
+                                )
+                            })?);
+                    }
+                    "grammar" => {
+                        entry.grammar = Some(GrammarMode::from_name(value).with_context(|| {
+                            format!(
+                                "Unknown grammar mode: {} (expected one of: {})",
+                                value,
+                                GrammarMode::NAMES.join(", ")
+                            )
+                        })?);
+                    }
+                    "cache_prompt" => {
+                        entry.cache_prompt = Some(value.parse().context("Invalid boolean value")?);
+                    }
+                    "slot_id" => {
+                        entry.slot_id = Some(value.parse().context("Invalid number")?);
+                    }
+                    "temperature" => {
+                        entry.params.temperature = Some(value.parse().context("Invalid number")?);
+                    }
+                    "top_p" => {
+                        entry.params.top_p = Some(value.parse().context("Invalid number")?);
+                    }
+                    "max_tokens" => {
+                        entry.params.max_tokens = Some(value.parse().context("Invalid number")?);
+                    }
+                    "seed" => {
+                        entry.params.seed = Some(value.parse().context("Invalid number")?);
+                    }
+                    "stop" => {
+                        entry.params.stop = value
+                            .split(',')
+                            .filter(|stop| !stop.is_empty())
+                            .map(str::to_string)
+                            .collect();
+                    }
+                    "num_ctx" => {
+                        entry.params.num_ctx = Some(value.parse().context("Invalid number")?);
+                    }
+                    "keep_alive" => entry.params.keep_alive = Some(value.to_string()),
+                    _ if HTTP_FIELDS.contains(&field) => entry.http.set(field, value)?,
+                    _ => anyhow::bail!("Unknown configuration key: {}", key),
+                }
+                if let Some(kind) = entry.kind {
+                    entry.params.validate(kind)?;
+                }
+            }
         }
         Ok(())
     }
+}
 
-    /// Get default provider from config
-    pub fn get_default_provider(&self) -> Result<ProviderChoice> {
-        match self.default.provider.as_str() {
-            "ollama" | "ollama-local" | "local" => Ok(ProviderChoice::OllamaLocal),
-            "ollama-cloud" | "cloud" => Ok(ProviderChoice::OllamaCloud),
-            "openai" | "gpt" => Ok(ProviderChoice::OpenAI),
-            "anthropic" | "claude" => Ok(ProviderChoice::Anthropic),
-            other => anyhow::bail!("Unknown provider in config: {}", other),
-        }
+/// Per-provider fields settable via `config set providers.<name>.<field>`
+const PROVIDER_FIELDS: &[&str] = &[
+    "kind",
+    "endpoint",
+    "model",
+    "api_key_env",
+    "name",
+    "json_mode",
+    "structured_output",
+    "max_attempts",
+    "retry_deadline_secs",
+    "fixture",
+    "resource",
+    "deployment",
+    "api_version",
+    "region",
+    "profile",
+    "chat_template",
+    "grammar",
+    "cache_prompt",
+    "slot_id",
+];
+
+/// Split `pricing.<model>.<field>` into its model and field. Model names may
+/// contain dots, so the field is taken from the end.
+fn split_pricing_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix("pricing.")?.rsplit_once('.')
+}
+
+/// Split `system_prompts.<name>.<field>` into its name and field
+fn split_system_prompt_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix("system_prompts.")?.rsplit_once('.')
+}
+
+/// Split `providers.<name>.headers.<header>` into its name and header
+fn split_provider_header_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix("providers.")?.split_once(".headers.")
+}
+
+/// Split `providers.<name>.<field>` into its name and field
+fn split_provider_key(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix("providers.")?;
+    rest.rsplit_once('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load `[providers]` tables and write them back out, as `config set` does
+    fn round_trip(toml_text: &str) -> (ProvidersConfig, toml::Table) {
+        let providers: ProvidersConfig = toml::from_str(toml_text).unwrap();
+        let written = toml::to_string(&providers).unwrap();
+        (providers, toml::from_str(&written).unwrap())
+    }
+
+    #[test]
+    fn unchanged_builtins_are_not_written() {
+        let openai = ProviderConfig::builtin(ProviderKind::OpenAI);
+        let (providers, written) = round_trip(&format!(
+            "[openai]\nkind = \"openai\"\nendpoint = {:?}\nmodel = {:?}\n",
+            openai.endpoint.unwrap(),
+            openai.model.unwrap()
+        ));
+
+        assert!(written.is_empty(), "wrote {:?}", written);
+        assert_eq!(providers.0, builtin_providers());
+        assert!(toml::to_string(&ProvidersConfig::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn overridden_builtins_write_only_their_changes() {
+        let (providers, written) = round_trip(
+            "[ollama]\nmodel = \"qwen2.5\"\nmax_attempts = 5\n\
+             [anthropic]\nendpoint = \"https://gateway.example/anthropic\"\n",
+        );
+
+        let ollama = &providers.0["ollama"];
+        assert_eq!(ollama.kind, Some(ProviderKind::Ollama));
+        assert_eq!(
+            ollama.endpoint.as_deref(),
+            Some(ProviderKind::Ollama.default_endpoint())
+        );
+        assert_eq!(ollama.model.as_deref(), Some("qwen2.5"));
+
+        let expected: toml::Table = toml::from_str(
+            "[ollama]\nmodel = \"qwen2.5\"\nmax_attempts = 5\n\
+             [anthropic]\nendpoint = \"https://gateway.example/anthropic\"\n",
+        )
+        .unwrap();
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn custom_providers_are_written_whole() {
+        let custom = "[work]\nkind = \"openai-compatible\"\n\
+                      endpoint = \"http://localhost:1234/v1\"\nmodel = \"local-model\"\n\
+                      [openai]\nkind = \"mock\"\nfixture = \"replies.toml\"\n";
+        let (providers, written) = round_trip(custom);
+
+        assert_eq!(written, toml::from_str::<toml::Table>(custom).unwrap());
+        let (_, again) = round_trip(&toml::to_string(&providers).unwrap());
+        assert_eq!(again, written);
+    }
+
+    #[test]
+    fn legacy_ollama_local_table_is_written_as_ollama() {
+        let (_, written) = round_trip("[ollama-local]\nmodel = \"qwen2.5\"\n");
+        let expected: toml::Table = toml::from_str("[ollama]\nmodel = \"qwen2.5\"\n").unwrap();
+        assert_eq!(written, expected);
     }
 }
@@ -0,0 +1,497 @@
+//! `prep doctor`: diagnose configuration, connectivity and model problems
+
+use crate::config::{Config, ResolvedProvider};
+use crate::history::History;
+use crate::providers::{
+    build_client, build_provider, check_model, AwsCredentials, ProviderError, ProviderKind,
+    RetryPolicy,
+};
+use futures_util::future::join_all;
+use serde::Serialize;
+use std::time::Duration;
+
+/// How long to wait for a provider endpoint to answer
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    /// Not run, e.g. because an earlier check failed
+    Skip,
+}
+
+/// A single diagnostic result
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    /// Group the check belongs to, e.g. `config` or `provider ollama`
+    pub section: String,
+    pub name: String,
+    pub status: Status,
+    pub message: String,
+    /// Suggested fix for warnings and failures
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn new(section: &str, name: &str, status: Status, message: impl Into<String>) -> Self {
+        Self {
+            section: section.to_string(),
+            name: name.to_string(),
+            status,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn with_fix(mut self, fix: impl Into<String>) -> Self {
+        self.fix = Some(fix.into());
+        self
+    }
+}
+
+/// Run every check. `loaded` is the result of [`Config::load`], so a broken
+/// config file is reported rather than aborting the run.
+///
+/// Only the default provider and its fallbacks are probed unless `all` is set.
+pub async fn run(loaded: anyhow::Result<Config>, all: bool) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let config = check_config(loaded, &mut checks);
+
+    let in_use: Vec<&str> = std::iter::once(config.default.provider.as_str())
+        .chain(config.default.fallback.iter().map(String::as_str))
+        .collect();
+    for name in &in_use {
+        if config.providers.resolve(name).is_none() {
+            checks.push(
+                Check::new(
+                    "config",
+                    "Provider chain",
+                    Status::Fail,
+                    format!("'{}' is not a registered provider", name),
+                )
+                .with_fix(format!(
+                    "Add a [providers.{}] section or choose one of: {}",
+                    name,
+                    config.providers.names().collect::<Vec<_>>().join(", ")
+                )),
+            );
+        }
+    }
+
+    checks.extend(check_system_prompts(&config));
+
+    let probes = config.providers.iter().map(|(name, _)| {
+        let used = in_use
+            .iter()
+            .any(|n| config.providers.resolve(n).map(|(key, _)| key) == Some(name));
+        let role = if name == resolve_name(&config, &config.default.provider) {
+            Some("default")
+        } else if used {
+            Some("fallback")
+        } else {
+            None
+        };
+        check_provider(&config, name, role, all || used)
+    });
+    for provider_checks in join_all(probes).await {
+        checks.extend(provider_checks);
+    }
+
+    checks.push(check_history());
+    checks.push(check_clipboard());
+
+    checks
+}
+
+/// Canonical registry name for `name`, or `name` itself if unknown
+fn resolve_name<'a>(config: &'a Config, name: &'a str) -> &'a str {
+    config
+        .providers
+        .resolve(name)
+        .map(|(key, _)| key)
+        .unwrap_or(name)
+}
+
+fn check_config(loaded: anyhow::Result<Config>, checks: &mut Vec<Check>) -> Config {
+    let path = Config::path()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    match loaded {
+        Ok(config) => {
+            let exists = Config::path().map(|p| p.exists()).unwrap_or(false);
+            if exists {
+                checks.push(Check::new(
+                    "config",
+                    "Config file",
+                    Status::Pass,
+                    format!("Loaded {}", path),
+                ));
+            } else {
+                checks.push(
+                    Check::new(
+                        "config",
+                        "Config file",
+                        Status::Warn,
+                        format!("No config file at {}; using defaults", path),
+                    )
+                    .with_fix("Run 'prep config init' to create one"),
+                );
+            }
+            config
+        }
+        Err(e) => {
+            checks.push(
+                Check::new("config", "Config file", Status::Fail, format!("{:#}", e)).with_fix(
+                    format!(
+                        "Fix the file at {} or recreate it with 'prep config init --force'",
+                        path
+                    ),
+                ),
+            );
+            Config::default()
+        }
+    }
+}
+
+async fn check_provider(
+    config: &Config,
+    name: &str,
+    role: Option<&str>,
+    probe: bool,
+) -> Vec<Check> {
+    let section = match role {
+        Some(role) => format!("provider {} ({})", name, role),
+        None => format!("provider {}", name),
+    };
+    let mut checks = Vec::new();
+
+    let resolved = match config.resolve_provider(Some(name), None, None) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            let status = if probe { Status::Fail } else { Status::Skip };
+            checks.push(
+                Check::new(&section, "Config", status, format!("{:#}", e))
+                    .with_fix(format!("Fix [providers.{}] in the config file", name)),
+            );
+            return checks;
+        }
+    };
+
+    match reqwest::Url::parse(&resolved.endpoint) {
+        Ok(_) if resolved.kind == ProviderKind::Mock => checks.push(Check::new(
+            &section,
+            "Config",
+            Status::Pass,
+            match &resolved.settings.fixture {
+                Some(path) => format!("mock with fixture {}", path.display()),
+                None => "mock, echoing prompts".to_string(),
+            },
+        )),
+        Ok(_) => checks.push(Check::new(
+            &section,
+            "Config",
+            Status::Pass,
+            format!("{} at {}", resolved.kind, resolved.endpoint),
+        )),
+        Err(e) => {
+            checks.push(
+                Check::new(
+                    &section,
+                    "Config",
+                    Status::Fail,
+                    format!("Invalid endpoint '{}': {}", resolved.endpoint, e),
+                )
+                .with_fix(format!(
+                    "Set a full URL, e.g. 'prep config set providers.{}.endpoint {}'",
+                    name,
+                    resolved.kind.default_endpoint()
+                )),
+            );
+            return checks;
+        }
+    }
+
+    if let Err(e) = resolved.params.validate(resolved.kind) {
+        checks.push(
+            Check::new(&section, "Generation settings", Status::Fail, e.to_string())
+                .with_fix(format!("Fix the value in [providers.{}]", name)),
+        );
+        return checks;
+    }
+
+    if let Err(e) = build_client(&resolved.http) {
+        checks.push(
+            Check::new(&section, "HTTP settings", Status::Fail, format!("{:#}", e)).with_fix(
+                format!(
+                    "Check proxy, ca_bundle and headers in [http] and [providers.{}]",
+                    name
+                ),
+            ),
+        );
+        return checks;
+    }
+
+    let key_check = match resolved.kind {
+        ProviderKind::Bedrock => check_aws_credentials(&section, &resolved, probe),
+        _ => check_api_key(&section, &resolved, probe),
+    };
+    let key_missing = key_check.status != Status::Pass;
+    checks.push(key_check);
+
+    if !probe {
+        checks.push(Check::new(
+            &section,
+            "Endpoint",
+            Status::Skip,
+            "Not in the provider chain; run 'prep doctor --all' to probe it",
+        ));
+        return checks;
+    }
+    if key_missing {
+        checks.push(Check::new(
+            &section,
+            "Endpoint",
+            Status::Skip,
+            "Skipped without credentials",
+        ));
+        return checks;
+    }
+
+    checks.extend(probe_endpoint(&section, resolved).await);
+    checks
+}
+
+fn check_api_key(section: &str, resolved: &ResolvedProvider, in_use: bool) -> Check {
+    let env_set = resolved
+        .api_key_env
+        .as_deref()
+        .filter(|var| std::env::var(var).is_ok_and(|v| !v.is_empty()));
+
+    match (env_set, resolved.api_key.is_some(), &resolved.api_key_env) {
+        (Some(var), _, _) => {
+            Check::new(section, "API key", Status::Pass, format!("{} is set", var))
+        }
+        (None, true, _) => Check::new(section, "API key", Status::Pass, "Set in the config file"),
+        (None, false, _) if !resolved.kind.requires_api_key() => {
+            Check::new(section, "API key", Status::Pass, "Not required")
+        }
+        (None, false, var) => {
+            let var = var.as_deref().unwrap_or("an API key variable");
+            let status = if in_use { Status::Fail } else { Status::Skip };
+            Check::new(section, "API key", status, format!("{} is not set", var)).with_fix(format!(
+                "export {}=... or set api_key in [providers.{}]",
+                var, resolved.name
+            ))
+        }
+    }
+}
+
+fn check_aws_credentials(section: &str, resolved: &ResolvedProvider, in_use: bool) -> Check {
+    match AwsCredentials::load(resolved.settings.profile.as_deref()) {
+        Ok(credentials) => Check::new(
+            section,
+            "AWS credentials",
+            Status::Pass,
+            format!("From {}", credentials.source),
+        ),
+        Err(e) => {
+            let status = if in_use { Status::Fail } else { Status::Skip };
+            Check::new(section, "AWS credentials", status, format!("{:#}", e)).with_fix(format!(
+                "export AWS_ACCESS_KEY_ID=... AWS_SECRET_ACCESS_KEY=..., or set profile in [providers.{}]",
+                resolved.name
+            ))
+        }
+    }
+}
+
+/// Reach the endpoint by listing models, then check the configured model
+async fn probe_endpoint(section: &str, mut resolved: ResolvedProvider) -> Vec<Check> {
+    resolved.transport.retry = RetryPolicy::none();
+
+    let provider = match build_provider(&resolved) {
+        Ok(provider) => provider,
+        Err(e) => {
+            return vec![Check::new(
+                section,
+                "Endpoint",
+                Status::Fail,
+                format!("{:#}", e),
+            )];
+        }
+    };
+
+    let models = match tokio::time::timeout(PROBE_TIMEOUT, provider.list_models()).await {
+        Ok(Ok(models)) => models,
+        Ok(Err(e)) => {
+            let fix = match e.downcast_ref::<ProviderError>() {
+                Some(ProviderError::Connection(_)) => {
+                    format!(
+                        "Check that the server is running and reachable at {}",
+                        resolved.endpoint
+                    )
+                }
+                Some(ProviderError::Auth(_)) if resolved.kind == ProviderKind::Bedrock => {
+                    "Check that the AWS credentials are valid and may call Bedrock in this region"
+                        .to_string()
+                }
+                Some(ProviderError::Auth(_)) => "Check that the API key is valid".to_string(),
+                _ => "The endpoint answered but could not list models; refinement may still work"
+                    .to_string(),
+            };
+            let status = match e.downcast_ref::<ProviderError>() {
+                Some(_) => Status::Fail,
+                None => Status::Warn,
+            };
+            return vec![
+                Check::new(section, "Endpoint", status, first_line(&e)).with_fix(fix),
+                Check::new(section, "Model", Status::Skip, "Could not list models"),
+            ];
+        }
+        Err(_) => {
+            return vec![
+                Check::new(
+                    section,
+                    "Endpoint",
+                    Status::Fail,
+                    format!("No answer within {}s", PROBE_TIMEOUT.as_secs()),
+                )
+                .with_fix(format!("Check that {} is reachable", resolved.endpoint)),
+                Check::new(section, "Model", Status::Skip, "Could not list models"),
+            ];
+        }
+    };
+
+    let endpoint = Check::new(
+        section,
+        "Endpoint",
+        Status::Pass,
+        format!("Reachable, {} models available", models.len()),
+    );
+
+    let model = if models.is_empty() {
+        Check::new(
+            section,
+            "Model",
+            Status::Warn,
+            format!("{}: the server listed no models", resolved.model),
+        )
+    } else {
+        match check_model(&resolved.name, &resolved.model, &models) {
+            Ok(()) => Check::new(
+                section,
+                "Model",
+                Status::Pass,
+                format!("{} is available", resolved.model),
+            ),
+            // llama.cpp serves the model it was started with; the name is a label
+            Err(_) if resolved.kind == ProviderKind::LlamaCpp => Check::new(
+                section,
+                "Model",
+                Status::Warn,
+                format!(
+                    "{}: the server has {} loaded",
+                    resolved.model,
+                    models
+                        .iter()
+                        .map(|m| m.id.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )
+            .with_fix(format!(
+                "prep config set providers.{}.model <MODEL> so history records the right name",
+                resolved.name
+            )),
+            Err(e) => Check::new(section, "Model", Status::Fail, first_line(&e)).with_fix(format!(
+                "prep config set providers.{}.model <MODEL> (see 'prep models --provider {}')",
+                resolved.name, resolved.name
+            )),
+        }
+    };
+
+    vec![endpoint, model]
+}
+
+/// Each `[system_prompts]` entry, and the selected one if it is a file, must
+/// load and still ask for the JSON response
+fn check_system_prompts(config: &Config) -> Vec<Check> {
+    let mut specs: Vec<&str> = config.system_prompts.keys().map(String::as_str).collect();
+    if let Some(selected) = config.default.system_prompt.as_deref() {
+        if !specs.contains(&selected) {
+            specs.push(selected);
+        }
+    }
+
+    specs
+        .into_iter()
+        .map(|spec| {
+            let selected = config.default.system_prompt.as_deref() == Some(spec);
+            let name = format!("System prompt {}", spec);
+            match config.resolve_system_prompt(Some(spec)) {
+                Ok(prompt) => Check::new(
+                    "config",
+                    &name,
+                    Status::Pass,
+                    format!(
+                        "sha256 {}{}",
+                        prompt.short_hash(),
+                        if selected { ", selected" } else { "" }
+                    ),
+                ),
+                Err(e) => Check::new(
+                    "config",
+                    &name,
+                    if selected { Status::Fail } else { Status::Warn },
+                    format!("{:#}", e),
+                )
+                .with_fix(format!(
+                    "Check [system_prompts.{}] or default.system_prompt; the prompt must ask \
+                     for a JSON object with refined_prompt, needs_clarification and questions",
+                    spec
+                )),
+            }
+        })
+        .collect()
+}
+
+/// First line of an error, so server error pages don't flood the report
+fn first_line(error: &anyhow::Error) -> String {
+    let message = format!("{:#}", error);
+    message.lines().next().unwrap_or_default().to_string()
+}
+
+fn check_history() -> Check {
+    let result = History::open().and_then(|history| history.integrity_check());
+    match result {
+        Ok(()) => Check::new(
+            "history",
+            "Database",
+            Status::Pass,
+            History::db_path()
+                .map(|p| format!("{} is healthy", p.display()))
+                .unwrap_or_else(|_| "Healthy".to_string()),
+        ),
+        Err(e) => Check::new("history", "Database", Status::Fail, format!("{:#}", e))
+            .with_fix("Run 'prep history clear', or delete the database file to start over"),
+    }
+}
+
+fn check_clipboard() -> Check {
+    match arboard::Clipboard::new() {
+        Ok(_) => Check::new("clipboard", "Clipboard", Status::Pass, "Available"),
+        Err(e) => Check::new(
+            "clipboard",
+            "Clipboard",
+            Status::Warn,
+            format!("Unavailable: {}", e),
+        )
+        .with_fix("--copy won't work here; pipe the output instead (e.g. prep ... | pbcopy)"),
+    }
+}
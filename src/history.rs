@@ -3,9 +3,30 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use directories::ProjectDirs;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, Row};
 use std::path::PathBuf;
 
+use crate::judge::{Judgement, RubricScores};
+use crate::refiner::RefineStats;
+use crate::system_prompt::SystemPrompt;
+
+/// Columns selected for a [`HistoryEntry`], in `entry_from_row` order
+const ENTRY_COLUMNS: &str = "id, original_prompt, refined_prompt, provider, model, created_at, \
+     prompt_tokens, completion_tokens, latency_ms, cost_usd, draft, clarification, system_prompt, \
+     system_prompt_hash";
+
+/// Columns added after the first release, with their types
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("prompt_tokens", "INTEGER"),
+    ("completion_tokens", "INTEGER"),
+    ("latency_ms", "INTEGER"),
+    ("cost_usd", "REAL"),
+    ("draft", "INTEGER NOT NULL DEFAULT 0"),
+    ("clarification", "TEXT"),
+    ("system_prompt", "TEXT"),
+    ("system_prompt_hash", "TEXT"),
+];
+
 /// A single history entry
 #[derive(Debug, Clone)]
 pub struct HistoryEntry {
@@ -15,6 +36,31 @@ pub struct HistoryEntry {
     pub provider: String,
     pub model: String,
     pub created_at: DateTime<Utc>,
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    pub latency_ms: Option<u64>,
+    pub cost_usd: Option<f64>,
+    /// Saved when a refinement was interrupted before it finished
+    pub draft: bool,
+    /// Clarifying questions and the answers given so far
+    pub clarification: Option<String>,
+    /// Name of the system prompt used
+    pub system_prompt: Option<String>,
+    /// SHA-256 of the system prompt's content
+    pub system_prompt_hash: Option<String>,
+}
+
+/// One provider's answer in a `--compare` run
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub provider: String,
+    pub model: String,
+    /// `None` if the provider failed
+    pub refined_prompt: Option<String>,
+    pub error: Option<String>,
+    pub stats: RefineStats,
+    /// Picked as the winner
+    pub chosen: bool,
 }
 
 /// History database manager
@@ -56,9 +102,77 @@ impl History {
             [],
         )?;
 
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS candidates (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                history_id INTEGER NOT NULL,
+                provider TEXT NOT NULL,
+                model TEXT NOT NULL,
+                refined_prompt TEXT,
+                error TEXT,
+                prompt_tokens INTEGER,
+                completion_tokens INTEGER,
+                latency_ms INTEGER NOT NULL,
+                cost_usd REAL,
+                chosen INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_candidates_history_id ON candidates(history_id)",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS scores (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                history_id INTEGER NOT NULL,
+                judge TEXT NOT NULL,
+                model TEXT NOT NULL,
+                original_clarity INTEGER NOT NULL,
+                original_specificity INTEGER NOT NULL,
+                original_output_format INTEGER NOT NULL,
+                refined_clarity INTEGER NOT NULL,
+                refined_specificity INTEGER NOT NULL,
+                refined_output_format INTEGER NOT NULL,
+                intent_preservation INTEGER NOT NULL,
+                rationale TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_scores_history_id ON scores(history_id)",
+            [],
+        )?;
+
+        Self::migrate(&conn)?;
+
         Ok(Self { conn })
     }
 
+    /// Add columns missing from databases created by older versions
+    fn migrate(conn: &Connection) -> Result<()> {
+        let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('history')")?;
+        let existing = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (column, column_type) in ADDED_COLUMNS {
+            if !existing.iter().any(|name| name == column) {
+                conn.execute(
+                    &format!("ALTER TABLE history ADD COLUMN {} {}", column, column_type),
+                    [],
+                )
+                .with_context(|| format!("Failed to add history column {}", column))?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Add a new entry
     pub fn add(
         &self,
@@ -66,101 +180,258 @@ impl History {
         refined_prompt: &str,
         provider: &str,
         model: &str,
+        stats: &RefineStats,
     ) -> Result<i64> {
         self.conn.execute(
-            "INSERT INTO history (original_prompt, refined_prompt, provider, model) VALUES (?1, ?2, ?3, ?4)",
-            params![original_prompt, refined_prompt, provider, model],
+            "INSERT INTO history (original_prompt, refined_prompt, provider, model, \
+             prompt_tokens, completion_tokens, latency_ms, cost_usd) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                original_prompt,
+                refined_prompt,
+                provider,
+                model,
+                stats.prompt_tokens.map(|t| t as i64),
+                stats.completion_tokens.map(|t| t as i64),
+                stats.latency_ms as i64,
+                stats.cost_usd,
+            ],
         )?;
 
         Ok(self.conn.last_insert_rowid())
     }
 
-    /// List recent entries
-    pub fn list(&self, limit: usize) -> Result<Vec<HistoryEntry>> {
+    /// Save an interrupted refinement. `refined_prompt` is the best version
+    /// available so far and `clarification` holds any answers already given.
+    pub fn add_draft(
+        &self,
+        original_prompt: &str,
+        refined_prompt: &str,
+        provider: &str,
+        model: &str,
+        clarification: Option<&str>,
+    ) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO history (original_prompt, refined_prompt, provider, model, \
+             draft, clarification) VALUES (?1, ?2, ?3, ?4, 1, ?5)",
+            params![
+                original_prompt,
+                refined_prompt,
+                provider,
+                model,
+                clarification
+            ],
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Save a `--compare` run. The entry takes the winner's prompt, provider
+    /// and stats; without a winner it is recorded under the `compare`
+    /// provider, with the candidates' providers as its model and an empty
+    /// refined prompt.
+    pub fn add_comparison(&self, original_prompt: &str, candidates: &[Candidate]) -> Result<i64> {
+        let tx = self.conn.unchecked_transaction()?;
+
+        let id = match candidates.iter().find(|c| c.chosen) {
+            Some(winner) => self.add(
+                original_prompt,
+                winner.refined_prompt.as_deref().unwrap_or_default(),
+                &winner.provider,
+                &winner.model,
+                &winner.stats,
+            )?,
+            None => {
+                let providers = candidates
+                    .iter()
+                    .map(|c| c.provider.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                self.add(
+                    original_prompt,
+                    "",
+                    "compare",
+                    &providers,
+                    &RefineStats::default(),
+                )?
+            }
+        };
+
+        for candidate in candidates {
+            self.conn.execute(
+                "INSERT INTO candidates (history_id, provider, model, refined_prompt, error, \
+                 prompt_tokens, completion_tokens, latency_ms, cost_usd, chosen) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    id,
+                    candidate.provider,
+                    candidate.model,
+                    candidate.refined_prompt,
+                    candidate.error,
+                    candidate.stats.prompt_tokens.map(|t| t as i64),
+                    candidate.stats.completion_tokens.map(|t| t as i64),
+                    candidate.stats.latency_ms as i64,
+                    candidate.stats.cost_usd,
+                    candidate.chosen,
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Candidates of a `--compare` entry, in the order they were given
+    pub fn candidates(&self, id: i64) -> Result<Vec<Candidate>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, original_prompt, refined_prompt, provider, model, created_at 
-             FROM history 
-             ORDER BY created_at DESC 
-             LIMIT ?1",
+            "SELECT provider, model, refined_prompt, error, prompt_tokens, completion_tokens, \
+             latency_ms, cost_usd, chosen FROM candidates WHERE history_id = ?1 ORDER BY id",
         )?;
 
-        let entries = stmt
-            .query_map(params![limit as i64], |row| {
-                let created_at_str: String = row.get(5)?;
-                let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now());
-
-                Ok(HistoryEntry {
-                    id: row.get(0)?,
-                    original_prompt: row.get(1)?,
+        let candidates = stmt
+            .query_map(params![id], |row| {
+                let prompt_tokens = row.get::<_, Option<i64>>(4)?.map(|t| t as u64);
+                let completion_tokens = row.get::<_, Option<i64>>(5)?.map(|t| t as u64);
+                Ok(Candidate {
+                    provider: row.get(0)?,
+                    model: row.get(1)?,
                     refined_prompt: row.get(2)?,
-                    provider: row.get(3)?,
-                    model: row.get(4)?,
-                    created_at,
+                    error: row.get(3)?,
+                    stats: RefineStats {
+                        prompt_tokens,
+                        completion_tokens,
+                        total_tokens: prompt_tokens.zip(completion_tokens).map(|(p, c)| p + c),
+                        latency_ms: row.get::<_, i64>(6)? as u64,
+                        cost_usd: row.get(7)?,
+                    },
+                    chosen: row.get(8)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(entries)
+        Ok(candidates)
     }
 
-    /// Get a specific entry
-    pub fn get(&self, id: i64) -> Result<Option<HistoryEntry>> {
+    /// Record the questions and answers of an entry's clarification rounds
+    pub fn set_clarification(&self, id: i64, clarification: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE history SET clarification = ?2 WHERE id = ?1",
+            params![id, clarification],
+        )?;
+        Ok(())
+    }
+
+    /// Record which system prompt, and which version of it, produced an entry
+    pub fn set_system_prompt(&self, id: i64, system_prompt: &SystemPrompt) -> Result<()> {
+        self.conn.execute(
+            "UPDATE history SET system_prompt = ?2, system_prompt_hash = ?3 WHERE id = ?1",
+            params![id, system_prompt.name, system_prompt.hash()],
+        )?;
+        Ok(())
+    }
+
+    /// Save a judge's scores for an entry. An entry may be scored more than
+    /// once, by different judges or the same one.
+    pub fn add_score(&self, id: i64, judgement: &Judgement) -> Result<i64> {
+        let (original, refined) = (&judgement.original, &judgement.refined);
+        self.conn.execute(
+            "INSERT INTO scores (history_id, judge, model, original_clarity, \
+             original_specificity, original_output_format, refined_clarity, \
+             refined_specificity, refined_output_format, intent_preservation, rationale) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                id,
+                judgement.judge,
+                judgement.model,
+                original.clarity,
+                original.specificity,
+                original.output_format,
+                refined.clarity,
+                refined.specificity,
+                refined.output_format,
+                judgement.intent_preservation,
+                judgement.rationale,
+            ],
+        )?;
+
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Scores saved for an entry, oldest first
+    pub fn scores(&self, id: i64) -> Result<Vec<Judgement>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, original_prompt, refined_prompt, provider, model, created_at 
-             FROM history 
-             WHERE id = ?1",
+            "SELECT judge, model, original_clarity, original_specificity, \
+             original_output_format, refined_clarity, refined_specificity, \
+             refined_output_format, intent_preservation, rationale \
+             FROM scores WHERE history_id = ?1 ORDER BY id",
         )?;
 
-        let mut entries = stmt
+        let scores = stmt
             .query_map(params![id], |row| {
-                let created_at_str: String = row.get(5)?;
-                let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now());
-
-                Ok(HistoryEntry {
-                    id: row.get(0)?,
-                    original_prompt: row.get(1)?,
-                    refined_prompt: row.get(2)?,
-                    provider: row.get(3)?,
-                    model: row.get(4)?,
-                    created_at,
+                Ok(Judgement {
+                    judge: row.get(0)?,
+                    model: row.get(1)?,
+                    original: RubricScores {
+                        clarity: row.get(2)?,
+                        specificity: row.get(3)?,
+                        output_format: row.get(4)?,
+                    },
+                    refined: RubricScores {
+                        clarity: row.get(5)?,
+                        specificity: row.get(6)?,
+                        output_format: row.get(7)?,
+                    },
+                    intent_preservation: row.get(8)?,
+                    rationale: row.get(9)?,
+                    usage: None,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
 
+        Ok(scores)
+    }
+
+    /// List recent entries
+    pub fn list(&self, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM history ORDER BY created_at DESC LIMIT ?1",
+            ENTRY_COLUMNS
+        ))?;
+
+        let entries = stmt
+            .query_map(params![limit as i64], entry_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(entries)
+    }
+
+    /// Get a specific entry
+    pub fn get(&self, id: i64) -> Result<Option<HistoryEntry>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM history WHERE id = ?1",
+            ENTRY_COLUMNS
+        ))?;
+
+        let mut entries = stmt
+            .query_map(params![id], entry_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
         Ok(entries.pop())
     }
 
     /// Search history
     pub fn search(&self, query: &str) -> Result<Vec<HistoryEntry>> {
         let pattern = format!("%{}%", query);
-        let mut stmt = self.conn.prepare(
-            "SELECT id, original_prompt, refined_prompt, provider, model, created_at 
-             FROM history 
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM history
              WHERE original_prompt LIKE ?1 OR refined_prompt LIKE ?1
-             ORDER BY created_at DESC 
+             ORDER BY created_at DESC
              LIMIT 50",
-        )?;
+            ENTRY_COLUMNS
+        ))?;
 
         let entries = stmt
-            .query_map(params![pattern], |row| {
-                let created_at_str: String = row.get(5)?;
-                let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now());
-
-                Ok(HistoryEntry {
-                    id: row.get(0)?,
-                    original_prompt: row.get(1)?,
-                    refined_prompt: row.get(2)?,
-                    provider: row.get(3)?,
-                    model: row.get(4)?,
-                    created_at,
-                })
-            })?
+            .query_map(params![pattern], entry_from_row)?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(entries)
@@ -169,6 +440,8 @@ impl History {
     /// Clear all history
     pub fn clear(&self) -> Result<usize> {
         let count = self.conn.execute("DELETE FROM history", [])?;
+        self.conn.execute("DELETE FROM candidates", [])?;
+        self.conn.execute("DELETE FROM scores", [])?;
         self.conn.execute("VACUUM", [])?;
         Ok(count)
     }
@@ -181,6 +454,52 @@ impl History {
             )",
             params![max_entries as i64],
         )?;
+        self.conn.execute(
+            "DELETE FROM candidates WHERE history_id NOT IN (SELECT id FROM history)",
+            [],
+        )?;
+        self.conn.execute(
+            "DELETE FROM scores WHERE history_id NOT IN (SELECT id FROM history)",
+            [],
+        )?;
         Ok(count)
     }
+
+    /// Run SQLite's integrity check, failing with its report if the
+    /// database is damaged
+    pub fn integrity_check(&self) -> Result<()> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if problems.len() == 1 && problems[0] == "ok" {
+            return Ok(());
+        }
+        anyhow::bail!("History database is corrupt:\n{}", problems.join("\n"))
+    }
+}
+
+fn entry_from_row(row: &Row<'_>) -> rusqlite::Result<HistoryEntry> {
+    let created_at_str: String = row.get(5)?;
+    let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+        .map(|dt| dt.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now());
+
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        original_prompt: row.get(1)?,
+        refined_prompt: row.get(2)?,
+        provider: row.get(3)?,
+        model: row.get(4)?,
+        created_at,
+        prompt_tokens: row.get::<_, Option<i64>>(6)?.map(|t| t as u64),
+        completion_tokens: row.get::<_, Option<i64>>(7)?.map(|t| t as u64),
+        latency_ms: row.get::<_, Option<i64>>(8)?.map(|t| t as u64),
+        cost_usd: row.get(9)?,
+        draft: row.get(10)?,
+        clarification: row.get(11)?,
+        system_prompt: row.get(12)?,
+        system_prompt_hash: row.get(13)?,
+    })
 }
@@ -0,0 +1,332 @@
+//! LLM-as-judge scoring of refined prompts (`--score`, `prep score`)
+
+use crate::config::ResolvedProvider;
+use crate::providers::{build_provider, ChatMessage};
+use crate::refiner::{extract_json_object, Usage};
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Lowest and highest score on the rubric
+pub const SCORE_RANGE: std::ops::RangeInclusive<u8> = 1..=10;
+
+/// Instructions for the judge model
+pub const JUDGE_PROMPT: &str = r#"You are an impartial evaluator of prompts written for AI assistants. You will be given an ORIGINAL prompt and a REFINED version of it. Do not answer or carry out either prompt; only assess them.
+
+Score each prompt from 1 (very poor) to 10 (excellent) on:
+- clarity: how easy it is to understand what is being asked, without ambiguity
+- specificity: how concrete the requirements, constraints and context are
+- output_format: how well the expected output (format, structure, length) is defined
+
+Then score the refined prompt alone on:
+- intent_preservation: how faithfully it keeps the original's goal, without dropping requirements or adding ones the user did not ask for
+
+Judge the prompts as written. A longer prompt is not better unless the extra detail helps.
+
+Respond with ONLY a JSON object matching this schema:
+{
+  "original": { "clarity": integer, "specificity": integer, "output_format": integer },
+  "refined": { "clarity": integer, "specificity": integer, "output_format": integer },
+  "intent_preservation": integer,
+  "rationale": "string, two or three sentences explaining the scores"
+}"#;
+
+/// Scores of one prompt on the comparative criteria
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RubricScores {
+    pub clarity: u8,
+    pub specificity: u8,
+    pub output_format: u8,
+}
+
+impl RubricScores {
+    /// Average of the three criteria
+    pub fn mean(&self) -> f64 {
+        (self.clarity as f64 + self.specificity as f64 + self.output_format as f64) / 3.0
+    }
+}
+
+/// A judge's assessment of a refinement
+#[derive(Debug, Clone, Serialize)]
+pub struct Judgement {
+    /// Registry name of the judge provider
+    pub judge: String,
+    pub model: String,
+    pub original: RubricScores,
+    pub refined: RubricScores,
+    /// How well the refined prompt keeps the original's intent
+    pub intent_preservation: u8,
+    pub rationale: String,
+    #[serde(skip)]
+    pub usage: Option<Usage>,
+}
+
+/// Ask the `judge` provider to score `refined` against `original`. If the
+/// reply can't be parsed the judge is asked once to correct it.
+pub async fn score(judge: &ResolvedProvider, original: &str, refined: &str) -> Result<Judgement> {
+    let provider = build_provider(judge)?;
+    let mut messages = vec![
+        ChatMessage::system(JUDGE_PROMPT),
+        ChatMessage::user(format!(
+            "ORIGINAL prompt:\n<<<\n{}\n>>>\n\nREFINED prompt:\n<<<\n{}\n>>>",
+            original.trim(),
+            refined.trim()
+        )),
+    ];
+
+    let first = provider.chat(&messages).await?;
+    let (mut judgement, usage) = match parse_judgement(&first.content) {
+        Ok(judgement) => (judgement, first.usage),
+        Err(error) => {
+            tracing::debug!("Invalid judge reply, asking for a correction: {:#}", error);
+            messages.push(ChatMessage::assistant(first.content));
+            messages.push(ChatMessage::user(format!(
+                "Your previous reply could not be used ({}). Respond again with ONLY the JSON \
+                 object described in the instructions, with integer scores from 1 to 10.",
+                error
+            )));
+            let corrected = provider.chat(&messages).await?;
+            let judgement = parse_judgement(&corrected.content)
+                .context("Judge reply was still invalid after a correction")?;
+            (judgement, Usage::sum(first.usage, corrected.usage))
+        }
+    };
+
+    judgement.judge = judge.name.clone();
+    judgement.model = judge.model.clone();
+    judgement.usage = usage;
+    Ok(judgement)
+}
+
+/// Parse a judge reply. Scores may be numbers or numeric strings, and must
+/// be within [`SCORE_RANGE`].
+pub fn parse_judgement(content: &str) -> Result<Judgement> {
+    let object =
+        extract_json_object(content).context("Judge reply did not contain a JSON object")?;
+
+    let rationale = match object.get("rationale") {
+        Some(Value::String(text)) => text.trim().to_string(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => String::new(),
+    };
+
+    Ok(Judgement {
+        judge: String::new(),
+        model: String::new(),
+        original: rubric(&object, "original")?,
+        refined: rubric(&object, "refined")?,
+        intent_preservation: score_field(&object, "intent_preservation")?,
+        rationale,
+        usage: None,
+    })
+}
+
+/// The scores of one prompt from its nested object
+fn rubric(object: &Map<String, Value>, key: &str) -> Result<RubricScores> {
+    let scores = object
+        .get(key)
+        .and_then(Value::as_object)
+        .with_context(|| format!("missing \"{}\" scores", key))?;
+    let field =
+        |name: &str| score_field(scores, name).with_context(|| format!("in \"{}\" scores", key));
+    Ok(RubricScores {
+        clarity: field("clarity")?,
+        specificity: field("specificity")?,
+        output_format: field("output_format")?,
+    })
+}
+
+/// A single score, rounded to a whole number and range-checked
+fn score_field(object: &Map<String, Value>, name: &str) -> Result<u8> {
+    let value = match object.get(name) {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+    .with_context(|| format!("missing or non-numeric \"{}\"", name))?;
+
+    let rounded = value.round();
+    let range = *SCORE_RANGE.start() as f64..=*SCORE_RANGE.end() as f64;
+    if !range.contains(&rounded) {
+        anyhow::bail!(
+            "\"{}\" is {}, outside {}-{}",
+            name,
+            value,
+            SCORE_RANGE.start(),
+            SCORE_RANGE.end()
+        );
+    }
+    Ok(rounded as u8)
+}
+
+/// A table of the scores followed by the rationale
+pub fn render(judgement: &Judgement, color: bool) -> String {
+    let delta = |original: f64, refined: f64| {
+        let change = refined - original;
+        let text = if change.fract() == 0.0 {
+            format!("{:+}", change)
+        } else {
+            format!("{:+.1}", change)
+        };
+        if !color {
+            text
+        } else if change > 0.0 {
+            text.green().to_string()
+        } else if change < 0.0 {
+            text.red().to_string()
+        } else {
+            text.bright_black().to_string()
+        }
+    };
+    let row = |name: &str, original: u8, refined: u8| {
+        format!(
+            "{:<21}{:>8}{:>9}  {}",
+            name,
+            original,
+            refined,
+            delta(original as f64, refined as f64)
+        )
+    };
+
+    let (original, refined) = (&judgement.original, &judgement.refined);
+    let heading = format!("{:<21}{:>8}{:>9}", "", "Original", "Refined");
+    let mut lines = vec![
+        if color {
+            heading.bold().to_string()
+        } else {
+            heading
+        },
+        row("Clarity", original.clarity, refined.clarity),
+        row("Specificity", original.specificity, refined.specificity),
+        row(
+            "Output format",
+            original.output_format,
+            refined.output_format,
+        ),
+        format!(
+            "{:<21}{:>8}{:>9}",
+            "Intent preservation", "", judgement.intent_preservation
+        ),
+        format!(
+            "{:<21}{:>8.1}{:>9.1}  {}",
+            "Mean",
+            original.mean(),
+            refined.mean(),
+            delta(original.mean(), refined.mean())
+        ),
+    ];
+    if !judgement.rationale.is_empty() {
+        lines.push(String::new());
+        lines.push(judgement.rationale.clone());
+    }
+    let judged_by = format!("Judged by {} ({})", judgement.judge, judgement.model);
+    lines.push(if color {
+        judged_by.bright_black().to_string()
+    } else {
+        judged_by
+    });
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = r#"{"original": {"clarity": 3, "specificity": 2, "output_format": 1},
+        "refined": {"clarity": 8, "specificity": 7, "output_format": 9},
+        "intent_preservation": 10, "rationale": " Clearer. "}"#;
+
+    fn scores(clarity: u8, specificity: u8, output_format: u8) -> RubricScores {
+        RubricScores {
+            clarity,
+            specificity,
+            output_format,
+        }
+    }
+
+    #[test]
+    fn parse_judgement_accepts_loose_replies() {
+        let cases = [
+            ("plain", VALID.to_string()),
+            ("fenced", format!("```json\n{}\n```", VALID)),
+            (
+                "prose",
+                format!("Here is my assessment:\n{}\nThanks!", VALID),
+            ),
+            (
+                "strings and fractions",
+                r#"{"original": {"clarity": "3", "specificity": 2.4, "output_format": " 1 "},
+                    "refined": {"clarity": 7.5, "specificity": "7", "output_format": 9.0},
+                    "intent_preservation": "10", "rationale": ["Clearer."]}"#
+                    .to_string(),
+            ),
+        ];
+        for (name, content) in cases {
+            let judgement =
+                parse_judgement(&content).unwrap_or_else(|e| panic!("{}: {:#}", name, e));
+            assert_eq!(judgement.original, scores(3, 2, 1), "{}", name);
+            assert_eq!(judgement.refined, scores(8, 7, 9), "{}", name);
+            assert_eq!(judgement.intent_preservation, 10, "{}", name);
+            assert_eq!(judgement.rationale, "Clearer.", "{}", name);
+        }
+
+        let without_rationale = VALID.replace(r#""rationale": " Clearer. ""#, r#""note": 1"#);
+        assert_eq!(parse_judgement(&without_rationale).unwrap().rationale, "");
+    }
+
+    #[test]
+    fn parse_judgement_rejects_bad_scores() {
+        let cases = [
+            (
+                "no JSON",
+                "The refined prompt is better.".to_string(),
+                "did not contain a JSON object",
+            ),
+            (
+                "missing rubric",
+                VALID.replace(r#""original""#, r#""before""#),
+                "missing \"original\" scores",
+            ),
+            (
+                "missing field",
+                VALID.replace(r#""clarity": 8"#, r#""clear": 8"#),
+                "in \"refined\" scores",
+            ),
+            (
+                "non-numeric",
+                VALID.replace(
+                    r#""intent_preservation": 10"#,
+                    r#""intent_preservation": "high""#,
+                ),
+                "non-numeric \"intent_preservation\"",
+            ),
+            (
+                "too high",
+                VALID.replace(
+                    r#""intent_preservation": 10"#,
+                    r#""intent_preservation": 10.6"#,
+                ),
+                "outside 1-10",
+            ),
+            (
+                "too low",
+                VALID.replace(r#""clarity": 3"#, r#""clarity": 0"#),
+                "outside 1-10",
+            ),
+            (
+                "not a number",
+                VALID.replace(r#""clarity": 3"#, r#""clarity": "NaN""#),
+                "outside 1-10",
+            ),
+        ];
+        for (name, content, expected) in cases {
+            let error = format!("{:#}", parse_judgement(&content).unwrap_err());
+            assert!(error.contains(expected), "{}: {}", name, error);
+        }
+    }
+}
@@ -1,12 +1,18 @@
 //! Prep - A beautiful CLI tool to refine prompts for AI assistants
 
+pub mod cache;
+pub mod clarify;
 pub mod cli;
+pub mod compare;
 pub mod config;
+pub mod doctor;
 pub mod history;
+pub mod judge;
 pub mod providers;
 pub mod refiner;
+pub mod system_prompt;
 pub mod templates;
 pub mod ui;
 
 pub use config::Config;
-pub use refiner::{Refiner, RefinerResponse};
+pub use refiner::{RefineOutcome, Refiner, RefinerResponse};
@@ -1,35 +1,50 @@
 use anyhow::{Context, Result};
 use arboard::Clipboard;
-use clap::{CommandFactory, Parser};
+use clap::CommandFactory;
 use clap_complete::generate;
 use colored::control::set_override;
 use dialoguer::Confirm;
+use serde::Serialize;
+use std::future::Future;
 use std::io::{self, Read, Write};
+use std::sync::Arc;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
+use prep::cache::ResponseCache;
+use prep::clarify::InteractiveClarifier;
 use prep::cli::{
-    Cli, Commands, ConfigAction, HistoryAction, OutputFormat, ProviderChoice, TemplateAction,
+    CacheAction, Cli, Commands, ConfigAction, HistoryAction, OutputFormat, TemplateAction,
 };
+use prep::compare::{self, CompareResult};
 use prep::config::Config;
+use prep::config::ResolvedProvider;
+use prep::doctor;
 use prep::history::History;
+use prep::judge::{self, Judgement};
 use prep::providers::{
-    AnthropicProvider, OllamaCloudProvider, OllamaLocalProvider, OpenAIProvider, Provider,
+    build_provider, check_model, new_conversation, Cassette, GenerationParams, ModelInfo,
+    RetryObserver, RetryPolicy,
+};
+use prep::refiner::{
+    apply_template, RefineEvent, RefineObserver, RefineStats, Refiner, RefinerResponse,
 };
-use prep::refiner::{build_clarification_summary, RefinerResponse};
 use prep::templates;
-use prep::ui::UI;
+use prep::ui::{ctrl_c, Interrupted, EXIT_INTERRUPTED, UI};
 
 #[tokio::main]
 async fn main() {
     if let Err(e) = run().await {
+        if e.is::<Interrupted>() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
         eprintln!("\x1b[31m✗ Error: {:#}\x1b[0m", e);
         std::process::exit(1);
     }
 }
 
 async fn run() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = Cli::parse_args();
 
     // Handle color override
     if cli.no_color {
@@ -49,6 +64,11 @@ async fn run() -> Result<()> {
         tracing::subscriber::set_global_default(subscriber).ok();
     }
 
+    // Doctor reports config errors instead of failing on them
+    if let Some(Commands::Doctor { all }) = cli.command {
+        return handle_doctor(all, &cli).await;
+    }
+
     // Load config
     let config = Config::load().context("Failed to load configuration")?;
 
@@ -60,6 +80,12 @@ async fn run() -> Result<()> {
         Some(Commands::Config { action }) => handle_config(action, &ui)?,
         Some(Commands::History { action }) => handle_history(action, &ui)?,
         Some(Commands::Templates { action }) => handle_templates(action, &ui)?,
+        Some(Commands::Models { ref provider }) => {
+            handle_models(provider.as_deref(), &cli, &config, &ui).await?
+        }
+        Some(Commands::Cache { ref action }) => handle_cache(action, &cli, &config, &ui)?,
+        Some(Commands::Score { id }) => handle_score(id, &cli, &config, &ui).await?,
+        Some(Commands::Doctor { .. }) => unreachable!("handled before loading config"),
         Some(Commands::Completions { shell }) => {
             let mut cmd = Cli::command();
             generate(shell, &mut cmd, "prep", &mut io::stdout());
@@ -133,6 +159,12 @@ fn handle_history(action: HistoryAction, ui: &UI) -> Result<()> {
                 );
                 ui.kv("Provider", &entry.provider);
                 ui.kv("Model", &entry.model);
+                if let Some(cost) = entry.cost_usd {
+                    ui.kv("Cost", &format_cost(cost));
+                }
+                if entry.draft {
+                    ui.kv("Status", "Draft (interrupted)");
+                }
 
                 let preview = entry.original_prompt.chars().take(60).collect::<String>();
                 ui.kv("Prompt", &format!("{}...", preview));
@@ -148,10 +180,56 @@ fn handle_history(action: HistoryAction, ui: &UI) -> Result<()> {
                 );
                 ui.kv("Provider", &entry.provider);
                 ui.kv("Model", &entry.model);
+                if entry.prompt_tokens.is_some() || entry.completion_tokens.is_some() {
+                    ui.kv(
+                        "Tokens",
+                        &format_tokens(entry.prompt_tokens, entry.completion_tokens),
+                    );
+                }
+                if let Some(latency_ms) = entry.latency_ms {
+                    ui.kv("Latency", &format_latency(latency_ms));
+                }
+                if let Some(cost) = entry.cost_usd {
+                    ui.kv("Cost", &format_cost(cost));
+                }
+                if let (Some(name), Some(hash)) = (&entry.system_prompt, &entry.system_prompt_hash)
+                {
+                    ui.kv("System prompt", &format!("{} (sha256 {})", name, hash));
+                }
+                if entry.draft {
+                    ui.kv("Status", "Draft (interrupted)");
+                }
                 println!();
                 ui.boxed(&entry.original_prompt, Some("Original Prompt"));
-                println!();
-                ui.boxed(&entry.refined_prompt, Some("Refined Prompt"));
+                if let Some(clarification) = &entry.clarification {
+                    println!();
+                    ui.boxed(clarification, Some("Clarifications"));
+                }
+                if !entry.refined_prompt.is_empty() {
+                    println!();
+                    ui.boxed(&entry.refined_prompt, Some("Refined Prompt"));
+                }
+                for candidate in history.candidates(id)? {
+                    let mut title = format!(
+                        "{} ({}), {}",
+                        candidate.provider,
+                        candidate.model,
+                        format_latency(candidate.stats.latency_ms)
+                    );
+                    if candidate.chosen {
+                        title.push_str(", picked");
+                    }
+                    let text = match (&candidate.refined_prompt, &candidate.error) {
+                        (Some(prompt), _) => prompt.clone(),
+                        (None, error) => format!("Failed: {}", error.as_deref().unwrap_or("")),
+                    };
+                    println!();
+                    ui.boxed(&text, Some(&title));
+                }
+                for judgement in history.scores(id)? {
+                    println!();
+                    ui.boxed(&judge::render(&judgement, false), Some("Score"));
+                }
             }
             None => {
                 ui.error(&format!("No history entry with ID {}", id));
@@ -197,6 +275,185 @@ fn handle_history(action: HistoryAction, ui: &UI) -> Result<()> {
     Ok(())
 }
 
+fn handle_cache(action: &CacheAction, cli: &Cli, config: &Config, ui: &UI) -> Result<()> {
+    let cache = ResponseCache::open(&config.cache)?;
+
+    match action {
+        CacheAction::Stats => {
+            let stats = cache.stats()?;
+            if cli.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+                return Ok(());
+            }
+
+            ui.header("Response Cache");
+            println!();
+            ui.kv("Path", &stats.path.display().to_string());
+            if !config.cache.enabled {
+                ui.kv("Status", "Disabled (cache.enabled = false)");
+            }
+            ui.kv("Entries", &stats.entries.to_string());
+            ui.kv("Size", &format_bytes(stats.size_bytes));
+            ui.kv("Hits", &stats.hits.to_string());
+            ui.kv("Tokens saved", &stats.tokens_saved.to_string());
+            if let (Some(oldest), Some(newest)) = (stats.oldest, stats.newest) {
+                ui.kv("Oldest", &oldest.format("%Y-%m-%d %H:%M").to_string());
+                ui.kv("Newest", &newest.format("%Y-%m-%d %H:%M").to_string());
+            }
+            ui.kv(
+                "Limits",
+                &format!(
+                    "{}s TTL, {} entries, {} MB",
+                    config.cache.ttl_secs, config.cache.max_entries, config.cache.max_size_mb
+                ),
+            );
+        }
+        CacheAction::Clear => {
+            let count = cache.clear()?;
+            ui.success(&format!("Cleared {} cached responses.", count));
+        }
+    }
+    Ok(())
+}
+
+async fn handle_models(provider: Option<&str>, cli: &Cli, config: &Config, ui: &UI) -> Result<()> {
+    let resolved = config.resolve_provider(
+        provider.or(cli.provider.as_deref()),
+        None,
+        cli.api_key.as_deref(),
+    )?;
+    let provider = build_provider(&resolved)?;
+
+    let spinner = ui.spinner(&format!("Fetching models from {}...", resolved.name));
+    let models = provider.list_models().await;
+    if let Some(pb) = spinner {
+        pb.finish_and_clear();
+    }
+    let mut models = models?;
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+
+    if matches!(cli.output, OutputFormat::Json) {
+        #[derive(Serialize)]
+        struct ModelEntry<'a> {
+            #[serde(flatten)]
+            model: &'a ModelInfo,
+            default: bool,
+        }
+        let entries: Vec<_> = models
+            .iter()
+            .map(|model| ModelEntry {
+                model,
+                default: model.matches(&resolved.model),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    if models.is_empty() {
+        ui.info(&format!("{} reported no models.", resolved.name));
+        return Ok(());
+    }
+
+    ui.header(&format!("Models for {} ({})", resolved.name, resolved.kind));
+    println!();
+    let width = models.iter().map(|m| m.id.len()).max().unwrap_or(0);
+    for model in &models {
+        let mut details = Vec::new();
+        if let Some(name) = &model.display_name {
+            details.push(name.clone());
+        }
+        if let Some(params) = &model.parameter_size {
+            details.push(params.clone());
+        }
+        if let Some(bytes) = model.size_bytes {
+            details.push(format!("{:.1} GB", bytes as f64 / 1e9));
+        }
+        if let Some(context) = model.context_length {
+            details.push(format!("{}k context", context / 1000));
+        }
+
+        let bullet = if model.matches(&resolved.model) {
+            "*"
+        } else {
+            " "
+        };
+        ui.list_item(
+            bullet,
+            format!(
+                "{:<width$}  {}",
+                model.id,
+                details.join(", "),
+                width = width
+            )
+            .trim_end(),
+        );
+    }
+    println!();
+    ui.info(&format!("* configured default ({})", resolved.model));
+
+    Ok(())
+}
+
+async fn handle_doctor(all: bool, cli: &Cli) -> Result<()> {
+    let loaded = Config::load();
+    let (color, spinner) = loaded
+        .as_ref()
+        .map(|c| (c.ui.color, c.ui.spinner))
+        .unwrap_or((true, true));
+    let ui = UI::new(!cli.no_color && color, spinner);
+
+    let spinner = ui.spinner("Running checks...");
+    let checks = doctor::run(loaded, all).await;
+    if let Some(pb) = spinner {
+        pb.finish_and_clear();
+    }
+
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    let failed = count(doctor::Status::Fail);
+
+    if matches!(cli.output, OutputFormat::Json) {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        let mut section = None;
+        for check in &checks {
+            if section != Some(&check.section) {
+                ui.header(&check.section);
+                section = Some(&check.section);
+            }
+            let line = format!("{}: {}", check.name, check.message);
+            match check.status {
+                doctor::Status::Pass => ui.success(&line),
+                doctor::Status::Warn => ui.warning(&line),
+                doctor::Status::Fail => ui.error(&line),
+                doctor::Status::Skip => ui.status(&line),
+            }
+            if let Some(fix) = &check.fix {
+                ui.hint(fix);
+            }
+        }
+
+        eprintln!();
+        let summary = format!(
+            "{} passed, {} warnings, {} failed, {} skipped",
+            count(doctor::Status::Pass),
+            count(doctor::Status::Warn),
+            failed,
+            count(doctor::Status::Skip)
+        );
+        if failed > 0 {
+            ui.error(&summary);
+        } else {
+            ui.success(&summary);
+        }
+    }
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
 fn handle_templates(action: TemplateAction, ui: &UI) -> Result<()> {
     match action {
         TemplateAction::List => {
@@ -251,15 +508,6 @@ async fn handle_refine(cli: Cli, config: Config, ui: UI) -> Result<()> {
         anyhow::bail!("No prompt provided. Pass a prompt as arguments or pipe it via stdin.\n\nUsage: prep \"your prompt here\"\n       echo \"your prompt\" | prep");
     }
 
-    // Apply template if specified
-    let raw_prompt = if let Some(template_name) = &cli.template {
-        let template = templates::get_template(template_name)
-            .with_context(|| format!("Unknown template: {}", template_name))?;
-        template.apply(&raw_prompt)
-    } else {
-        raw_prompt
-    };
-
     // Load context file if specified
     let context = if let Some(path) = &cli.context {
         let content = std::fs::read_to_string(path)
@@ -269,26 +517,50 @@ async fn handle_refine(cli: Cli, config: Config, ui: UI) -> Result<()> {
         None
     };
 
-    // Determine provider
-    let provider_choice = cli.provider.unwrap_or_else(|| {
-        config
-            .get_default_provider()
-            .unwrap_or(ProviderChoice::OllamaLocal)
-    });
-
-    // Get model
-    let model = config.get_model(provider_choice, cli.model.as_deref());
+    if !cli.compare.is_empty() {
+        let prompt = apply_template(&raw_prompt, cli.template.as_deref())?;
+        return handle_compare(&cli, &config, ui, &raw_prompt, &prompt, context.as_deref()).await;
+    }
 
-    // Get API key
-    let api_key = config.get_api_key(provider_choice, cli.api_key.as_deref());
+    // Resolve the provider and its fallbacks from the registry
+    let mut chain = config.resolve_chain(
+        cli.provider.as_deref(),
+        &cli.fallback,
+        cli.model.as_deref(),
+        cli.api_key.as_deref(),
+    )?;
 
-    // Get endpoint
-    let endpoint = config.get_endpoint(provider_choice);
+    let cassette = cli_cassette(&cli)?;
+    for resolved in &mut chain {
+        apply_cli_overrides(resolved, &cli, &cassette)?;
+    }
+    let primary = &chain[0];
+    let judge = cli
+        .score
+        .then(|| resolve_judge(&cli, &config, &cassette))
+        .transpose()?;
+    let system_prompt = config.resolve_system_prompt(cli.system_prompt.as_deref())?;
 
     if cli.verbose {
-        ui.debug("Provider", &format!("{}", provider_choice));
-        ui.debug("Model", &model);
-        ui.debug("Endpoint", &endpoint);
+        ui.debug("Provider", &format!("{} ({})", primary.name, primary.kind));
+        ui.debug("Model", &primary.model);
+        ui.debug("Endpoint", &primary.endpoint);
+        if primary.params != GenerationParams::default() {
+            ui.debug("Params", &primary.params.to_string());
+        }
+        if chain.len() > 1 {
+            ui.debug("Fallback", &fallback_names(&chain));
+        }
+        ui.debug("System prompt", &system_prompt.to_string());
+        if let Some(judge) = &judge {
+            ui.debug("Judge", &format!("{} ({})", judge.name, judge.model));
+        }
+        if let Some(cassette) = &cassette {
+            ui.debug(
+                "Cassette",
+                &format!("{:?} {}", cassette.mode(), cassette.path().display()),
+            );
+        }
         if cli.dry_run {
             ui.debug("Mode", "Dry run");
         }
@@ -297,11 +569,22 @@ async fn handle_refine(cli: Cli, config: Config, ui: UI) -> Result<()> {
     // Handle dry run
     if cli.dry_run {
         ui.header("Dry Run");
-        ui.kv("Provider", &format!("{}", provider_choice));
-        ui.kv("Model", &model);
-        ui.kv("Endpoint", &endpoint);
+        ui.kv("Provider", &format!("{} ({})", primary.name, primary.kind));
+        ui.kv("Model", &primary.model);
+        ui.kv("Endpoint", &primary.endpoint);
+        if primary.params != GenerationParams::default() {
+            ui.kv("Params", &primary.params.to_string());
+        }
+        if chain.len() > 1 {
+            ui.kv("Fallback", &fallback_names(&chain));
+        }
+        ui.kv("System prompt", &system_prompt.to_string());
+        if let Some(judge) = &judge {
+            ui.kv("Judge", &format!("{} ({})", judge.name, judge.model));
+        }
         println!();
-        ui.boxed(&raw_prompt, Some("Prompt to be sent"));
+        let prompt = apply_template(&raw_prompt, cli.template.as_deref())?;
+        ui.boxed(&prompt, Some("Prompt to be sent"));
         if let Some(ctx) = &context {
             println!();
             ui.boxed(ctx, Some("Context"));
@@ -309,84 +592,125 @@ async fn handle_refine(cli: Cli, config: Config, ui: UI) -> Result<()> {
         return Ok(());
     }
 
-    // Create provider
-    let provider: Box<dyn Provider> = match provider_choice {
-        ProviderChoice::OllamaLocal => Box::new(OllamaLocalProvider::new(endpoint, model.clone())),
-        ProviderChoice::OllamaCloud => {
-            let key = api_key.context(
-                "Ollama Cloud requires an API key. Set OLLAMA_API_KEY environment variable or use --api-key."
-            )?;
-            Box::new(OllamaCloudProvider::new(endpoint, model.clone(), key))
-        }
-        ProviderChoice::OpenAI => {
-            let key = api_key.context(
-                "OpenAI requires an API key. Set OPENAI_API_KEY environment variable or use --api-key."
-            )?;
-            Box::new(OpenAIProvider::new(endpoint, model.clone(), key))
-        }
-        ProviderChoice::Anthropic => {
-            let key = api_key.context(
-                "Anthropic requires an API key. Set ANTHROPIC_API_KEY environment variable or use --api-key."
-            )?;
-            Box::new(AnthropicProvider::new(endpoint, model.clone(), key))
-        }
-    };
-
-    // First refinement call
-    let spinner = ui.spinner(&format!(
-        "Refining prompt with {} ({})...",
-        provider.name(),
-        provider.model()
-    ));
+    // Catch typos in --model before sending the refinement request
+    if cli.model.is_some() {
+        validate_model(primary, &ui, cli.verbose).await?;
+    }
 
-    let response = provider.refine(&raw_prompt, context.as_deref(), None).await;
+    // Show retries of transient failures in the spinner
+    let ui = Arc::new(ui);
+    let retry_ui = Arc::clone(&ui);
+    let retry_observer: RetryObserver =
+        Arc::new(move |notice| retry_ui.spinner_status(&notice.to_string()));
 
-    if let Some(pb) = spinner {
-        pb.finish_and_clear();
+    // Cassettes bypass the cache so recordings hold real exchanges
+    let mut refiner = Refiner::new(config.clone())
+        .with_chain(chain)
+        .with_system_prompt(system_prompt)
+        .with_history(config.history.enabled && !cli.no_history)
+        .with_cache(!cli.no_cache && cassette.is_none())
+        .with_refresh(cli.refresh)
+        .with_stream(cli.stream || config.ui.stream)
+        .with_max_rounds(cli.max_rounds.unwrap_or(config.clarification.max_rounds))
+        .with_ctrl_c(true)
+        .with_observer(refine_observer(Arc::clone(&ui), cli.verbose))
+        .with_retry_observer(retry_observer);
+    if let Some(template) = &cli.template {
+        refiner = refiner.with_template(template);
+    }
+    if let Some(context) = context {
+        refiner = refiner.with_context(context);
+    }
+    if atty::is(atty::Stream::Stdin) {
+        refiner = refiner.with_clarification_handler(InteractiveClarifier::new(Arc::clone(&ui)));
     }
 
-    let response = response?;
+    let outcome = refiner.refine(&raw_prompt).await?;
 
-    // Handle clarification if needed
-    let final_response = if response.needs_clarification && !response.questions.is_empty() {
-        // Check if we're in an interactive terminal
-        if !atty::is(atty::Stream::Stdin) {
-            // Non-interactive mode: output questions and use initial refined prompt
-            ui.warning("Clarification needed but running non-interactively.");
-            ui.info("Questions the AI wanted to ask:");
-            for (i, q) in response.questions.iter().enumerate() {
-                eprintln!("  Q{}: {}", i + 1, q);
-            }
-            ui.info("Using initial refined prompt. Re-run interactively for better results.");
-            response
-        } else {
-            let answers = ui.ask_questions(&response.questions)?;
-            let summary = build_clarification_summary(&response.questions, &answers);
+    if cli.verbose {
+        ui.debug(
+            "Served by",
+            &format!("{} ({})", outcome.provider, outcome.model),
+        );
+    }
 
-            let spinner = ui.spinner("Refining with clarifications...");
+    // Non-interactive mode: show the questions that went unanswered
+    let max_rounds = cli.max_rounds.unwrap_or(config.clarification.max_rounds);
+    let response = &outcome.response;
+    if let Some(round) = outcome.clarifications.last().filter(|r| r.skipped) {
+        ui.warning("Clarification needed but running non-interactively.");
+        ui.info("Questions the AI wanted to ask:");
+        for (i, q) in round.questions.iter().enumerate() {
+            eprintln!("  Q{}: {}", i + 1, q);
+        }
+        ui.info("Using initial refined prompt. Re-run interactively for better results.");
+    } else if max_rounds > 0
+        && outcome.clarifications.len() == max_rounds as usize
+        && response.needs_clarification
+        && !response.questions.is_empty()
+    {
+        ui.warning(&format!(
+            "Stopped after {} clarification round{}; the model still had questions:",
+            max_rounds,
+            if max_rounds == 1 { "" } else { "s" }
+        ));
+        for (i, q) in response.questions.iter().enumerate() {
+            eprintln!("  Q{}: {}", i + 1, q);
+        }
+        ui.info("Raise --max-rounds or clarification.max_rounds to answer them.");
+    }
 
-            let final_resp = provider
-                .refine(&raw_prompt, context.as_deref(), Some(&summary))
-                .await;
+    let stats = &outcome.stats;
+    if cli.verbose {
+        ui.debug(
+            "Tokens",
+            &format_tokens(stats.prompt_tokens, stats.completion_tokens),
+        );
+        ui.debug("Latency", &format_latency(stats.latency_ms));
+        if let Some(cost) = stats.cost_usd {
+            ui.debug("Cost", &format_cost(cost));
+        }
+    }
 
-            if let Some(pb) = spinner {
-                pb.finish_and_clear();
+    // Score before output so the scores can be part of the JSON. A failed
+    // score is reported but doesn't cost the user the refinement.
+    let judgement = match &judge {
+        Some(judge) => {
+            match score_with_progress(
+                judge,
+                &ui,
+                &outcome.prompt,
+                &outcome.response.refined_prompt,
+            )
+            .await
+            {
+                Ok(judgement) => Some(judgement),
+                Err(e) if e.is::<Interrupted>() => return Err(e),
+                Err(e) => {
+                    ui.warning(&format!("Scoring failed: {:#}", e));
+                    None
+                }
             }
-
-            final_resp?
         }
-    } else {
-        response
+        None => None,
     };
 
     // Output the result
-    output_result(&cli, &final_response, &ui)?;
+    output_result(
+        &cli,
+        &outcome.response,
+        stats,
+        judgement.as_ref(),
+        outcome.cached,
+        outcome.streamed,
+        &ui,
+    )?;
 
     // Copy to clipboard if requested
     if cli.copy || config.default.copy_to_clipboard {
         match Clipboard::new() {
             Ok(mut clipboard) => {
-                clipboard.set_text(&final_response.refined_prompt)?;
+                clipboard.set_text(&outcome.response.refined_prompt)?;
                 ui.success("Copied to clipboard!");
             }
             Err(e) => {
@@ -395,16 +719,208 @@ async fn handle_refine(cli: Cli, config: Config, ui: UI) -> Result<()> {
         }
     }
 
-    // Save to history
+    // The refiner saved the entry; attach the scores to it
+    if let (Some(id), Some(judgement)) = (outcome.history_id, &judgement) {
+        if let Ok(history) = History::open() {
+            let _ = history.add_score(id, judgement);
+        }
+    }
+
+    Ok(())
+}
+
+/// Show the refiner's progress: a spinner per request, streamed text,
+/// fallbacks and interruptions
+fn refine_observer(ui: Arc<UI>, verbose: bool) -> RefineObserver {
+    Arc::new(move |event| match event {
+        RefineEvent::Requesting {
+            provider,
+            model,
+            round,
+        } => {
+            let message = if round > 0 {
+                "Refining with clarifications...".to_string()
+            } else {
+                format!("Refining prompt with {} ({})...", provider, model)
+            };
+            ui.spinner(&message);
+        }
+        RefineEvent::Token(text) => {
+            ui.finish_spinner();
+            ui.stream_text(text);
+        }
+        RefineEvent::Finished { streamed, replaced } => {
+            ui.finish_spinner();
+            if streamed {
+                eprintln!();
+            }
+            if replaced {
+                ui.warning("The streamed reply was invalid; showing the corrected prompt instead");
+            }
+        }
+        RefineEvent::FallingBack { from, to, error } => {
+            ui.warning(&format!("{} is unavailable, falling back to {}", from, to));
+            if verbose {
+                ui.debug("Fallback", &format!("{}: {:#}", from, error));
+            }
+        }
+        RefineEvent::CacheHit { provider, stored } => {
+            if verbose {
+                ui.debug(
+                    "Cache",
+                    &format!(
+                        "hit for {}, stored {}",
+                        provider,
+                        stored.format("%Y-%m-%d %H:%M:%S")
+                    ),
+                );
+            }
+        }
+        RefineEvent::Interrupted => {
+            ui.reset_after_interrupt();
+            ui.warning("Interrupted");
+        }
+        RefineEvent::DraftSaved(id) => ui.info(&format!(
+            "Draft saved to history as #{} (prep history show {})",
+            id, id
+        )),
+    })
+}
+
+/// Refine with every `--compare` target at once, show the results side by
+/// side and record them, with the winner the user picks, in history.
+///
+/// `prompt` is `raw_prompt` with any template applied and is what the
+/// providers receive; history keeps `raw_prompt`, as the user typed it.
+async fn handle_compare(
+    cli: &Cli,
+    config: &Config,
+    ui: UI,
+    raw_prompt: &str,
+    prompt: &str,
+    context: Option<&str>,
+) -> Result<()> {
+    let system_prompt = config.resolve_system_prompt(cli.system_prompt.as_deref())?;
+    let cassette = cli_cassette(cli)?;
+    let mut targets = Vec::new();
+    for spec in &cli.compare {
+        let (name, model) = compare::parse_target(spec);
+        let mut resolved = config.resolve_provider(Some(name), model, None)?;
+        apply_cli_overrides(&mut resolved, cli, &cassette)?;
+        targets.push(resolved);
+    }
+    if targets.len() < 2 {
+        anyhow::bail!("--compare needs at least two providers, e.g. --compare ollama,openai");
+    }
+
+    let names = targets
+        .iter()
+        .map(|r| format!("{} ({})", r.name, r.model))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if cli.dry_run {
+        ui.header("Dry Run");
+        ui.kv("Compare", &names);
+        ui.kv("System prompt", &system_prompt.to_string());
+        println!();
+        ui.boxed(prompt, Some("Prompt to be sent"));
+        if let Some(ctx) = context {
+            println!();
+            ui.boxed(ctx, Some("Context"));
+        }
+        return Ok(());
+    }
+    if cli.verbose {
+        ui.debug("Compare", &names);
+        ui.debug("System prompt", &system_prompt.to_string());
+    }
+
+    let conversation = new_conversation(&system_prompt.content, prompt, context);
+    let spinner = ui.spinner(&format!(
+        "Refining prompt with {} providers...",
+        targets.len()
+    ));
+    let results = cancellable(&ui, async {
+        Ok(compare::run(&targets, &conversation, config).await)
+    })
+    .await;
+    if let Some(pb) = spinner {
+        pb.finish_and_clear();
+    }
+    let results = results?;
+
+    let color = !cli.no_color && config.ui.color;
+    match cli.output {
+        OutputFormat::Text => {
+            let width = console::Term::stderr()
+                .size_checked()
+                .map_or(120, |(_, cols)| cols as usize);
+            eprintln!();
+            eprintln!("{}", compare::render(&results, width, color));
+            eprintln!();
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&results)?),
+        OutputFormat::Markdown => {
+            for result in &results {
+                println!("## {}\n", result.label());
+                match (&result.response, &result.error) {
+                    (Some(response), _) => println!("{}\n", response.refined_prompt),
+                    (None, error) => println!("_Failed: {}_\n", error.as_deref().unwrap_or("")),
+                }
+            }
+        }
+    }
+
+    let answered: Vec<&CompareResult> = results.iter().filter(|r| r.response.is_some()).collect();
+    if answered.is_empty() {
+        anyhow::bail!("Every provider failed");
+    }
+
+    // Only ask for a winner when someone is there to answer
+    let interactive = cli.output == OutputFormat::Text
+        && atty::is(atty::Stream::Stdin)
+        && atty::is(atty::Stream::Stderr);
+    let winner = if interactive {
+        let labels = answered.iter().map(|r| r.label()).collect::<Vec<_>>();
+        match ui.choose("Which refinement is best?", &labels) {
+            Ok(choice) => choice.map(|i| answered[i]),
+            Err(e) => {
+                if e.is::<Interrupted>() {
+                    ui.reset_after_interrupt();
+                    ui.warning("Interrupted");
+                }
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    if let Some(winner) = winner {
+        let refined = &winner.response.as_ref().expect("answered").refined_prompt;
+        println!("{}", refined);
+        if cli.copy || config.default.copy_to_clipboard {
+            match Clipboard::new() {
+                Ok(mut clipboard) => {
+                    clipboard.set_text(refined)?;
+                    ui.success("Copied to clipboard!");
+                }
+                Err(e) => {
+                    ui.warning(&format!("Could not copy to clipboard: {}", e));
+                }
+            }
+        }
+    }
+
     if config.history.enabled && !cli.no_history {
         if let Ok(history) = History::open() {
-            let _ = history.add(
-                &raw_prompt,
-                &final_response.refined_prompt,
-                &format!("{}", provider_choice),
-                &model,
-            );
-            // Prune old entries
+            let candidates = results
+                .iter()
+                .map(|r| r.to_candidate(winner.is_some_and(|w| std::ptr::eq(w, r))))
+                .collect::<Vec<_>>();
+            if let Ok(id) = history.add_comparison(raw_prompt, &candidates) {
+                let _ = history.set_system_prompt(id, &system_prompt);
+            }
             let _ = history.prune(config.history.max_entries);
         }
     }
@@ -412,6 +928,119 @@ async fn handle_refine(cli: Cli, config: Config, ui: UI) -> Result<()> {
     Ok(())
 }
 
+/// Score a refinement from history and save the scores with it
+async fn handle_score(id: i64, cli: &Cli, config: &Config, ui: &UI) -> Result<()> {
+    let history = History::open()?;
+    let Some(entry) = history.get(id)? else {
+        ui.error(&format!("No history entry with ID {}", id));
+        std::process::exit(1);
+    };
+    if entry.refined_prompt.is_empty() {
+        anyhow::bail!(
+            "History entry {} has no refined prompt to score (a comparison without a pick?)",
+            id
+        );
+    }
+
+    let judge = resolve_judge(cli, config, &cli_cassette(cli)?)?;
+    if cli.verbose {
+        ui.debug("Judge", &format!("{} ({})", judge.name, judge.model));
+    }
+    let judgement =
+        score_with_progress(&judge, ui, &entry.original_prompt, &entry.refined_prompt).await?;
+
+    match cli.output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&judgement)?),
+        OutputFormat::Markdown => println!("{}", scores_markdown(&judgement)),
+        OutputFormat::Text => println!("{}", judge::render(&judgement, ui.color_enabled())),
+    }
+
+    if config.history.enabled && !cli.no_history {
+        history.add_score(id, &judgement)?;
+    }
+
+    Ok(())
+}
+
+/// The judge from `--judge`, the `[judge]` table or the default provider
+fn resolve_judge(
+    cli: &Cli,
+    config: &Config,
+    cassette: &Option<Arc<Cassette>>,
+) -> Result<ResolvedProvider> {
+    let (name, model) = match cli.judge.as_deref() {
+        Some(spec) => {
+            let (name, model) = compare::parse_target(spec);
+            (Some(name), model)
+        }
+        None => (None, None),
+    };
+    let mut judge = config.resolve_judge(name, model)?;
+    judge.transport.cassette = cassette.clone();
+    Ok(judge)
+}
+
+/// Ask the judge for scores behind a spinner, stopping on Ctrl-C
+async fn score_with_progress(
+    judge: &ResolvedProvider,
+    ui: &UI,
+    original: &str,
+    refined: &str,
+) -> Result<Judgement> {
+    let spinner = ui.spinner("Scoring...");
+    let result = cancellable(ui, judge::score(judge, original, refined)).await;
+    if let Some(pb) = spinner {
+        pb.finish_and_clear();
+    }
+    let judgement = result?;
+
+    if let Some(usage) = judgement.usage {
+        tracing::debug!("Judge used {} tokens", usage.total());
+    }
+    Ok(judgement)
+}
+
+/// The cassette selected by `--record` or `--replay`, if any
+fn cli_cassette(cli: &Cli) -> Result<Option<Arc<Cassette>>> {
+    Ok(match (&cli.record, &cli.replay) {
+        (Some(path), _) => Some(Arc::new(Cassette::record(path))),
+        (None, Some(path)) => Some(Arc::new(Cassette::replay(path)?)),
+        (None, None) => None,
+    })
+}
+
+/// Layer the generation flags and the cassette onto a resolved provider
+fn apply_cli_overrides(
+    resolved: &mut ResolvedProvider,
+    cli: &Cli,
+    cassette: &Option<Arc<Cassette>>,
+) -> Result<()> {
+    let cli_params = GenerationParams {
+        temperature: cli.temperature,
+        seed: cli.seed,
+        ..GenerationParams::default()
+    };
+    resolved.transport.cassette = cassette.clone();
+    resolved.params = cli_params.or(&resolved.params);
+    resolved
+        .params
+        .validate(resolved.kind)
+        .with_context(|| format!("Invalid generation settings for '{}'", resolved.name))
+}
+
+/// Run `future` unless Ctrl-C arrives first. Dropping the future cancels any
+/// request in flight.
+async fn cancellable<T>(ui: &UI, future: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::select! {
+        result = future => result,
+        () = ctrl_c() => {
+            ui.reset_after_interrupt();
+            ui.warning("Interrupted");
+            Err(Interrupted.into())
+        }
+    }
+}
+
 fn get_prompt(cli: &Cli) -> Result<String> {
     if !cli.prompt.is_empty() {
         return Ok(cli.prompt.join(" "));
@@ -427,14 +1056,79 @@ fn get_prompt(cli: &Cli) -> Result<String> {
     Ok(buffer.trim().to_string())
 }
 
-fn output_result(cli: &Cli, response: &RefinerResponse, _ui: &UI) -> Result<()> {
+/// Check the model against the provider's model list, with suggestions for
+/// near misses. If the list can't be fetched the check is skipped, leaving the
+/// refinement request to report the problem.
+async fn validate_model(resolved: &ResolvedProvider, ui: &UI, verbose: bool) -> Result<()> {
+    let mut resolved = resolved.clone();
+    resolved.transport.retry = RetryPolicy::none();
+    let Ok(provider) = build_provider(&resolved) else {
+        return Ok(());
+    };
+
+    match provider.list_models().await {
+        Ok(models) => check_model(&resolved.name, &resolved.model, &models),
+        Err(e) => {
+            if verbose {
+                ui.debug("Models", &format!("Could not list models: {:#}", e));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Comma-separated names of the fallback providers in a chain
+fn fallback_names(chain: &[ResolvedProvider]) -> String {
+    chain[1..]
+        .iter()
+        .map(|r| r.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// JSON output: the response plus how it was produced
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    #[serde(flatten)]
+    response: &'a RefinerResponse,
+    usage: &'a RefineStats,
+    /// Served from the response cache without calling the provider
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<&'a Judgement>,
+}
+
+fn output_result(
+    cli: &Cli,
+    response: &RefinerResponse,
+    stats: &RefineStats,
+    score: Option<&Judgement>,
+    cached: bool,
+    streamed: bool,
+    ui: &UI,
+) -> Result<()> {
     match cli.output {
         OutputFormat::Text => {
-            // Print to stdout for piping
-            println!("{}", response.refined_prompt);
+            // The prompt was already shown on the terminal while streaming;
+            // only repeat it when stdout is redirected
+            if !(streamed && atty::is(atty::Stream::Stdout)) {
+                // Print to stdout for piping
+                println!("{}", response.refined_prompt);
+            }
+            // Scores go to stderr to keep stdout pipeable
+            if let Some(judgement) = score {
+                ui.header("Score");
+                eprintln!("{}", judge::render(judgement, ui.color_enabled()));
+            }
         }
         OutputFormat::Json => {
-            let json = serde_json::to_string_pretty(response)?;
+            let json = serde_json::to_string_pretty(&JsonOutput {
+                response,
+                usage: stats,
+                cached,
+                score,
+            })?;
             println!("{}", json);
         }
         OutputFormat::Markdown => {
@@ -446,7 +1140,74 @@ fn output_result(cli: &Cli, response: &RefinerResponse, _ui: &UI) -> Result<()>
                     println!("{}. {}", i + 1, q);
                 }
             }
+            if let Some(judgement) = score {
+                println!("\n{}", scores_markdown(judgement));
+            }
         }
     }
     Ok(())
 }
+
+/// A judge's scores as a Markdown section
+fn scores_markdown(judgement: &Judgement) -> String {
+    let (original, refined) = (&judgement.original, &judgement.refined);
+    let mut markdown = format!(
+        "### Scores\n\n\
+         | Criterion | Original | Refined |\n\
+         | --- | ---: | ---: |\n\
+         | Clarity | {} | {} |\n\
+         | Specificity | {} | {} |\n\
+         | Output format | {} | {} |\n\
+         | Intent preservation | | {} |\n\
+         | Mean | {:.1} | {:.1} |\n",
+        original.clarity,
+        refined.clarity,
+        original.specificity,
+        refined.specificity,
+        original.output_format,
+        refined.output_format,
+        judgement.intent_preservation,
+        original.mean(),
+        refined.mean(),
+    );
+    if !judgement.rationale.is_empty() {
+        markdown.push_str(&format!("\n{}\n", judgement.rationale));
+    }
+    markdown.push_str(&format!(
+        "\n_Judged by {} ({})_",
+        judgement.judge, judgement.model
+    ));
+    markdown
+}
+
+fn format_tokens(prompt_tokens: Option<u64>, completion_tokens: Option<u64>) -> String {
+    match (prompt_tokens, completion_tokens) {
+        (None, None) => "not reported".to_string(),
+        (prompt, completion) => {
+            let prompt = prompt.unwrap_or(0);
+            let completion = completion.unwrap_or(0);
+            format!(
+                "{} prompt + {} completion = {}",
+                prompt,
+                completion,
+                prompt + completion
+            )
+        }
+    }
+}
+
+fn format_latency(latency_ms: u64) -> String {
+    format!("{:.2}s", latency_ms as f64 / 1000.0)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+fn format_cost(cost: f64) -> String {
+    format!("${:.4}", cost)
+}
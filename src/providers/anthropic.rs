@@ -1,39 +1,275 @@
-use super::{build_user_message, Provider, SYSTEM_PROMPT};
-use crate::refiner::RefinerResponse;
+use super::stream::{for_each_line, read_body, sse_data, RefinedPromptExtractor, TokenSink};
+use super::transport::Transport;
+use super::{
+    after_rejection,
+    message::{split_system, JSON_ONLY_INSTRUCTION},
+    structured_output_rejected, ChatMessage, GenerationParams, ModelInfo, Provider, ProviderError,
+};
+use crate::refiner::{self, Completion, RefinerResponse, Usage};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use reqwest::Client;
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Tool the model is forced to call with the refinement as its input
+const SUBMIT_TOOL: &str = "submit_refinement";
+
+/// `max_tokens` is required by the Messages API
+const DEFAULT_MAX_TOKENS: u32 = 4096;
 
 pub struct AnthropicProvider {
     client: Client,
     endpoint: String,
     model: String,
     api_key: String,
+    transport: Transport,
+    /// Force a `submit_refinement` tool call; cleared if the API rejects it
+    structured: AtomicBool,
+    params: GenerationParams,
 }
 
 impl AnthropicProvider {
-    pub fn new(endpoint: String, model: String, api_key: String) -> Self {
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(300))
-            .build()
-            .expect("Failed to create HTTP client");
-
+    pub fn new(client: Client, endpoint: String, model: String, api_key: String) -> Self {
         Self {
             client,
             endpoint,
             model,
             api_key,
+            transport: Transport::default(),
+            structured: AtomicBool::new(true),
+            params: GenerationParams::default(),
         }
     }
+
+    /// How HTTP requests are sent (retries, record/replay)
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Get the response through a forced `submit_refinement` tool call whose
+    /// input schema is the `RefinerResponse` schema
+    pub fn with_structured_output(self, enabled: bool) -> Self {
+        self.structured.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    /// Sampling and length settings; `seed` has no Anthropic equivalent
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    fn build_request(&self, messages: &[ChatMessage], stream: bool) -> AnthropicRequest {
+        let (system, turns) = split_system(messages);
+
+        let mut request = AnthropicRequest {
+            model: self.model.clone(),
+            max_tokens: self.params.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: self.params.temperature,
+            top_p: self.params.top_p,
+            stop_sequences: self.params.stop.clone(),
+            system,
+            messages: turns
+                .into_iter()
+                .map(|message| AnthropicMessage {
+                    role: message.role.to_string(),
+                    content: message.content.clone(),
+                })
+                .collect(),
+            tools: vec![AnthropicTool {
+                name: SUBMIT_TOOL.to_string(),
+                description: "Submit the refined prompt and any clarifying questions".to_string(),
+                input_schema: refiner::response_schema(),
+            }],
+            tool_choice: Some(ToolChoice {
+                choice_type: "tool".to_string(),
+                name: SUBMIT_TOOL.to_string(),
+            }),
+            stream,
+        };
+        if !self.structured.load(Ordering::Relaxed) {
+            request.drop_tool();
+        }
+        request
+    }
+
+    /// Turn a transport failure into a user-facing error
+    fn request_error(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_connect() => anyhow::Error::new(ProviderError::Connection(
+                format!("Could not connect to Anthropic API at {}", self.endpoint),
+            )),
+            Some(err) if err.is_timeout() => anyhow::anyhow!("Request timed out"),
+            Some(err) => anyhow::anyhow!("HTTP request failed: {}", err),
+            None => e,
+        }
+    }
+
+    /// The error for a non-success response
+    fn status_error(&self, status: reqwest::StatusCode, body: &str) -> anyhow::Error {
+        if status.as_u16() == 401 {
+            ProviderError::Auth(
+                "Authentication failed. Check your ANTHROPIC_API_KEY environment variable."
+                    .to_string(),
+            )
+            .into()
+        } else if status.as_u16() == 429 {
+            ProviderError::RateLimited(
+                "Rate limited by Anthropic. Please wait and try again.".to_string(),
+            )
+            .into()
+        } else {
+            anyhow::anyhow!("Anthropic returned error {}: {}", status, body)
+        }
+    }
+
+    async fn send(&self, request: &mut AnthropicRequest) -> Result<Response> {
+        let mut rejection = None;
+        loop {
+            let payload: &AnthropicRequest = request;
+            let response = self
+                .transport
+                .send(|| {
+                    self.client
+                        .post(format!("{}/messages", self.endpoint))
+                        .header("x-api-key", &self.api_key)
+                        .header("anthropic-version", "2023-06-01")
+                        .header("Content-Type", "application/json")
+                        .json(payload)
+                })
+                .await
+                .map_err(|e| after_rejection(self.request_error(e), rejection.take()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+
+                if structured_output_rejected(status, &body) && request.drop_tool() {
+                    tracing::debug!(
+                        "Anthropic rejected the forced tool call ({}), retrying without it",
+                        status
+                    );
+                    rejection = Some(format!("Anthropic returned error {}: {}", status, body));
+                    continue;
+                }
+
+                return Err(after_rejection(self.status_error(status, &body), rejection));
+            }
+
+            if rejection.is_some() {
+                self.structured.store(false, Ordering::Relaxed);
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Send a non-streaming request and return the model's reply text
+    async fn complete(&self, request: &mut AnthropicRequest) -> Result<Completion> {
+        let response = self.send(request).await?;
+
+        let anthropic_response: AnthropicResponse =
+            serde_json::from_slice(&read_body(response).await?)
+                .context("Failed to parse Anthropic response")?;
+
+        // Prefer the forced tool call's input over any accompanying text
+        let tool_input = anthropic_response
+            .content
+            .iter()
+            .find(|block| block.content_type == "tool_use")
+            .and_then(|block| block.input.as_ref())
+            .map(Value::to_string);
+        let text = || {
+            anthropic_response
+                .content
+                .iter()
+                .find_map(|block| block.text.clone())
+        };
+
+        let content = tool_input
+            .or_else(text)
+            .context("No response from Anthropic")?;
+
+        Ok(Completion {
+            content,
+            usage: anthropic_response.usage.map(Usage::from),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicModelList {
+    data: Vec<AnthropicModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicModel {
+    id: String,
+    display_name: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 struct AnthropicRequest {
     model: String,
     max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
     system: String,
     messages: Vec<AnthropicMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<AnthropicTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicTool {
+    name: String,
+    description: String,
+    input_schema: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct ToolChoice {
+    #[serde(rename = "type")]
+    choice_type: String,
+    name: String,
+}
+
+impl AnthropicRequest {
+    /// Fall back from the forced tool call to asking for raw JSON in the
+    /// system prompt. Returns `false` if no tool was being sent.
+    fn drop_tool(&mut self) -> bool {
+        if self.tools.is_empty() {
+            return false;
+        }
+        self.tools.clear();
+        self.tool_choice = None;
+        // Anthropic requires specific JSON instruction in the prompt
+        self.system = format!("{}\n\n{}", self.system, JSON_ONLY_INSTRUCTION);
+        true
+    }
+
+    /// Append the rejected reply and a corrective instruction as a follow-up turn
+    fn push_repair(&mut self, previous: String, correction: String) {
+        self.stream = false;
+        self.messages.push(AnthropicMessage {
+            role: "assistant".to_string(),
+            content: previous,
+        });
+        self.messages.push(AnthropicMessage {
+            role: "user".to_string(),
+            content: correction,
+        });
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,16 +281,66 @@ struct AnthropicMessage {
 #[derive(Debug, Deserialize)]
 struct AnthropicResponse {
     content: Vec<AnthropicContent>,
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl From<AnthropicUsage> for Usage {
+    fn from(usage: AnthropicUsage) -> Self {
+        Self {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct AnthropicContent {
-    text: String,
+    #[serde(rename = "type")]
+    content_type: String,
+    text: Option<String>,
+    input: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    delta: Option<AnthropicDelta>,
+    error: Option<AnthropicStreamError>,
+    /// Set on `message_start`, carrying the input token count
+    message: Option<AnthropicStreamMessage>,
+    /// Set on `message_delta`, carrying the output token count
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamMessage {
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicDelta {
+    text: Option<String>,
+    /// Tool input arrives as `input_json_delta` fragments
+    partial_json: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamError {
+    message: String,
 }
 
 #[async_trait]
 impl Provider for AnthropicProvider {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "Anthropic"
     }
 
@@ -62,94 +348,124 @@ impl Provider for AnthropicProvider {
         &self.model
     }
 
-    async fn refine(
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages, false);
+        let completion = self.complete(&mut request).await?;
+
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion> {
+        let mut request = self.build_request(messages, false);
+        request.drop_tool();
+        self.complete(&mut request).await
+    }
+
+    async fn refine_stream(
         &self,
-        prompt: &str,
-        context: Option<&str>,
-        clarification: Option<&str>,
+        messages: &[ChatMessage],
+        on_token: TokenSink<'_>,
     ) -> Result<RefinerResponse> {
-        let user_message = build_user_message(prompt, context, clarification);
+        let mut request = self.build_request(messages, true);
+        let response = self.send(&mut request).await?;
 
-        // Anthropic requires specific JSON instruction in the prompt
-        let json_system = format!(
-            "{}\n\nIMPORTANT: Respond with ONLY a valid JSON object. No markdown code blocks, no explanation, just the raw JSON.",
-            SYSTEM_PROMPT
-        );
+        let mut extractor = RefinedPromptExtractor::new();
+        let mut input_tokens = None;
+        let mut output_tokens = None;
+        for_each_line(response, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(true);
+            };
+            let event: AnthropicStreamEvent = serde_json::from_str(data)
+                .with_context(|| format!("Failed to parse Anthropic stream event: {}", data))?;
+            match event.event_type.as_str() {
+                "content_block_delta" => {
+                    if let Some(delta_text) = event.delta.and_then(|d| d.text.or(d.partial_json)) {
+                        let text = extractor.push(&delta_text);
+                        if !text.is_empty() {
+                            on_token(&text);
+                        }
+                    }
+                    Ok(true)
+                }
+                "error" => {
+                    let message = event
+                        .error
+                        .map(|e| e.message)
+                        .unwrap_or_else(|| "unknown error".to_string());
+                    anyhow::bail!("Anthropic returned error: {}", message)
+                }
+                "message_start" => {
+                    input_tokens = event.message.and_then(|m| m.usage).map(|u| u.input_tokens);
+                    Ok(true)
+                }
+                "message_delta" => {
+                    output_tokens = event.usage.map(|u| u.output_tokens);
+                    Ok(true)
+                }
+                "message_stop" => Ok(false),
+                _ => Ok(true),
+            }
+        })
+        .await?;
 
-        let request = AnthropicRequest {
-            model: self.model.clone(),
-            max_tokens: 4096,
-            system: json_system,
-            messages: vec![AnthropicMessage {
-                role: "user".to_string(),
-                content: user_message,
-            }],
+        if extractor.raw().is_empty() {
+            anyhow::bail!("No response from Anthropic");
+        }
+
+        let completion = Completion {
+            content: extractor.raw().to_string(),
+            usage: Usage::from_counts(input_tokens, output_tokens),
         };
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
 
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
         let response = self
-            .client
-            .post(format!("{}/messages", self.endpoint))
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
+            .transport
+            .send(|| {
+                self.client
+                    .get(format!("{}/models", self.endpoint))
+                    .query(&[("limit", "1000")])
+                    .header("x-api-key", &self.api_key)
+                    .header("anthropic-version", "2023-06-01")
+            })
             .await
-            .map_err(|e| {
-                if e.is_connect() {
-                    anyhow::anyhow!("Could not connect to Anthropic API at {}", self.endpoint)
-                } else if e.is_timeout() {
-                    anyhow::anyhow!("Request timed out")
-                } else {
-                    anyhow::anyhow!("HTTP request failed: {}", e)
-                }
-            })?;
+            .map_err(|e| self.request_error(e))?;
 
         if !response.status().is_success() {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
 
             if status.as_u16() == 401 {
-                anyhow::bail!(
+                return Err(ProviderError::Auth(
                     "Authentication failed. Check your ANTHROPIC_API_KEY environment variable."
-                );
-            } else if status.as_u16() == 429 {
-                anyhow::bail!("Rate limited by Anthropic. Please wait and try again.");
+                        .to_string(),
+                )
+                .into());
             }
 
             anyhow::bail!("Anthropic returned error {}: {}", status, body);
         }
 
-        let anthropic_response: AnthropicResponse = response
-            .json()
-            .await
-            .context("Failed to parse Anthropic response")?;
-
-        let content = anthropic_response
-            .content
-            .first()
-            .context("No response from Anthropic")?;
-
-        // Clean up potential markdown code blocks
-        let json_text = content.text.trim();
-        let json_text = json_text
-            .strip_prefix("```json")
-            .or_else(|| json_text.strip_prefix("```"))
-            .unwrap_or(json_text);
-        let json_text = json_text.strip_suffix("```").unwrap_or(json_text).trim();
-
-        let refiner_response: RefinerResponse =
-            serde_json::from_str(json_text).with_context(|| {
-                format!(
-                    "Failed to parse refiner response as JSON. Raw content:\n{}",
-                    content.text
-                )
-            })?;
-
-        if refiner_response.refined_prompt.is_empty() {
-            anyhow::bail!("Refiner returned an empty refined_prompt");
-        }
+        let list: AnthropicModelList = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse Anthropic model list")?;
 
-        Ok(refiner_response)
+        Ok(list
+            .data
+            .into_iter()
+            .map(|model| ModelInfo {
+                display_name: model.display_name,
+                ..ModelInfo::new(model.id)
+            })
+            .collect())
     }
 }
@@ -0,0 +1,331 @@
+//! AWS credentials and Signature Version 4 request signing
+
+use super::ProviderError;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use directories::BaseDirs;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Method, Url};
+use ring::{digest, hmac};
+use std::path::PathBuf;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Static AWS credentials
+#[derive(Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    /// Where the credentials were found, for diagnostics
+    pub source: String,
+}
+
+impl std::fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AwsCredentials {
+    /// Load credentials the way the AWS CLI does: `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`, then the shared
+    /// credentials file (`AWS_SHARED_CREDENTIALS_FILE` or
+    /// `~/.aws/credentials`).
+    ///
+    /// A configured `profile` skips the environment variables; otherwise the
+    /// file profile is `AWS_PROFILE` or `default`.
+    pub fn load(profile: Option<&str>) -> Result<Self> {
+        if profile.is_none() {
+            if let Some(credentials) = Self::from_env() {
+                return Ok(credentials);
+            }
+        }
+
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| non_empty_env("AWS_PROFILE"))
+            .unwrap_or_else(|| "default".to_string());
+        let path = credentials_file()?;
+
+        if !path.exists() {
+            return Err(ProviderError::Auth(format!(
+                "No AWS credentials found. Set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, \
+                 or add a [{}] profile to {}",
+                profile,
+                path.display()
+            ))
+            .into());
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let source = format!("profile {} in {}", profile, path.display());
+        Self::from_profile(&contents, &profile, source).ok_or_else(|| {
+            ProviderError::Auth(format!(
+                "Profile [{}] in {} has no aws_access_key_id and aws_secret_access_key",
+                profile,
+                path.display()
+            ))
+            .into()
+        })
+    }
+
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: non_empty_env("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: non_empty_env("AWS_SECRET_ACCESS_KEY")?,
+            session_token: non_empty_env("AWS_SESSION_TOKEN"),
+            source: "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY".to_string(),
+        })
+    }
+
+    /// Read a profile from an INI-style shared credentials file
+    fn from_profile(contents: &str, profile: &str, source: String) -> Option<Self> {
+        let mut section = None;
+        let mut access_key_id = None;
+        let mut secret_access_key = None;
+        let mut session_token = None;
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(name.trim().to_string());
+                continue;
+            }
+            if section.as_deref() != Some(profile) {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                "aws_access_key_id" => access_key_id = value,
+                "aws_secret_access_key" => secret_access_key = value,
+                "aws_session_token" => session_token = value,
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            access_key_id: access_key_id?,
+            secret_access_key: secret_access_key?,
+            session_token,
+            source,
+        })
+    }
+}
+
+fn non_empty_env(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|v| !v.is_empty())
+}
+
+fn credentials_file() -> Result<PathBuf> {
+    if let Some(path) = non_empty_env("AWS_SHARED_CREDENTIALS_FILE") {
+        return Ok(PathBuf::from(path));
+    }
+    let dirs = BaseDirs::new().context("Could not determine home directory")?;
+    Ok(dirs.home_dir().join(".aws").join("credentials"))
+}
+
+/// Signs requests for one service in one region
+pub struct Signer<'a> {
+    pub credentials: &'a AwsCredentials,
+    pub region: &'a str,
+    /// Signing name, e.g. `bedrock`
+    pub service: &'a str,
+}
+
+impl Signer<'_> {
+    /// Headers that authenticate a request: `x-amz-date`, the session token
+    /// if any, `content-type` if given, and `authorization`. Signing must
+    /// happen per attempt since the signature embeds the time.
+    pub fn headers(
+        &self,
+        method: &Method,
+        url: &Url,
+        content_type: Option<&str>,
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> HeaderMap {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let mut signed: Vec<(&str, String)> =
+            vec![("host", host), ("x-amz-date", amz_date.clone())];
+        if let Some(content_type) = content_type {
+            signed.push(("content-type", content_type.to_string()));
+        }
+        if let Some(token) = &self.credentials.session_token {
+            signed.push(("x-amz-security-token", token.clone()));
+        }
+        signed.sort_by(|a, b| a.0.cmp(b.0));
+
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            uri_encode(url.path(), false),
+            canonical_query(url),
+            canonical_headers,
+            signed_headers,
+            hex_sha256(body)
+        );
+
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            amz_date,
+            scope,
+            hex_sha256(canonical_request.as_bytes())
+        );
+
+        let key = [date, self.region, self.service, "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.credentials.secret_access_key).into_bytes(),
+                |key, part| hmac_sha256(&key, part.as_bytes()),
+            );
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.credentials.access_key_id, scope, signed_headers, signature
+        );
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in signed {
+            if name == "host" {
+                // reqwest derives Host from the URL
+                continue;
+            }
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+        if let Ok(mut value) = HeaderValue::from_str(&authorization) {
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        if let Some(value) = headers.get_mut("x-amz-security-token") {
+            value.set_sensitive(true);
+        }
+        headers
+    }
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters, keeping
+/// `/` when `encode_slash` is false
+pub(super) fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn canonical_query(url: &Url) -> String {
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(&k, true), uri_encode(&v, true)))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Credentials, time and scope shared by the AWS SigV4 test suite
+    fn suite_authorization(url: &str) -> String {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+            source: "test".to_string(),
+        };
+        let signer = Signer {
+            credentials: &credentials,
+            region: "us-east-1",
+            service: "service",
+        };
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+        let headers = signer.headers(&Method::GET, &Url::parse(url).unwrap(), None, b"", now);
+        assert_eq!(headers["x-amz-date"], "20150830T123600Z");
+        headers[AUTHORIZATION].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn signs_get_vanilla() {
+        assert_eq!(
+            suite_authorization("https://example.amazonaws.com/"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn signs_get_vanilla_query_order_key() {
+        assert_eq!(
+            suite_authorization("https://example.amazonaws.com/?Param2=value2&Param1=value1"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn uri_encodes_reserved_characters() {
+        assert_eq!(uri_encode("a b/c~d=e", true), "a%20b%2Fc~d%3De");
+        assert_eq!(
+            uri_encode("/model/anthropic.claude:0", false),
+            "/model/anthropic.claude%3A0"
+        );
+    }
+}
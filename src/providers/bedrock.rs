@@ -0,0 +1,549 @@
+use super::aws::{uri_encode, AwsCredentials, Signer};
+use super::stream::read_body;
+use super::transport::Transport;
+use super::{
+    after_rejection,
+    message::{split_system, JSON_ONLY_INSTRUCTION},
+    structured_output_rejected, ChatMessage, GenerationParams, ModelInfo, Provider, ProviderError,
+};
+use crate::refiner::{self, Completion, RefinerResponse, Usage};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::{Client, Method, Response, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Tool the model is forced to call with the refinement as its input
+const SUBMIT_TOOL: &str = "submit_refinement";
+
+/// SigV4 signing name shared by the runtime and control plane endpoints
+const SIGNING_SERVICE: &str = "bedrock";
+
+pub struct BedrockProvider {
+    client: Client,
+    endpoint: String,
+    model: String,
+    region: String,
+    credentials: AwsCredentials,
+    transport: Transport,
+    /// Force a `submit_refinement` tool call; cleared if the model rejects it
+    structured: AtomicBool,
+    params: GenerationParams,
+}
+
+impl BedrockProvider {
+    pub fn new(
+        client: Client,
+        endpoint: String,
+        model: String,
+        region: String,
+        credentials: AwsCredentials,
+    ) -> Self {
+        Self {
+            client,
+            endpoint,
+            model,
+            region,
+            credentials,
+            transport: Transport::default(),
+            structured: AtomicBool::new(true),
+            params: GenerationParams::default(),
+        }
+    }
+
+    /// How HTTP requests are sent (retries, record/replay)
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Get the response through a forced `submit_refinement` tool call. Only
+    /// some Bedrock models support forcing a tool; the others fall back to
+    /// JSON instructions in the system prompt.
+    pub fn with_structured_output(self, enabled: bool) -> Self {
+        self.structured.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    /// Sampling and length settings; `seed` has no Converse equivalent
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    fn build_request(&self, messages: &[ChatMessage]) -> ConverseRequest {
+        let (system, turns) = split_system(messages);
+
+        let mut request = ConverseRequest {
+            system: vec![ContentBlock::text(system)],
+            messages: turns
+                .into_iter()
+                .map(|message| Message {
+                    role: message.role.to_string(),
+                    content: vec![ContentBlock::text(message.content.clone())],
+                })
+                .collect(),
+            inference_config: InferenceConfig {
+                max_tokens: self.params.max_tokens,
+                temperature: self.params.temperature,
+                top_p: self.params.top_p,
+                stop_sequences: self.params.stop.clone(),
+            },
+            tool_config: Some(ToolConfig {
+                tools: vec![Tool {
+                    tool_spec: ToolSpec {
+                        name: SUBMIT_TOOL.to_string(),
+                        description: "Submit the refined prompt and any clarifying questions"
+                            .to_string(),
+                        input_schema: InputSchema {
+                            json: refiner::response_schema(),
+                        },
+                    },
+                }],
+                tool_choice: ToolChoice {
+                    tool: ToolName {
+                        name: SUBMIT_TOOL.to_string(),
+                    },
+                },
+            }),
+        };
+        if !self.structured.load(Ordering::Relaxed) {
+            request.drop_tool();
+        }
+        request
+    }
+
+    /// Turn a transport failure into a user-facing error
+    fn request_error(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_connect() => anyhow::Error::new(ProviderError::Connection(
+                format!("Could not connect to Bedrock at {}", self.endpoint),
+            )),
+            Some(err) if err.is_timeout() => anyhow::anyhow!("Request timed out"),
+            Some(err) => anyhow::anyhow!("HTTP request failed: {}", err),
+            None => e,
+        }
+    }
+
+    /// Send a request signed afresh for every attempt
+    async fn send_signed(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response> {
+        let url = Url::parse(url).with_context(|| format!("Invalid Bedrock URL: {}", url))?;
+        let signer = Signer {
+            credentials: &self.credentials,
+            region: &self.region,
+            service: SIGNING_SERVICE,
+        };
+        let content_type = body.as_ref().map(|_| "application/json");
+        let payload = body.unwrap_or_default();
+
+        self.transport
+            .send(|| {
+                let headers = signer.headers(&method, &url, content_type, &payload, Utc::now());
+                let builder = self
+                    .client
+                    .request(method.clone(), url.clone())
+                    .headers(headers);
+                if content_type.is_some() {
+                    builder.body(payload.clone())
+                } else {
+                    builder
+                }
+            })
+            .await
+            .map_err(|e| self.request_error(e))
+    }
+
+    async fn send(&self, request: &mut ConverseRequest) -> Result<Response> {
+        let url = format!(
+            "{}/model/{}/converse",
+            self.endpoint,
+            uri_encode(&self.model, true)
+        );
+
+        let mut rejection = None;
+        loop {
+            let body = serde_json::to_vec(request).context("Failed to encode Bedrock request")?;
+            let response = self
+                .send_signed(Method::POST, &url, Some(body))
+                .await
+                .map_err(|e| after_rejection(e, rejection.take()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+
+                if let Some(error) = self.status_error(status, &body) {
+                    return Err(after_rejection(error, rejection));
+                }
+
+                if structured_output_rejected(status, &body) && request.drop_tool() {
+                    tracing::debug!(
+                        "Bedrock rejected the forced tool call ({}), retrying without it",
+                        status
+                    );
+                    rejection = Some(format!(
+                        "Bedrock returned error {}: {}",
+                        status,
+                        error_message(&body)
+                    ));
+                    continue;
+                }
+
+                return Err(after_rejection(
+                    anyhow::anyhow!(
+                        "Bedrock returned error {}: {}",
+                        status,
+                        error_message(&body)
+                    ),
+                    rejection,
+                ));
+            }
+
+            if rejection.is_some() {
+                self.structured.store(false, Ordering::Relaxed);
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Typed errors for authentication and throttling responses
+    fn status_error(&self, status: reqwest::StatusCode, body: &str) -> Option<anyhow::Error> {
+        match status.as_u16() {
+            401 | 403 => Some(
+                ProviderError::Auth(format!(
+                    "AWS rejected the request: {}. Check your AWS credentials ({}) and that \
+                     they may invoke {} in {}.",
+                    error_message(body).trim_end_matches('.'),
+                    self.credentials.source,
+                    self.model,
+                    self.region
+                ))
+                .into(),
+            ),
+            429 => Some(
+                ProviderError::RateLimited(
+                    "Rate limited by Bedrock. Please wait and try again.".to_string(),
+                )
+                .into(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Send a request and return the model's reply text
+    async fn complete(&self, request: &mut ConverseRequest) -> Result<Completion> {
+        let response = self.send(request).await?;
+
+        let converse: ConverseResponse = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse Bedrock response")?;
+
+        // Prefer the forced tool call's input over any accompanying text
+        let content = &converse.output.message.content;
+        let tool_input = content
+            .iter()
+            .find_map(|block| block.tool_use.as_ref())
+            .map(|tool_use| tool_use.input.to_string());
+        let text = || content.iter().find_map(|block| block.text.clone());
+
+        let content = tool_input
+            .or_else(text)
+            .context("No response from Bedrock")?;
+
+        Ok(Completion {
+            content,
+            usage: converse.usage.map(Usage::from),
+        })
+    }
+
+    /// Control plane endpoint for model listing, e.g.
+    /// `https://bedrock.us-east-1.amazonaws.com` for the runtime endpoint
+    /// `https://bedrock-runtime.us-east-1.amazonaws.com`
+    fn control_endpoint(&self) -> String {
+        self.endpoint.replacen("bedrock-runtime.", "bedrock.", 1)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}{}", self.control_endpoint(), path);
+        let response = self.send_signed(Method::GET, &url, None).await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+
+            if let Some(error) = self.status_error(status, &body) {
+                return Err(error);
+            }
+
+            anyhow::bail!(
+                "Bedrock returned error {}: {}",
+                status,
+                error_message(&body)
+            );
+        }
+
+        serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse Bedrock model list")
+    }
+}
+
+/// The `message` field of an AWS JSON error body, or the body itself
+fn error_message(body: &str) -> String {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|value| {
+            value
+                .get("message")
+                .or_else(|| value.get("Message"))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| body.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FoundationModelList {
+    #[serde(default)]
+    model_summaries: Vec<FoundationModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FoundationModel {
+    model_id: String,
+    model_name: Option<String>,
+    provider_name: Option<String>,
+    #[serde(default)]
+    output_modalities: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InferenceProfileList {
+    #[serde(default)]
+    inference_profile_summaries: Vec<InferenceProfile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InferenceProfile {
+    inference_profile_id: String,
+    inference_profile_name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseRequest {
+    system: Vec<ContentBlock>,
+    messages: Vec<Message>,
+    inference_config: InferenceConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_config: Option<ToolConfig>,
+}
+
+impl ConverseRequest {
+    /// Fall back from the forced tool call to asking for raw JSON in the
+    /// system prompt. Returns `false` if no tool was being sent.
+    fn drop_tool(&mut self) -> bool {
+        if self.tool_config.take().is_none() {
+            return false;
+        }
+        self.system
+            .push(ContentBlock::text(JSON_ONLY_INSTRUCTION.to_string()));
+        true
+    }
+
+    /// Append the rejected reply and a corrective instruction as a follow-up turn
+    fn push_repair(&mut self, previous: String, correction: String) {
+        self.messages.push(Message {
+            role: "assistant".to_string(),
+            content: vec![ContentBlock::text(previous)],
+        });
+        self.messages.push(Message {
+            role: "user".to_string(),
+            content: vec![ContentBlock::text(correction)],
+        });
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InferenceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolConfig {
+    tools: Vec<Tool>,
+    tool_choice: ToolChoice,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Tool {
+    tool_spec: ToolSpec,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolSpec {
+    name: String,
+    description: String,
+    input_schema: InputSchema,
+}
+
+#[derive(Debug, Serialize)]
+struct InputSchema {
+    json: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct ToolChoice {
+    tool: ToolName,
+}
+
+#[derive(Debug, Serialize)]
+struct ToolName {
+    name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    role: String,
+    content: Vec<ContentBlock>,
+}
+
+/// A text or tool use block; other block types are ignored
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContentBlock {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing)]
+    tool_use: Option<ToolUse>,
+}
+
+impl ContentBlock {
+    fn text(text: String) -> Self {
+        Self {
+            text: Some(text),
+            tool_use: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolUse {
+    input: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConverseResponse {
+    output: ConverseOutput,
+    usage: Option<ConverseUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConverseOutput {
+    message: Message,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl From<ConverseUsage> for Usage {
+    fn from(usage: ConverseUsage) -> Self {
+        Self {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for BedrockProvider {
+    fn name(&self) -> &str {
+        "Bedrock"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages);
+        let completion = self.complete(&mut request).await?;
+
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion> {
+        let mut request = self.build_request(messages);
+        request.drop_tool();
+        self.complete(&mut request).await
+    }
+
+    /// Foundation models with text output, plus cross-region inference
+    /// profiles when the credentials may list them
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        let list: FoundationModelList = self
+            .get_json("/foundation-models?byOutputModality=TEXT")
+            .await?;
+
+        let mut models: Vec<ModelInfo> = list
+            .model_summaries
+            .into_iter()
+            .filter(|model| {
+                model.output_modalities.is_empty()
+                    || model.output_modalities.iter().any(|m| m == "TEXT")
+            })
+            .map(|model| ModelInfo {
+                display_name: match (model.provider_name, model.model_name) {
+                    (Some(provider), Some(name)) => Some(format!("{} {}", provider, name)),
+                    (_, name) => name,
+                },
+                ..ModelInfo::new(model.model_id)
+            })
+            .collect();
+
+        match self
+            .get_json::<InferenceProfileList>("/inference-profiles")
+            .await
+        {
+            Ok(profiles) => models.extend(profiles.inference_profile_summaries.into_iter().map(
+                |profile| ModelInfo {
+                    display_name: profile.inference_profile_name,
+                    ..ModelInfo::new(profile.inference_profile_id)
+                },
+            )),
+            Err(e) => tracing::debug!("Could not list Bedrock inference profiles: {:#}", e),
+        }
+
+        Ok(models)
+    }
+}
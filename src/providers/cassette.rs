@@ -0,0 +1,198 @@
+//! Record and replay provider HTTP exchanges for offline testing
+
+use anyhow::{Context, Result};
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Response headers that no longer apply once the body has been buffered
+const SKIPPED_HEADERS: &[&str] = &["content-length", "content-encoding", "transfer-encoding"];
+
+/// Whether a cassette captures real traffic or serves it back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    Record,
+    Replay,
+}
+
+/// A single recorded request/response pair.
+///
+/// Request headers are never recorded so API keys don't end up on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+/// A file of recorded HTTP exchanges
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Mutex<Vec<Interaction>>,
+    /// Which recorded interactions have already been replayed
+    used: Mutex<Vec<bool>>,
+}
+
+impl Cassette {
+    /// Start recording to `path`, overwriting any existing cassette
+    pub fn record(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: CassetteMode::Record,
+            interactions: Mutex::new(Vec::new()),
+            used: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Load a cassette from `path` for replay
+    pub fn replay(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read cassette: {}", path.display()))?;
+        let file: CassetteFile = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse cassette: {}", path.display()))?;
+        let used = vec![false; file.interactions.len()];
+
+        Ok(Self {
+            path,
+            mode: CassetteMode::Replay,
+            interactions: Mutex::new(file.interactions),
+            used: Mutex::new(used),
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Send (or replay) a single request
+    pub(super) async fn send(&self, builder: RequestBuilder) -> Result<Response> {
+        let (client, request) = builder.build_split();
+        let request = request?;
+        let recorded_request = RecordedRequest {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            body: request
+                .body()
+                .and_then(|b| b.as_bytes())
+                .and_then(|bytes| serde_json::from_slice(bytes).ok()),
+        };
+
+        match self.mode {
+            CassetteMode::Replay => {
+                let recorded = self.find(&recorded_request)?;
+                to_response(&recorded)
+            }
+            CassetteMode::Record => {
+                let response = client.execute(request).await?;
+                let status = response.status().as_u16();
+                let headers = response
+                    .headers()
+                    .iter()
+                    .filter(|(k, _)| !SKIPPED_HEADERS.contains(&k.as_str()))
+                    .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                    .collect();
+                // The whole body is buffered, so streamed responses arrive at once
+                let body = response.text().await?;
+
+                let recorded = RecordedResponse {
+                    status,
+                    headers,
+                    body,
+                };
+                self.save(Interaction {
+                    request: recorded_request,
+                    response: recorded.clone(),
+                })?;
+                to_response(&recorded)
+            }
+        }
+    }
+
+    /// Find the first unused interaction matching the request, preferring an
+    /// exact body match over a method and URL match
+    fn find(&self, request: &RecordedRequest) -> Result<RecordedResponse> {
+        let interactions = self.interactions.lock().expect("cassette lock poisoned");
+        let mut used = self.used.lock().expect("cassette lock poisoned");
+
+        let unused = || interactions.iter().enumerate().filter(|(i, _)| !used[*i]);
+        let index = unused()
+            .find(|(_, interaction)| interaction.request == *request)
+            .or_else(|| {
+                unused().find(|(_, interaction)| {
+                    interaction.request.method == request.method
+                        && interaction.request.url == request.url
+                })
+            })
+            .map(|(i, _)| i)
+            .with_context(|| {
+                format!(
+                    "No recorded response for {} {} in cassette {}",
+                    request.method,
+                    request.url,
+                    self.path.display()
+                )
+            })?;
+
+        used[index] = true;
+        Ok(interactions[index].response.clone())
+    }
+
+    fn save(&self, interaction: Interaction) -> Result<()> {
+        let mut interactions = self.interactions.lock().expect("cassette lock poisoned");
+        interactions.push(interaction);
+
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create cassette directory: {}", parent.display())
+            })?;
+        }
+
+        let file = CassetteFile {
+            interactions: interactions.clone(),
+        };
+        let contents = serde_json::to_string_pretty(&file)?;
+        fs::write(&self.path, contents)
+            .with_context(|| format!("Failed to write cassette: {}", self.path.display()))
+    }
+}
+
+fn to_response(recorded: &RecordedResponse) -> Result<Response> {
+    let mut builder = http::Response::builder().status(recorded.status);
+    for (name, value) in &recorded.headers {
+        builder = builder.header(name, value);
+    }
+    let response = builder
+        .body(recorded.body.clone())
+        .context("Invalid recorded response")?;
+    Ok(Response::from(response))
+}
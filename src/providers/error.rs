@@ -0,0 +1,25 @@
+//! Typed provider failures
+
+use thiserror::Error;
+
+/// Provider failures that callers may react to, e.g. by falling back to
+/// another provider. Other failures are reported as plain `anyhow` errors.
+#[derive(Debug, Error)]
+pub enum ProviderError {
+    /// The endpoint could not be reached
+    #[error("{0}")]
+    Connection(String),
+    /// Missing or rejected credentials
+    #[error("{0}")]
+    Auth(String),
+    /// The provider kept rate limiting us after retries
+    #[error("{0}")]
+    RateLimited(String),
+}
+
+impl ProviderError {
+    /// Whether another provider could succeed where this error occurred
+    pub fn is_fallback_eligible(err: &anyhow::Error) -> bool {
+        err.chain().any(|cause| cause.is::<ProviderError>())
+    }
+}
@@ -0,0 +1,527 @@
+use super::stream::{for_each_line, read_body, sse_data, RefinedPromptExtractor, TokenSink};
+use super::transport::Transport;
+use super::{
+    after_rejection, message::split_system, structured_output_rejected, ChatMessage,
+    GenerationParams, ModelInfo, Provider, ProviderError, Role,
+};
+use crate::refiner::{self, Completion, RefinerResponse, Usage};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub struct GeminiProvider {
+    client: Client,
+    endpoint: String,
+    model: String,
+    api_key: String,
+    transport: Transport,
+    /// Send `responseSchema`; cleared if the API rejects it
+    structured: AtomicBool,
+    params: GenerationParams,
+}
+
+impl GeminiProvider {
+    pub fn new(client: Client, endpoint: String, model: String, api_key: String) -> Self {
+        Self {
+            client,
+            endpoint,
+            model,
+            api_key,
+            transport: Transport::default(),
+            structured: AtomicBool::new(true),
+            params: GenerationParams::default(),
+        }
+    }
+
+    /// How HTTP requests are sent (retries, record/replay)
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Send the `RefinerResponse` schema as `responseSchema` alongside the
+    /// JSON response MIME type
+    pub fn with_structured_output(self, enabled: bool) -> Self {
+        self.structured.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    /// Sampling and length settings
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    fn build_request(&self, messages: &[ChatMessage]) -> GeminiRequest {
+        let (system, turns) = split_system(messages);
+
+        GeminiRequest {
+            system_instruction: GeminiContent::text(None, system),
+            contents: turns
+                .into_iter()
+                .map(|message| GeminiContent::text(Some(message.role), message.content.clone()))
+                .collect(),
+            generation_config: GenerationConfig {
+                temperature: self.params.temperature,
+                top_p: self.params.top_p,
+                max_output_tokens: self.params.max_tokens,
+                seed: self.params.seed,
+                stop_sequences: self.params.stop.clone(),
+                response_mime_type: "application/json".to_string(),
+                response_schema: self
+                    .structured
+                    .load(Ordering::Relaxed)
+                    .then(|| gemini_schema(refiner::response_schema())),
+            },
+        }
+    }
+
+    /// URL of a model method such as `generateContent`
+    fn method_url(&self, method: &str) -> String {
+        format!("{}/models/{}:{}", self.endpoint, self.model, method)
+    }
+
+    /// Turn a transport failure into a user-facing error
+    fn request_error(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_connect() => anyhow::Error::new(ProviderError::Connection(
+                format!("Could not connect to Gemini API at {}", self.endpoint),
+            )),
+            Some(err) if err.is_timeout() => anyhow::anyhow!("Request timed out"),
+            Some(err) => anyhow::anyhow!("HTTP request failed: {}", err),
+            None => e,
+        }
+    }
+
+    async fn send(&self, request: &mut GeminiRequest, stream: bool) -> Result<Response> {
+        let (url, query): (String, &[(&str, &str)]) = if stream {
+            (self.method_url("streamGenerateContent"), &[("alt", "sse")])
+        } else {
+            (self.method_url("generateContent"), &[])
+        };
+
+        let mut rejection = None;
+        loop {
+            let payload: &GeminiRequest = request;
+            let response = self
+                .transport
+                .send(|| {
+                    self.client
+                        .post(&url)
+                        .query(query)
+                        .header("x-goog-api-key", &self.api_key)
+                        .header("Content-Type", "application/json")
+                        .json(payload)
+                })
+                .await
+                .map_err(|e| after_rejection(self.request_error(e), rejection.take()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+
+                // An invalid key is reported as a 400, so rule it out before
+                // blaming the schema
+                if auth_failed(status, &body) {
+                    return Err(after_rejection(auth_error(), rejection));
+                }
+
+                if structured_output_rejected(status, &body) && request.drop_schema() {
+                    tracing::debug!(
+                        "Gemini rejected the response schema ({}), retrying without it",
+                        status
+                    );
+                    rejection = Some(format!("Gemini returned error {}: {}", status, body));
+                    continue;
+                }
+
+                let error = if status.as_u16() == 429 {
+                    ProviderError::RateLimited(
+                        "Rate limited by Gemini. Please wait and try again.".to_string(),
+                    )
+                    .into()
+                } else {
+                    anyhow::anyhow!("Gemini returned error {}: {}", status, body)
+                };
+                return Err(after_rejection(error, rejection));
+            }
+
+            if rejection.is_some() {
+                self.structured.store(false, Ordering::Relaxed);
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Send a non-streaming request and return the model's reply text
+    async fn complete(&self, request: &mut GeminiRequest) -> Result<Completion> {
+        let response = self.send(request, false).await?;
+
+        let gemini_response: GeminiResponse = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse Gemini response")?;
+
+        let usage = gemini_response
+            .usage_metadata
+            .as_ref()
+            .and_then(UsageMetadata::usage);
+        let content = gemini_response.text()?;
+
+        Ok(Completion { content, usage })
+    }
+}
+
+/// Convert a JSON Schema into the OpenAPI subset `responseSchema` accepts:
+/// upper-case type names, no `additionalProperties`, and a
+/// `propertyOrdering` so `refined_prompt` is generated first and can stream
+fn gemini_schema(schema: Value) -> Value {
+    let Value::Object(object) = schema else {
+        return schema;
+    };
+
+    let mut converted = Map::new();
+    // `required` lists fields in declaration order; a plain `Map` is sorted
+    if let Some(required) = object.get("required") {
+        converted.insert("propertyOrdering".to_string(), required.clone());
+    }
+    for (key, value) in object {
+        match key.as_str() {
+            "additionalProperties" => {}
+            "type" => {
+                let name = value.as_str().unwrap_or_default().to_uppercase();
+                converted.insert(key, Value::from(name));
+            }
+            "properties" => {
+                let Value::Object(properties) = value else {
+                    continue;
+                };
+                let properties = properties
+                    .into_iter()
+                    .map(|(name, property)| (name, gemini_schema(property)))
+                    .collect();
+                converted.insert(key, Value::Object(properties));
+            }
+            "items" => {
+                converted.insert(key, gemini_schema(value));
+            }
+            _ => {
+                converted.insert(key, value);
+            }
+        }
+    }
+    Value::Object(converted)
+}
+
+/// Whether an error response means the API key was missing or rejected
+fn auth_failed(status: reqwest::StatusCode, body: &str) -> bool {
+    matches!(status.as_u16(), 401 | 403) || body.contains("API_KEY_INVALID")
+}
+
+fn auth_error() -> anyhow::Error {
+    ProviderError::Auth(
+        "Authentication failed. Check your GEMINI_API_KEY environment variable.".to_string(),
+    )
+    .into()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiModelList {
+    #[serde(default)]
+    models: Vec<GeminiModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiModel {
+    /// Resource name, e.g. `models/gemini-2.5-flash`
+    name: String,
+    display_name: Option<String>,
+    input_token_limit: Option<u64>,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+    system_instruction: GeminiContent,
+    contents: Vec<GeminiContent>,
+    generation_config: GenerationConfig,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    response_mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<Value>,
+}
+
+impl GeminiRequest {
+    /// Fall back from a response schema to plain JSON output. Returns `false`
+    /// if no schema was being sent.
+    fn drop_schema(&mut self) -> bool {
+        self.generation_config.response_schema.take().is_some()
+    }
+
+    /// Append the rejected reply and a corrective instruction as a follow-up turn
+    fn push_repair(&mut self, previous: String, correction: String) {
+        self.contents
+            .push(GeminiContent::text(Some(Role::Assistant), previous));
+        self.contents
+            .push(GeminiContent::text(Some(Role::User), correction));
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
+}
+
+impl GeminiContent {
+    /// Gemini calls the assistant role `model`
+    fn text(role: Option<Role>, text: String) -> Self {
+        Self {
+            role: role.map(|role| match role {
+                Role::Assistant => "model".to_string(),
+                role => role.to_string(),
+            }),
+            parts: vec![GeminiPart { text: Some(text) }],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+/// A `generateContent` reply, or one chunk of a streamed reply
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<GeminiCandidate>,
+    prompt_feedback: Option<PromptFeedback>,
+    usage_metadata: Option<UsageMetadata>,
+    /// Sent in place of a chunk when a stream fails
+    error: Option<GeminiError>,
+}
+
+impl GeminiResponse {
+    /// Concatenated text of the first candidate, or why there is none
+    fn text(&self) -> Result<String> {
+        if let Some(reason) = self
+            .prompt_feedback
+            .as_ref()
+            .and_then(|f| f.block_reason.as_deref())
+        {
+            anyhow::bail!("Gemini blocked the prompt: {}", reason);
+        }
+
+        let candidate = self.candidates.first().context("No response from Gemini")?;
+        let text = candidate.text();
+        if text.is_empty() {
+            match candidate.finish_reason.as_deref() {
+                Some(reason) if reason != "STOP" => {
+                    anyhow::bail!("No response from Gemini (finish reason {})", reason)
+                }
+                _ => anyhow::bail!("No response from Gemini"),
+            }
+        }
+        Ok(text)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCandidate {
+    content: Option<GeminiContent>,
+    finish_reason: Option<String>,
+}
+
+impl GeminiCandidate {
+    fn text(&self) -> String {
+        self.content
+            .iter()
+            .flat_map(|content| &content.parts)
+            .filter_map(|part| part.text.as_deref())
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    prompt_token_count: Option<u64>,
+    candidates_token_count: Option<u64>,
+}
+
+impl UsageMetadata {
+    fn usage(&self) -> Option<Usage> {
+        Usage::from_counts(self.prompt_token_count, self.candidates_token_count)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiError {
+    message: String,
+}
+
+#[async_trait]
+impl Provider for GeminiProvider {
+    fn name(&self) -> &str {
+        "Gemini"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages);
+        let completion = self.complete(&mut request).await?;
+
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion> {
+        let mut request = self.build_request(messages);
+        request.drop_schema();
+        self.complete(&mut request).await
+    }
+
+    async fn refine_stream(
+        &self,
+        messages: &[ChatMessage],
+        on_token: TokenSink<'_>,
+    ) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages);
+        let response = self.send(&mut request, true).await?;
+
+        let mut extractor = RefinedPromptExtractor::new();
+        let mut usage = None;
+        let mut blocked = None;
+        for_each_line(response, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(true);
+            };
+            let chunk: GeminiResponse = serde_json::from_str(data)
+                .with_context(|| format!("Failed to parse Gemini stream chunk: {}", data))?;
+            if let Some(error) = chunk.error {
+                anyhow::bail!("Gemini returned error: {}", error.message);
+            }
+            // Every chunk carries the running totals
+            if let Some(metadata) = chunk.usage_metadata {
+                usage = metadata.usage().or(usage);
+            }
+            if let Some(feedback) = chunk.prompt_feedback {
+                if feedback.block_reason.is_some() {
+                    blocked = feedback.block_reason;
+                }
+            }
+            if let Some(candidate) = chunk.candidates.first() {
+                let text = extractor.push(&candidate.text());
+                if !text.is_empty() {
+                    on_token(&text);
+                }
+            }
+            Ok(true)
+        })
+        .await?;
+
+        if let Some(reason) = blocked {
+            anyhow::bail!("Gemini blocked the prompt: {}", reason);
+        }
+        if extractor.raw().is_empty() {
+            anyhow::bail!("No response from Gemini");
+        }
+
+        let completion = Completion {
+            content: extractor.raw().to_string(),
+            usage,
+        };
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        let response = self
+            .transport
+            .send(|| {
+                self.client
+                    .get(format!("{}/models", self.endpoint))
+                    .query(&[("pageSize", "1000")])
+                    .header("x-goog-api-key", &self.api_key)
+            })
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+
+            if auth_failed(status, &body) {
+                return Err(auth_error());
+            }
+
+            anyhow::bail!("Gemini returned error {}: {}", status, body);
+        }
+
+        let list: GeminiModelList = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse Gemini model list")?;
+
+        // Embedding and other models can't refine prompts
+        Ok(list
+            .models
+            .into_iter()
+            .filter(|model| {
+                model
+                    .supported_generation_methods
+                    .iter()
+                    .any(|method| method == "generateContent")
+            })
+            .map(|model| ModelInfo {
+                display_name: model.display_name,
+                context_length: model.input_token_limit,
+                ..ModelInfo::new(
+                    model
+                        .name
+                        .strip_prefix("models/")
+                        .unwrap_or(&model.name)
+                        .to_string(),
+                )
+            })
+            .collect())
+    }
+}
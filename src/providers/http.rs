@@ -0,0 +1,190 @@
+//! Shared construction of the HTTP client every provider sends requests with
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Client, NoProxy, Proxy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Seconds to wait for a response, and then for each chunk of its body
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 300;
+
+/// Seconds allowed to establish a connection
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+
+/// Client settings from the `[http]` table, overridable per provider.
+///
+/// Without `proxy`, the standard `HTTPS_PROXY`/`HTTP_PROXY`/`NO_PROXY`
+/// environment variables apply.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HttpSettings {
+    /// Seconds to wait for a connection (default 30)
+    pub connect_timeout_secs: Option<u64>,
+    /// Seconds a whole request may take, including a streamed response
+    /// (default unlimited: a request only fails once the server stops
+    /// sending for `read_timeout_secs`)
+    pub timeout_secs: Option<u64>,
+    /// Seconds to wait for a response, and then for each chunk of its body,
+    /// streamed or not (default 300)
+    pub read_timeout_secs: Option<u64>,
+    /// Proxy URL for all requests, e.g. `http://proxy.corp:3128`
+    pub proxy: Option<String>,
+    /// Comma-separated hosts, domains and CIDRs that bypass `proxy`; defaults
+    /// to the `NO_PROXY` environment variable
+    pub no_proxy: Option<String>,
+    /// PEM file of extra root certificates to trust
+    pub ca_bundle: Option<PathBuf>,
+    /// `User-Agent` header (default `prep/<version>`)
+    pub user_agent: Option<String>,
+    /// Static headers sent with every request
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Fields accepted by `config set http.<field>` and
+/// `config set providers.<name>.<field>`
+pub const HTTP_FIELDS: &[&str] = &[
+    "connect_timeout_secs",
+    "timeout_secs",
+    "read_timeout_secs",
+    "proxy",
+    "no_proxy",
+    "ca_bundle",
+    "user_agent",
+];
+
+impl HttpSettings {
+    /// Whether nothing is set
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fill unset fields from `base`. Headers are combined, with this
+    /// table's values winning.
+    pub fn or(self, base: &HttpSettings) -> Self {
+        let mut headers = base.headers.clone();
+        headers.extend(self.headers);
+        Self {
+            connect_timeout_secs: self.connect_timeout_secs.or(base.connect_timeout_secs),
+            timeout_secs: self.timeout_secs.or(base.timeout_secs),
+            read_timeout_secs: self.read_timeout_secs.or(base.read_timeout_secs),
+            proxy: self.proxy.or_else(|| base.proxy.clone()),
+            no_proxy: self.no_proxy.or_else(|| base.no_proxy.clone()),
+            ca_bundle: self.ca_bundle.or_else(|| base.ca_bundle.clone()),
+            user_agent: self.user_agent.or_else(|| base.user_agent.clone()),
+            headers,
+        }
+    }
+
+    /// Get a field by name, for `config get`
+    pub fn get(&self, field: &str) -> Option<String> {
+        match field {
+            "connect_timeout_secs" => self.connect_timeout_secs.map(|v| v.to_string()),
+            "timeout_secs" => self.timeout_secs.map(|v| v.to_string()),
+            "read_timeout_secs" => self.read_timeout_secs.map(|v| v.to_string()),
+            "proxy" => self.proxy.clone(),
+            "no_proxy" => self.no_proxy.clone(),
+            "ca_bundle" => self.ca_bundle.as_ref().map(|p| p.display().to_string()),
+            "user_agent" => self.user_agent.clone(),
+            _ => None,
+        }
+    }
+
+    /// Set a field by name, for `config set`
+    pub fn set(&mut self, field: &str, value: &str) -> Result<()> {
+        match field {
+            "connect_timeout_secs" => {
+                self.connect_timeout_secs = Some(value.parse().context("Invalid number")?);
+            }
+            "timeout_secs" => {
+                self.timeout_secs = Some(value.parse().context("Invalid number")?);
+            }
+            "read_timeout_secs" => {
+                self.read_timeout_secs = Some(value.parse().context("Invalid number")?);
+            }
+            "proxy" => {
+                proxy_url(value)?;
+                self.proxy = Some(value.to_string());
+            }
+            "no_proxy" => self.no_proxy = Some(value.to_string()),
+            "ca_bundle" => self.ca_bundle = Some(PathBuf::from(value)),
+            "user_agent" => self.user_agent = Some(value.to_string()),
+            _ => anyhow::bail!("Unknown HTTP setting: {}", field),
+        }
+        Ok(())
+    }
+
+    /// How long to wait for a response and for each chunk of its body
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs.unwrap_or(DEFAULT_READ_TIMEOUT_SECS))
+    }
+}
+
+/// Build a client from `settings`
+pub fn build_client(settings: &HttpSettings) -> Result<Client> {
+    let user_agent = settings
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| format!("prep/{}", env!("CARGO_PKG_VERSION")));
+
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(
+            settings
+                .connect_timeout_secs
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+        ))
+        .user_agent(user_agent)
+        .default_headers(header_map(&settings.headers)?);
+
+    // Streamed responses can legitimately run long, so there's no overall
+    // limit unless one is configured; stalls are caught by the read timeout
+    if let Some(secs) = settings.timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+
+    if let Some(url) = &settings.proxy {
+        let no_proxy = match &settings.no_proxy {
+            Some(list) => NoProxy::from_string(list),
+            None => NoProxy::from_env(),
+        };
+        let proxy = Proxy::all(proxy_url(url)?)
+            .with_context(|| format!("Invalid proxy URL: {}", url))?
+            .no_proxy(no_proxy);
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(path) = &settings.ca_bundle {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Failed to read CA bundle: {}", path.display()))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA bundle: {}", path.display()))?;
+        if certificates.is_empty() {
+            anyhow::bail!("CA bundle contains no certificates: {}", path.display());
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    builder.build().context("Failed to create HTTP client")
+}
+
+fn proxy_url(url: &str) -> Result<reqwest::Url> {
+    reqwest::Url::parse(url).with_context(|| format!("Invalid proxy URL: {}", url))
+}
+
+fn header_map(headers: &BTreeMap<String, String>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name: {}", name))?;
+        let mut header_value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value for header {}", name))?;
+        // Keep header values out of debug logs, they often carry credentials
+        header_value.set_sensitive(true);
+        map.insert(header_name, header_value);
+    }
+    Ok(map)
+}
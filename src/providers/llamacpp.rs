@@ -0,0 +1,652 @@
+use super::stream::{for_each_line, read_body, sse_data, RefinedPromptExtractor, TokenSink};
+use super::transport::Transport;
+use super::{
+    after_rejection, structured_output_rejected, ChatMessage, GenerationParams, ModelInfo,
+    Provider, ProviderError, Role,
+};
+use crate::refiner::{self, Completion, RefinerResponse, Usage};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// `n_predict` sent when `max_tokens` is unset, so a model that never
+/// finishes its JSON can't generate forever
+const DEFAULT_N_PREDICT: u32 = 2048;
+
+/// How the conversation is rendered into the raw `/completion` prompt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatTemplate {
+    /// The model's own template, via the server's `/apply-template`
+    Server,
+    ChatMl,
+    Llama3,
+    Gemma,
+}
+
+impl ChatTemplate {
+    pub const NAMES: &'static [&'static str] = &["server", "chatml", "llama3", "gemma"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "server" => Some(Self::Server),
+            "chatml" => Some(Self::ChatMl),
+            "llama3" => Some(Self::Llama3),
+            "gemma" => Some(Self::Gemma),
+            _ => None,
+        }
+    }
+
+    /// Render `messages` and open the assistant turn
+    fn render(self, messages: &[ChatMessage]) -> String {
+        let mut prompt = String::new();
+        match self {
+            Self::Server | Self::ChatMl => {
+                for message in messages {
+                    prompt.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        message.role, message.content
+                    ));
+                }
+                prompt.push_str("<|im_start|>assistant\n");
+            }
+            Self::Llama3 => {
+                prompt.push_str("<|begin_of_text|>");
+                for message in messages {
+                    prompt.push_str(&format!(
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        message.role, message.content
+                    ));
+                }
+                prompt.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+            }
+            Self::Gemma => {
+                // Gemma has no system role; the system prompt opens the first user turn
+                let mut system = None;
+                for message in messages {
+                    match message.role {
+                        Role::System => system = Some(message.content.as_str()),
+                        role => {
+                            let role = if role == Role::Assistant {
+                                "model"
+                            } else {
+                                role.as_str()
+                            };
+                            let content = match system.take() {
+                                Some(system) => format!("{}\n\n{}", system, message.content),
+                                None => message.content.clone(),
+                            };
+                            prompt.push_str(&format!(
+                                "<start_of_turn>{}\n{}<end_of_turn>\n",
+                                role, content
+                            ));
+                        }
+                    }
+                }
+                prompt.push_str("<start_of_turn>model\n");
+            }
+        }
+        prompt
+    }
+
+    /// End-of-turn marker to stop on when no grammar ends generation
+    fn stop(self) -> Option<&'static str> {
+        match self {
+            Self::Server => None,
+            Self::ChatMl => Some("<|im_end|>"),
+            Self::Llama3 => Some("<|eot_id|>"),
+            Self::Gemma => Some("<end_of_turn>"),
+        }
+    }
+}
+
+impl std::fmt::Display for ChatTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Server => write!(f, "server"),
+            Self::ChatMl => write!(f, "chatml"),
+            Self::Llama3 => write!(f, "llama3"),
+            Self::Gemma => write!(f, "gemma"),
+        }
+    }
+}
+
+/// How output is constrained to the `RefinerResponse` shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrammarMode {
+    /// A GBNF grammar generated from the response schema
+    Gbnf,
+    /// The response schema itself, converted to a grammar by the server
+    JsonSchema,
+}
+
+impl GrammarMode {
+    pub const NAMES: &'static [&'static str] = &["gbnf", "json_schema"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gbnf" => Some(Self::Gbnf),
+            "json_schema" => Some(Self::JsonSchema),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for GrammarMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gbnf => write!(f, "gbnf"),
+            Self::JsonSchema => write!(f, "json_schema"),
+        }
+    }
+}
+
+pub struct LlamaCppProvider {
+    client: Client,
+    endpoint: String,
+    model: String,
+    api_key: Option<String>,
+    transport: Transport,
+    /// Constrain output with a grammar; cleared if the server rejects it
+    structured: AtomicBool,
+    grammar: GrammarMode,
+    template: ChatTemplate,
+    /// Set once `/apply-template` turns out to be missing
+    no_server_template: AtomicBool,
+    params: GenerationParams,
+    cache_prompt: bool,
+    slot_id: Option<i64>,
+}
+
+impl LlamaCppProvider {
+    pub fn new(client: Client, endpoint: String, model: String, api_key: Option<String>) -> Self {
+        Self {
+            client,
+            endpoint,
+            model,
+            api_key,
+            transport: Transport::default(),
+            structured: AtomicBool::new(true),
+            grammar: GrammarMode::Gbnf,
+            template: ChatTemplate::Server,
+            no_server_template: AtomicBool::new(false),
+            params: GenerationParams::default(),
+            cache_prompt: true,
+            slot_id: None,
+        }
+    }
+
+    /// How HTTP requests are sent (retries, record/replay)
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Constrain output to the `RefinerResponse` shape with a grammar
+    pub fn with_structured_output(self, enabled: bool) -> Self {
+        self.structured.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    /// Send a GBNF grammar or the JSON Schema
+    pub fn with_grammar(mut self, grammar: GrammarMode) -> Self {
+        self.grammar = grammar;
+        self
+    }
+
+    /// How to render the chat into a prompt. `Server` falls back to ChatML
+    /// on servers without `/apply-template`.
+    pub fn with_chat_template(mut self, template: ChatTemplate) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Sampling and length settings; `max_tokens` is sent as `n_predict`
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Reuse the KV cache for a shared prompt prefix across requests
+    pub fn with_cache_prompt(mut self, cache_prompt: bool) -> Self {
+        self.cache_prompt = cache_prompt;
+        self
+    }
+
+    /// Pin requests to one server slot so its cached prompt is reused
+    pub fn with_slot(mut self, slot_id: Option<i64>) -> Self {
+        self.slot_id = slot_id;
+        self
+    }
+
+    fn authorize(&self, builder: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
+
+    fn build_request(&self, messages: &[ChatMessage], stream: bool) -> CompletionRequest {
+        let mut request = CompletionRequest {
+            messages: messages.to_vec(),
+            prompt: String::new(),
+            n_predict: self.params.max_tokens.unwrap_or(DEFAULT_N_PREDICT),
+            temperature: self.params.temperature,
+            top_p: self.params.top_p,
+            seed: self.params.seed,
+            stop: self.params.stop.clone(),
+            cache_prompt: self.cache_prompt,
+            id_slot: self.slot_id,
+            grammar: None,
+            json_schema: None,
+            stream,
+        };
+        if self.structured.load(Ordering::Relaxed) {
+            let schema = refiner::response_schema();
+            match (self.grammar, gbnf_from_schema(&schema)) {
+                (GrammarMode::Gbnf, Some(grammar)) => request.grammar = Some(grammar),
+                _ => request.json_schema = Some(schema),
+            }
+        }
+        request
+    }
+
+    /// Fill in `request.prompt` from its messages
+    async fn render(&self, request: &mut CompletionRequest) -> Result<()> {
+        let template = if self.template == ChatTemplate::Server
+            && self.no_server_template.load(Ordering::Relaxed)
+        {
+            ChatTemplate::ChatMl
+        } else {
+            self.template
+        };
+
+        if template == ChatTemplate::Server {
+            match self.apply_server_template(&request.messages).await? {
+                Some(prompt) => {
+                    request.prompt = prompt;
+                    return Ok(());
+                }
+                None => {
+                    tracing::debug!("llama.cpp has no /apply-template, using ChatML");
+                    self.no_server_template.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let template = match template {
+            ChatTemplate::Server => ChatTemplate::ChatMl,
+            template => template,
+        };
+        request.prompt = template.render(&request.messages);
+        if let Some(stop) = template.stop() {
+            if !request.stop.iter().any(|s| s == stop) {
+                request.stop.push(stop.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Render messages with the model's template. `None` if the server is too
+    /// old to have the endpoint.
+    async fn apply_server_template(&self, messages: &[ChatMessage]) -> Result<Option<String>> {
+        let body = ApplyTemplateRequest { messages };
+        let response = self
+            .transport
+            .send(|| {
+                let builder = self
+                    .client
+                    .post(format!("{}/apply-template", self.endpoint))
+                    .json(&body);
+                self.authorize(builder)
+            })
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        let status = response.status();
+        if matches!(status.as_u16(), 404 | 405 | 501) {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(self.status_error(status, &body));
+        }
+
+        let applied: ApplyTemplateResponse = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse llama.cpp template response")?;
+        Ok(Some(applied.prompt))
+    }
+
+    /// Turn a transport failure into a user-facing error
+    fn request_error(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_connect() => anyhow::Error::new(ProviderError::Connection(
+                format!("Could not connect to llama.cpp server at {}", self.endpoint),
+            )),
+            Some(err) if err.is_timeout() => anyhow::anyhow!("Request timed out"),
+            Some(err) => anyhow::anyhow!("HTTP request failed: {}", err),
+            None => e,
+        }
+    }
+
+    fn status_error(&self, status: StatusCode, body: &str) -> anyhow::Error {
+        match status.as_u16() {
+            401 => ProviderError::Auth(
+                "Authentication failed. Check your LLAMA_API_KEY environment variable.".to_string(),
+            )
+            .into(),
+            // Returned when every slot is busy
+            503 => ProviderError::RateLimited(
+                "llama.cpp server is busy. Please wait and try again.".to_string(),
+            )
+            .into(),
+            _ => anyhow::anyhow!("llama.cpp returned error {}: {}", status, body),
+        }
+    }
+
+    async fn send(&self, request: &mut CompletionRequest) -> Result<Response> {
+        let mut rejection = None;
+        loop {
+            self.render(request)
+                .await
+                .map_err(|e| after_rejection(e, rejection.take()))?;
+
+            let payload: &CompletionRequest = request;
+            let response = self
+                .transport
+                .send(|| {
+                    let builder = self
+                        .client
+                        .post(format!("{}/completion", self.endpoint))
+                        .header("Content-Type", "application/json")
+                        .json(payload);
+                    self.authorize(builder)
+                })
+                .await
+                .map_err(|e| after_rejection(self.request_error(e), rejection.take()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+
+                if structured_output_rejected(status, &body) && request.drop_grammar() {
+                    tracing::debug!(
+                        "llama.cpp rejected the grammar ({}), retrying without it",
+                        status
+                    );
+                    rejection = Some(format!("llama.cpp returned error {}: {}", status, body));
+                    continue;
+                }
+
+                return Err(after_rejection(self.status_error(status, &body), rejection));
+            }
+
+            if rejection.is_some() {
+                self.structured.store(false, Ordering::Relaxed);
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Send a non-streaming request and return the model's reply text
+    async fn complete(&self, request: &mut CompletionRequest) -> Result<Completion> {
+        let response = self.send(request).await?;
+
+        let completion: CompletionResponse = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse llama.cpp response")?;
+
+        if completion.content.is_empty() {
+            anyhow::bail!("No response from llama.cpp");
+        }
+
+        Ok(Completion {
+            usage: completion.usage(),
+            content: completion.content,
+        })
+    }
+}
+
+/// GBNF grammar matching `schema`, with properties in `required` order.
+/// Handles the object, string, boolean and string-array types the refiner
+/// schema uses; `None` for anything else.
+fn gbnf_from_schema(schema: &Value) -> Option<String> {
+    let properties = schema.get("properties")?.as_object()?;
+    let required = schema.get("required")?.as_array()?;
+
+    let mut fields = Vec::new();
+    for name in required {
+        let name = name.as_str()?;
+        let rule = match properties.get(name)?.get("type")?.as_str()? {
+            "string" => "string",
+            "boolean" => "boolean",
+            "array" => {
+                let items = properties[name].get("items")?.get("type")?.as_str()?;
+                if items != "string" {
+                    return None;
+                }
+                "string-array"
+            }
+            _ => return None,
+        };
+        fields.push(format!(r#""\"{}\":" ws {}"#, name, rule));
+    }
+
+    Some(format!(
+        r#"root ::= "{{" ws {} ws "}}"
+string-array ::= "[" ws ( string ( ws "," ws string )* )? ws "]"
+string ::= "\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\""
+boolean ::= "true" | "false"
+ws ::= | " "
+"#,
+        fields.join(r#" ws "," ws "#)
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct ApplyTemplateRequest<'a> {
+    messages: &'a [ChatMessage],
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplyTemplateResponse {
+    prompt: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionRequest {
+    /// The conversation `prompt` is rendered from
+    #[serde(skip)]
+    messages: Vec<ChatMessage>,
+    prompt: String,
+    n_predict: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    cache_prompt: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_slot: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grammar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_schema: Option<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+impl CompletionRequest {
+    /// Fall back from a grammar to the prompt's JSON instructions. Returns
+    /// `false` if no grammar was being sent.
+    fn drop_grammar(&mut self) -> bool {
+        let had_grammar = self.grammar.is_some() || self.json_schema.is_some();
+        self.grammar = None;
+        self.json_schema = None;
+        had_grammar
+    }
+
+    /// Append the rejected reply and a corrective instruction as a follow-up turn
+    fn push_repair(&mut self, previous: String, correction: String) {
+        self.stream = false;
+        self.messages.push(ChatMessage::assistant(previous));
+        self.messages.push(ChatMessage::user(correction));
+    }
+}
+
+/// A `/completion` reply, or one chunk of a streamed reply
+#[derive(Debug, Deserialize)]
+struct CompletionResponse {
+    #[serde(default)]
+    content: String,
+    /// Set on the last chunk of a stream
+    #[serde(default)]
+    stop: bool,
+    tokens_predicted: Option<u64>,
+    tokens_evaluated: Option<u64>,
+    error: Option<CompletionError>,
+}
+
+impl CompletionResponse {
+    fn usage(&self) -> Option<Usage> {
+        Usage::from_counts(self.tokens_evaluated, self.tokens_predicted)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LlamaCppModelList {
+    data: Vec<LlamaCppModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LlamaCppModel {
+    id: String,
+    meta: Option<LlamaCppModelMeta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LlamaCppModelMeta {
+    n_ctx_train: Option<u64>,
+    n_params: Option<u64>,
+    size: Option<u64>,
+}
+
+#[async_trait]
+impl Provider for LlamaCppProvider {
+    fn name(&self) -> &str {
+        "llama.cpp"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages, false);
+        let completion = self.complete(&mut request).await?;
+
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion> {
+        let mut request = self.build_request(messages, false);
+        request.drop_grammar();
+        self.complete(&mut request).await
+    }
+
+    async fn refine_stream(
+        &self,
+        messages: &[ChatMessage],
+        on_token: TokenSink<'_>,
+    ) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages, true);
+        let response = self.send(&mut request).await?;
+
+        let mut extractor = RefinedPromptExtractor::new();
+        let mut usage = None;
+        for_each_line(response, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(true);
+            };
+            let chunk: CompletionResponse = serde_json::from_str(data)
+                .with_context(|| format!("Failed to parse llama.cpp stream chunk: {}", data))?;
+            if let Some(error) = chunk.error {
+                anyhow::bail!("llama.cpp returned error: {}", error.message);
+            }
+            let text = extractor.push(&chunk.content);
+            if !text.is_empty() {
+                on_token(&text);
+            }
+            if chunk.stop {
+                usage = chunk.usage();
+                return Ok(false);
+            }
+            Ok(true)
+        })
+        .await?;
+
+        if extractor.raw().is_empty() {
+            anyhow::bail!("No response from llama.cpp");
+        }
+
+        let completion = Completion {
+            content: extractor.raw().to_string(),
+            usage,
+        };
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    /// The model the server has loaded
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        let response = self
+            .transport
+            .send(|| self.authorize(self.client.get(format!("{}/v1/models", self.endpoint))))
+            .await
+            .map_err(|e| self.request_error(e))?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(self.status_error(status, &body));
+        }
+
+        let list: LlamaCppModelList = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse llama.cpp model list")?;
+
+        Ok(list
+            .data
+            .into_iter()
+            .map(|model| {
+                let meta = model.meta;
+                ModelInfo {
+                    context_length: meta.as_ref().and_then(|m| m.n_ctx_train),
+                    size_bytes: meta.as_ref().and_then(|m| m.size),
+                    parameter_size: meta
+                        .as_ref()
+                        .and_then(|m| m.n_params)
+                        .map(|n| format!("{:.1}B", n as f64 / 1e9)),
+                    ..ModelInfo::new(model.id)
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,143 @@
+//! Conversation turns sent to providers
+
+use super::SYSTEM_PROMPT;
+use crate::refiner::RefinerResponse;
+use serde::{Deserialize, Serialize};
+
+/// Who a turn in the conversation comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// One turn of a conversation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+
+    /// The model's earlier reply, re-serialized as the JSON it answered with
+    pub fn from_response(response: &RefinerResponse) -> Self {
+        Self::assistant(serde_json::to_string(response).unwrap_or_default())
+    }
+}
+
+/// Start a refinement conversation: the system prompt, then the prompt to
+/// refine with any context
+pub fn new_conversation(
+    system_prompt: &str,
+    prompt: &str,
+    context: Option<&str>,
+) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::system(system_prompt),
+        ChatMessage::user(build_user_message(prompt, context)),
+    ]
+}
+
+/// The user turn answering the model's clarifying questions. Unless this is
+/// the `last` round, the model may ask again about anything still unclear.
+pub fn clarification_message(summary: &str, last: bool) -> ChatMessage {
+    let request = if last {
+        "Please provide the final refined prompt based on this additional context, without asking further questions."
+    } else {
+        "Please refine the prompt based on this additional context. Ask further questions only if something essential is still unclear."
+    };
+    ChatMessage::user(format!(
+        "User provided the following clarifications:\n{}\n\n{}",
+        summary.trim_end(),
+        request
+    ))
+}
+
+/// Introduces the prompt in the first user message
+const REFINE_HEADER: &str = "Please refine the following prompt:\n\n";
+
+/// Build the first user message for the refiner
+pub fn build_user_message(prompt: &str, context: Option<&str>) -> String {
+    let mut message = String::new();
+
+    if let Some(ctx) = context {
+        message.push_str("Context:\n");
+        message.push_str(ctx);
+        message.push_str("\n\n");
+    }
+
+    message.push_str(REFINE_HEADER);
+    message.push_str(prompt);
+
+    message
+}
+
+/// The prompt being refined, from the first user message of a conversation
+/// started with [`new_conversation`]
+pub fn original_prompt(messages: &[ChatMessage]) -> Option<&str> {
+    let first = messages.iter().find(|m| m.role == Role::User)?;
+    Some(
+        first
+            .content
+            .split_once(REFINE_HEADER)
+            .map_or(first.content.as_str(), |(_, prompt)| prompt),
+    )
+}
+
+/// The system prompt (system turns joined, or the default if there are none)
+/// and the remaining turns, for APIs that take the system prompt separately
+pub(crate) fn split_system(messages: &[ChatMessage]) -> (String, Vec<&ChatMessage>) {
+    let system = messages
+        .iter()
+        .filter(|m| m.role == Role::System)
+        .map(|m| m.content.as_str())
+        .collect::<Vec<_>>();
+    let system = if system.is_empty() {
+        SYSTEM_PROMPT.to_string()
+    } else {
+        system.join("\n\n")
+    };
+    let turns = messages.iter().filter(|m| m.role != Role::System).collect();
+    (system, turns)
+}
+
+/// Appended to the system prompt when a provider falls back from native
+/// structured output
+pub(crate) const JSON_ONLY_INSTRUCTION: &str = "IMPORTANT: Respond with ONLY a valid JSON object. No markdown code blocks, no explanation, just the raw JSON.";
@@ -0,0 +1,142 @@
+use super::{original_prompt, ChatMessage, ModelInfo, Provider, Role};
+use crate::refiner::{Completion, RefinerResponse};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Offline provider that returns scripted responses, for tests and demos.
+///
+/// Each call returns the next response from the fixture; the last one is
+/// repeated once the script runs out. Without a fixture the prompt is echoed,
+/// followed by any later user turns such as clarification answers.
+///
+/// Raw [`Provider::chat`] calls are served from `replies` in the same way,
+/// and echo the last user turn when there are none.
+pub struct MockProvider {
+    model: String,
+    responses: Vec<RefinerResponse>,
+    replies: Vec<String>,
+    calls: AtomicUsize,
+    chats: AtomicUsize,
+}
+
+/// Fixture file layout (TOML or JSON)
+#[derive(Debug, Deserialize)]
+struct Fixture {
+    #[serde(default)]
+    responses: Vec<RefinerResponse>,
+    #[serde(default)]
+    replies: Vec<String>,
+}
+
+impl MockProvider {
+    pub fn new(model: String) -> Self {
+        Self {
+            model,
+            responses: Vec::new(),
+            replies: Vec::new(),
+            calls: AtomicUsize::new(0),
+            chats: AtomicUsize::new(0),
+        }
+    }
+
+    /// Use a fixed list of responses
+    pub fn with_responses(mut self, responses: Vec<RefinerResponse>) -> Self {
+        self.responses = responses;
+        self
+    }
+
+    /// Use a fixed list of raw replies for [`Provider::chat`]
+    pub fn with_replies(mut self, replies: Vec<String>) -> Self {
+        self.replies = replies;
+        self
+    }
+
+    /// Load scripted responses from a `.toml` or `.json` fixture file
+    pub fn from_fixture(model: String, path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read mock fixture: {}", path.display()))?;
+
+        let fixture: Fixture = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse mock fixture: {}", path.display()))?
+        } else {
+            toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse mock fixture: {}", path.display()))?
+        };
+
+        if fixture.responses.is_empty() && fixture.replies.is_empty() {
+            anyhow::bail!("Mock fixture {} has no responses", path.display());
+        }
+
+        Ok(Self::new(model)
+            .with_responses(fixture.responses)
+            .with_replies(fixture.replies))
+    }
+
+    /// Number of refinement calls made so far
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl Provider for MockProvider {
+    fn name(&self) -> &str {
+        "Mock"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst);
+
+        if self.responses.is_empty() {
+            let mut turns = vec![original_prompt(messages).unwrap_or_default()];
+            turns.extend(
+                messages
+                    .iter()
+                    .filter(|m| m.role == Role::User)
+                    .skip(1)
+                    .map(|m| m.content.trim()),
+            );
+            let refined_prompt = turns.join("\n\n");
+            return Ok(RefinerResponse {
+                refined_prompt,
+                needs_clarification: false,
+                questions: Vec::new(),
+                usage: None,
+            });
+        }
+
+        let index = call.min(self.responses.len() - 1);
+        Ok(self.responses[index].clone())
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion> {
+        let call = self.chats.fetch_add(1, Ordering::SeqCst);
+
+        let content = if self.replies.is_empty() {
+            messages
+                .iter()
+                .rev()
+                .find(|m| m.role == Role::User)
+                .map(|m| m.content.clone())
+                .unwrap_or_default()
+        } else {
+            self.replies[call.min(self.replies.len() - 1)].clone()
+        };
+        Ok(Completion {
+            content,
+            usage: None,
+        })
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        Ok(vec![ModelInfo::new(self.model.clone())])
+    }
+}
@@ -1,16 +1,48 @@
 //! Provider implementations for different AI backends
 
 mod anthropic;
+mod aws;
+mod bedrock;
+mod cassette;
+mod error;
+mod gemini;
+mod http;
+mod llamacpp;
+mod message;
+mod mock;
+mod models;
 mod ollama_cloud;
 mod ollama_local;
 mod openai;
+mod params;
+mod registry;
+mod retry;
+mod stream;
+mod transport;
 
 pub use anthropic::AnthropicProvider;
+pub use aws::AwsCredentials;
+pub use bedrock::BedrockProvider;
+pub use cassette::{Cassette, CassetteMode};
+pub use error::ProviderError;
+pub use gemini::GeminiProvider;
+pub use http::{build_client, HttpSettings, HTTP_FIELDS};
+pub use llamacpp::{ChatTemplate, GrammarMode, LlamaCppProvider};
+pub use message::{
+    build_user_message, clarification_message, new_conversation, original_prompt, ChatMessage, Role,
+};
+pub use mock::MockProvider;
+pub use models::{check_model, ModelInfo};
 pub use ollama_cloud::OllamaCloudProvider;
 pub use ollama_local::OllamaLocalProvider;
 pub use openai::OpenAIProvider;
+pub use params::{GenerationParams, PARAM_FIELDS};
+pub use registry::{build_provider, ProviderKind};
+pub use retry::{RetryNotice, RetryObserver, RetryPolicy};
+pub use stream::{RefinedPromptExtractor, TokenSink};
+pub use transport::Transport;
 
-use crate::refiner::RefinerResponse;
+use crate::refiner::{Completion, RefinerResponse};
 use anyhow::Result;
 use async_trait::async_trait;
 
@@ -44,46 +76,75 @@ Remember: Your output is ONLY the JSON object, nothing else."#;
 #[async_trait]
 pub trait Provider: Send + Sync {
     /// Provider name for display
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
 
     /// Model being used
     fn model(&self) -> &str;
 
-    /// Refine a prompt
-    async fn refine(
+    /// Refine a prompt given the conversation so far: the system prompt, the
+    /// prompt to refine, and any earlier replies and clarifications
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse>;
+
+    /// Refine a prompt, passing the refined prompt text to `on_token` as it
+    /// is generated. The complete response is still returned at the end.
+    ///
+    /// Providers without streaming support emit the whole prompt at once.
+    async fn refine_stream(
         &self,
-        prompt: &str,
-        context: Option<&str>,
-        clarification: Option<&str>,
-    ) -> Result<RefinerResponse>;
-}
+        messages: &[ChatMessage],
+        on_token: TokenSink<'_>,
+    ) -> Result<RefinerResponse> {
+        let response = self.refine(messages).await?;
+        on_token(&response.refined_prompt);
+        Ok(response)
+    }
+
+    /// Send a conversation without the refiner's response schema and return
+    /// the raw reply, for tasks with their own output format such as scoring.
+    /// The reply is still requested as JSON where the backend supports it.
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion>;
 
-/// Build user message for the refiner
-pub fn build_user_message(
-    prompt: &str,
-    context: Option<&str>,
-    clarification: Option<&str>,
-) -> String {
-    let mut message = String::new();
-
-    if let Some(ctx) = context {
-        message.push_str("Context:\n");
-        message.push_str(ctx);
-        message.push_str("\n\n");
+    /// Models this provider can serve
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        anyhow::bail!("{} does not support listing models", self.name())
     }
+}
+
+/// Request fields for native structured output, as servers name them when
+/// they reject one (`toolConfig` is Bedrock's, "json schema" Ollama's)
+const STRUCTURED_OUTPUT_FIELDS: &[&str] = &[
+    "response_format",
+    "json_schema",
+    "json schema",
+    "tools",
+    "tool_choice",
+    "toolconfig",
+    "grammar",
+    "responseschema",
+];
 
-    if let Some(clarify) = clarification {
-        message.push_str("Original prompt:\n");
-        message.push_str(prompt);
-        message.push_str("\n\nUser provided the following clarifications:\n");
-        message.push_str(clarify);
-        message.push_str(
-            "\n\nPlease provide the final refined prompt based on this additional context.",
-        );
-    } else {
-        message.push_str("Please refine the following prompt:\n\n");
-        message.push_str(prompt);
+/// Whether an error response means the server rejected native structured
+/// output (a response schema, forced tool or grammar), so the request should
+/// be retried with the prompt-only JSON instructions. Other client errors,
+/// such as an oversized prompt or a bad parameter, are not retried.
+pub(crate) fn structured_output_rejected(status: reqwest::StatusCode, body: &str) -> bool {
+    if !matches!(status.as_u16(), 400 | 422 | 501) {
+        return false;
     }
+    let body = body.to_lowercase();
+    STRUCTURED_OUTPUT_FIELDS
+        .iter()
+        .any(|field| body.contains(field))
+}
 
-    message
+/// The error from a retry without structured output, carrying the rejection
+/// that led to it so its body isn't lost
+pub(crate) fn after_rejection(error: anyhow::Error, rejection: Option<String>) -> anyhow::Error {
+    match rejection {
+        Some(rejection) => error.context(format!(
+            "Retried without structured output after: {}",
+            rejection
+        )),
+        None => error,
+    }
 }
@@ -0,0 +1,192 @@
+//! Model discovery and `--model` validation
+
+use serde::Serialize;
+
+/// A model a provider can serve
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModelInfo {
+    /// Identifier passed as `--model`
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Download size in bytes (local models)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    /// Parameter count as reported by the server, e.g. `8.0B`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameter_size: Option<String>,
+    /// Maximum context length in tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<u64>,
+}
+
+impl ModelInfo {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether `model` names this model. Ollama's implicit `:latest` tag is
+    /// accepted on either side.
+    pub fn matches(&self, model: &str) -> bool {
+        let strip = |s: &str| s.strip_suffix(":latest").map(str::to_string);
+        self.id == model
+            || strip(&self.id).as_deref() == Some(model)
+            || strip(model).as_deref() == Some(self.id.as_str())
+    }
+}
+
+/// Check that `model` is among `models`, suggesting close matches if not
+pub fn check_model(provider: &str, model: &str, models: &[ModelInfo]) -> anyhow::Result<()> {
+    if models.is_empty() || models.iter().any(|m| m.matches(model)) {
+        return Ok(());
+    }
+
+    let suggestions = suggest(model, models.iter().map(|m| m.id.as_str()));
+    let hint = if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!(" Did you mean: {}?", suggestions.join(", "))
+    };
+
+    anyhow::bail!(
+        "Model '{}' is not available from {}.{}\nRun 'prep models --provider {}' to see available models.",
+        model,
+        provider,
+        hint,
+        provider
+    )
+}
+
+/// Up to three candidates close to `name`, best first
+fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let name = name.to_lowercase();
+    let mut scored: Vec<(usize, &str)> = candidates
+        .filter_map(|candidate| {
+            let lower = candidate.to_lowercase();
+            let lower = lower.strip_suffix(":latest").unwrap_or(&lower);
+            let distance = edit_distance(&name, lower);
+            // Allow roughly one typo per three characters, and prefix matches
+            let close = distance <= (name.len() / 3).max(2)
+                || lower.starts_with(&name)
+                || name.starts_with(lower);
+            close.then_some((distance, candidate))
+        })
+        .collect();
+    scored.sort();
+    scored.into_iter().take(3).map(|(_, c)| c).collect()
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(prev[j + 1] + 1).min(current[j] + 1);
+        }
+        prev = current;
+    }
+
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTALLED: &[&str] = &[
+        "llama3.2:latest",
+        "llama3.1:8b",
+        "qwen2.5:7b",
+        "mistral:latest",
+        "gpt-4o",
+        "gpt-4o-mini",
+        "gpt-4.1",
+    ];
+
+    #[test]
+    fn suggest_table() {
+        let cases: &[(&str, &[&str])] = &[
+            // Typos
+            ("llama3.3", &["llama3.2:latest"]),
+            ("mistrl", &["mistral:latest"]),
+            ("MISTRAL", &["mistral:latest"]),
+            // Prefixes either way, closest first
+            ("qwen2.5", &["qwen2.5:7b"]),
+            ("gpt-4o-mini-2024", &["gpt-4o-mini", "gpt-4o"]),
+            ("gpt-4", &["gpt-4o", "gpt-4.1", "gpt-4o-mini"]),
+            // At most three
+            ("gpt", &["gpt-4o", "gpt-4.1", "gpt-4o-mini"]),
+            // Nothing close
+            ("claude-sonnet", &[]),
+            ("phi3", &[]),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(
+                suggest(name, INSTALLED.iter().copied()),
+                *expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn edit_distance_table() {
+        let cases = [
+            ("", "", 0),
+            ("abc", "", 3),
+            ("", "abc", 3),
+            ("gpt-4o", "gpt-4o", 0),
+            ("gpt-4o", "gpt-40", 1),
+            ("mistrl", "mistral", 1),
+            ("kitten", "sitting", 3),
+            ("café", "cafe", 1),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(edit_distance(a, b), expected, "{} -> {}", a, b);
+        }
+    }
+
+    #[test]
+    fn latest_tag_is_optional_on_either_side() {
+        let cases = [
+            ("llama3.2:latest", "llama3.2", true),
+            ("llama3.2", "llama3.2:latest", true),
+            ("llama3.2:latest", "llama3.2:latest", true),
+            ("llama3.2:1b", "llama3.2", false),
+            ("llama3.2", "llama3.2:1b", false),
+        ];
+        for (id, model, expected) in cases {
+            assert_eq!(
+                ModelInfo::new(id).matches(model),
+                expected,
+                "{} ~ {}",
+                id,
+                model
+            );
+        }
+    }
+
+    #[test]
+    fn check_model_suggests_close_names() {
+        let models: Vec<ModelInfo> = INSTALLED.iter().map(|id| ModelInfo::new(*id)).collect();
+
+        assert!(check_model("ollama", "llama3.2", &models).is_ok());
+        assert!(check_model("ollama", "anything", &[]).is_ok());
+        let error = check_model("ollama", "mistrl", &models).unwrap_err();
+        assert!(
+            error.to_string().contains("Did you mean: mistral:latest?"),
+            "{}",
+            error
+        );
+        let error = check_model("ollama", "phi3", &models).unwrap_err();
+        assert!(!error.to_string().contains("Did you mean"), "{}", error);
+    }
+}
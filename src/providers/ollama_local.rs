@@ -1,29 +1,169 @@
-use super::{build_user_message, Provider, SYSTEM_PROMPT};
-use crate::refiner::RefinerResponse;
+use super::params::OllamaOptions;
+use super::stream::{for_each_line, read_body, RefinedPromptExtractor, TokenSink};
+use super::transport::Transport;
+use super::{
+    after_rejection, structured_output_rejected, ChatMessage, GenerationParams, ModelInfo,
+    Provider, ProviderError,
+};
+use crate::refiner::{self, Completion, RefinerResponse, Usage};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use reqwest::Client;
+use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub struct OllamaLocalProvider {
     client: Client,
     endpoint: String,
     model: String,
+    transport: Transport,
+    /// Send the response schema as `format`; cleared if the server rejects it
+    structured: AtomicBool,
+    params: GenerationParams,
 }
 
 impl OllamaLocalProvider {
-    pub fn new(endpoint: String, model: String) -> Self {
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(300))
-            .build()
-            .expect("Failed to create HTTP client");
-
+    pub fn new(client: Client, endpoint: String, model: String) -> Self {
         Self {
             client,
             endpoint,
             model,
+            transport: Transport::default(),
+            structured: AtomicBool::new(true),
+            params: GenerationParams::default(),
+        }
+    }
+
+    /// How HTTP requests are sent (retries, record/replay)
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Send the `RefinerResponse` JSON Schema as `format` instead of `"json"`
+    pub fn with_structured_output(self, enabled: bool) -> Self {
+        self.structured.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    /// Sampling, context and keep-alive settings, sent as `options` and
+    /// `keep_alive`
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    fn build_request(&self, messages: &[ChatMessage], stream: bool) -> OllamaRequest {
+        OllamaRequest {
+            model: self.model.clone(),
+            messages: messages
+                .iter()
+                .map(|message| OllamaMessage {
+                    role: message.role.to_string(),
+                    content: message.content.clone(),
+                })
+                .collect(),
+            stream,
+            format: if self.structured.load(Ordering::Relaxed) {
+                refiner::response_schema()
+            } else {
+                Value::from("json")
+            },
+            options: self.params.ollama_options(),
+            keep_alive: self.params.ollama_keep_alive(),
+        }
+    }
+
+    /// Turn a transport failure into a user-facing error
+    fn request_error(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_connect() => {
+                anyhow::Error::new(ProviderError::Connection(format!(
+                    "Could not connect to Ollama at {}. Is Ollama running? Try: ollama serve",
+                    self.endpoint
+                )))
+            }
+            Some(err) if err.is_timeout() => anyhow::anyhow!(
+                "Request timed out. The model might be loading or the prompt is very long."
+            ),
+            Some(err) => anyhow::anyhow!("HTTP request failed: {}", err),
+            None => e,
+        }
+    }
+
+    async fn send(&self, request: &mut OllamaRequest) -> Result<Response> {
+        let mut rejection = None;
+        loop {
+            let body: &OllamaRequest = request;
+            let response = self
+                .transport
+                .send(|| {
+                    self.client
+                        .post(format!("{}/api/chat", self.endpoint))
+                        .json(body)
+                })
+                .await
+                .map_err(|e| after_rejection(self.request_error(e), rejection.take()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+
+                if structured_output_rejected(status, &body) && request.drop_schema() {
+                    tracing::debug!(
+                        "Ollama rejected the response schema ({}), retrying with format \"json\"",
+                        status
+                    );
+                    rejection = Some(format!("Ollama returned error {}: {}", status, body));
+                    continue;
+                }
+
+                return Err(after_rejection(
+                    anyhow::anyhow!("Ollama returned error {}: {}", status, body),
+                    rejection,
+                ));
+            }
+
+            if rejection.is_some() {
+                self.structured.store(false, Ordering::Relaxed);
+            }
+            return Ok(response);
         }
     }
+
+    /// Send a non-streaming request and return the model's reply text
+    async fn complete(&self, request: &mut OllamaRequest) -> Result<Completion> {
+        let response = self.send(request).await?;
+
+        let ollama_response: OllamaResponse = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse Ollama response")?;
+
+        Ok(Completion {
+            content: ollama_response.message.content,
+            usage: Usage::from_counts(
+                ollama_response.prompt_eval_count,
+                ollama_response.eval_count,
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaTags {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaModel {
+    name: String,
+    size: Option<u64>,
+    details: Option<OllamaModelDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaModelDetails {
+    parameter_size: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -31,7 +171,37 @@ struct OllamaRequest {
     model: String,
     messages: Vec<OllamaMessage>,
     stream: bool,
-    format: String,
+    /// `"json"` or a JSON Schema object
+    format: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<OllamaOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<Value>,
+}
+
+impl OllamaRequest {
+    /// Fall back from a response schema to plain JSON mode. Returns `false`
+    /// if no schema was being sent.
+    fn drop_schema(&mut self) -> bool {
+        if !self.format.is_object() {
+            return false;
+        }
+        self.format = Value::from("json");
+        true
+    }
+
+    /// Append the rejected reply and a corrective instruction as a follow-up turn
+    fn push_repair(&mut self, previous: String, correction: String) {
+        self.stream = false;
+        self.messages.push(OllamaMessage {
+            role: "assistant".to_string(),
+            content: previous,
+        });
+        self.messages.push(OllamaMessage {
+            role: "user".to_string(),
+            content: correction,
+        });
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,11 +213,24 @@ struct OllamaMessage {
 #[derive(Debug, Deserialize)]
 struct OllamaResponse {
     message: OllamaMessage,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaStreamChunk {
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    error: Option<String>,
+    /// Token counts, sent with the final chunk
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
 }
 
 #[async_trait]
 impl Provider for OllamaLocalProvider {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "Ollama (Local)"
     }
 
@@ -55,50 +238,72 @@ impl Provider for OllamaLocalProvider {
         &self.model
     }
 
-    async fn refine(
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages, false);
+        let completion = self.complete(&mut request).await?;
+
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion> {
+        let mut request = self.build_request(messages, false);
+        request.drop_schema();
+        self.complete(&mut request).await
+    }
+
+    async fn refine_stream(
         &self,
-        prompt: &str,
-        context: Option<&str>,
-        clarification: Option<&str>,
+        messages: &[ChatMessage],
+        on_token: TokenSink<'_>,
     ) -> Result<RefinerResponse> {
-        let user_message = build_user_message(prompt, context, clarification);
+        let mut request = self.build_request(messages, true);
+        let response = self.send(&mut request).await?;
 
-        let request = OllamaRequest {
-            model: self.model.clone(),
-            messages: vec![
-                OllamaMessage {
-                    role: "system".to_string(),
-                    content: SYSTEM_PROMPT.to_string(),
-                },
-                OllamaMessage {
-                    role: "user".to_string(),
-                    content: user_message,
-                },
-            ],
-            stream: false,
-            format: "json".to_string(),
+        let mut extractor = RefinedPromptExtractor::new();
+        let mut usage = None;
+        for_each_line(response, |line| {
+            if line.trim().is_empty() {
+                return Ok(true);
+            }
+            let chunk: OllamaStreamChunk = serde_json::from_str(line)
+                .with_context(|| format!("Failed to parse Ollama stream chunk: {}", line))?;
+            if let Some(error) = chunk.error {
+                anyhow::bail!("Ollama returned error: {}", error);
+            }
+            if let Some(message) = chunk.message {
+                let text = extractor.push(&message.content);
+                if !text.is_empty() {
+                    on_token(&text);
+                }
+            }
+            if chunk.done {
+                usage = Usage::from_counts(chunk.prompt_eval_count, chunk.eval_count);
+            }
+            Ok(!chunk.done)
+        })
+        .await?;
+
+        let completion = Completion {
+            content: extractor.raw().to_string(),
+            usage,
         };
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
 
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
         let response = self
-            .client
-            .post(format!("{}/api/chat", self.endpoint))
-            .json(&request)
-            .send()
+            .transport
+            .send(|| self.client.get(format!("{}/api/tags", self.endpoint)))
             .await
-            .map_err(|e| {
-                if e.is_connect() {
-                    anyhow::anyhow!(
-                        "Could not connect to Ollama at {}. Is Ollama running? Try: ollama serve",
-                        self.endpoint
-                    )
-                } else if e.is_timeout() {
-                    anyhow::anyhow!(
-                        "Request timed out. The model might be loading or the prompt is very long."
-                    )
-                } else {
-                    anyhow::anyhow!("HTTP request failed: {}", e)
-                }
-            })?;
+            .map_err(|e| self.request_error(e))?;
 
         if !response.status().is_success() {
             let status = response.status();
@@ -106,23 +311,17 @@ impl Provider for OllamaLocalProvider {
             anyhow::bail!("Ollama returned error {}: {}", status, body);
         }
 
-        let ollama_response: OllamaResponse = response
-            .json()
-            .await
-            .context("Failed to parse Ollama response")?;
-
-        let refiner_response: RefinerResponse =
-            serde_json::from_str(&ollama_response.message.content).with_context(|| {
-                format!(
-                    "Failed to parse refiner response as JSON. Raw content:\n{}",
-                    ollama_response.message.content
-                )
-            })?;
-
-        if refiner_response.refined_prompt.is_empty() {
-            anyhow::bail!("Refiner returned an empty refined_prompt");
-        }
+        let tags: OllamaTags = serde_json::from_slice(&read_body(response).await?)
+            .context("Failed to parse Ollama model list")?;
 
-        Ok(refiner_response)
+        Ok(tags
+            .models
+            .into_iter()
+            .map(|model| ModelInfo {
+                size_bytes: model.size,
+                parameter_size: model.details.and_then(|d| d.parameter_size),
+                ..ModelInfo::new(model.name)
+            })
+            .collect())
     }
 }
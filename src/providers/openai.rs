@@ -1,45 +1,369 @@
-use super::{build_user_message, Provider, SYSTEM_PROMPT};
-use crate::refiner::RefinerResponse;
+use super::stream::{for_each_line, read_body, sse_data, RefinedPromptExtractor, TokenSink};
+use super::transport::Transport;
+use super::{
+    after_rejection, structured_output_rejected, ChatMessage, GenerationParams, ModelInfo,
+    Provider, ProviderError,
+};
+use crate::refiner::{self, Completion, RefinerResponse, Usage};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub struct OpenAIProvider {
     client: Client,
     endpoint: String,
     model: String,
-    api_key: String,
+    api_key: Option<String>,
+    name: String,
+    api_key_env: &'static str,
+    json_mode: bool,
+    query: Vec<(String, String)>,
+    transport: Transport,
+    /// Send `response_format: json_schema`; cleared if the server rejects it
+    structured: AtomicBool,
+    /// Ask for token usage at the end of a stream
+    stream_usage: bool,
+    params: GenerationParams,
+    /// Send the token limit as `max_completion_tokens` rather than the
+    /// older `max_tokens` that compatible servers understand
+    max_completion_tokens: bool,
+    /// Azure OpenAI deployment; routes requests through
+    /// `/openai/deployments/{deployment}` with an `api-key` header
+    deployment: Option<String>,
 }
 
 impl OpenAIProvider {
-    pub fn new(endpoint: String, model: String, api_key: String) -> Self {
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(300))
-            .build()
-            .expect("Failed to create HTTP client");
-
+    pub fn new(client: Client, endpoint: String, model: String, api_key: String) -> Self {
         Self {
             client,
             endpoint,
             model,
+            api_key: Some(api_key),
+            name: "OpenAI".to_string(),
+            api_key_env: "OPENAI_API_KEY",
+            json_mode: true,
+            query: Vec::new(),
+            transport: Transport::default(),
+            structured: AtomicBool::new(true),
+            stream_usage: true,
+            params: GenerationParams::default(),
+            max_completion_tokens: true,
+            deployment: None,
+        }
+    }
+
+    /// How HTTP requests are sent (retries, record/replay)
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Create a provider for a server that speaks the OpenAI chat completions
+    /// API (LM Studio, vLLM, llama.cpp server, gateways). Authentication is
+    /// optional, and JSON mode and structured output are off unless enabled.
+    pub fn compatible(
+        client: Client,
+        endpoint: String,
+        model: String,
+        api_key: Option<String>,
+        name: String,
+    ) -> Self {
+        Self {
             api_key,
+            name,
+            api_key_env: "OPENAI_COMPATIBLE_API_KEY",
+            json_mode: false,
+            // Not every server understands stream_options
+            stream_usage: false,
+            max_completion_tokens: false,
+            ..Self::new(client, endpoint, model, String::new()).with_structured_output(false)
+        }
+    }
+
+    /// Create a provider for an Azure OpenAI deployment. `endpoint` is the
+    /// resource URL, e.g. `https://my-resource.openai.azure.com`.
+    pub fn azure(
+        client: Client,
+        endpoint: String,
+        deployment: String,
+        api_version: String,
+        api_key: String,
+    ) -> Self {
+        Self {
+            name: "Azure OpenAI".to_string(),
+            api_key_env: "AZURE_OPENAI_API_KEY",
+            query: vec![("api-version".to_string(), api_version)],
+            // Older API versions reject these
+            stream_usage: false,
+            max_completion_tokens: false,
+            deployment: Some(deployment.clone()),
+            ..Self::new(client, endpoint, deployment, api_key)
+        }
+    }
+
+    /// Enable or disable `response_format: json_object`
+    pub fn with_json_mode(mut self, json_mode: bool) -> Self {
+        self.json_mode = json_mode;
+        self
+    }
+
+    /// Enable or disable `response_format: json_schema` with the
+    /// `RefinerResponse` schema in strict mode. Takes precedence over JSON mode.
+    pub fn with_structured_output(self, enabled: bool) -> Self {
+        self.structured.store(enabled, Ordering::Relaxed);
+        self
+    }
+
+    /// Sampling and length settings; temperature defaults to 0.7
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Extra query parameters appended to every request
+    pub fn with_query(mut self, query: Vec<(String, String)>) -> Self {
+        self.query.extend(query);
+        self
+    }
+
+    fn chat_url(&self) -> String {
+        match &self.deployment {
+            Some(deployment) => format!(
+                "{}/openai/deployments/{}/chat/completions",
+                self.endpoint, deployment
+            ),
+            None => format!("{}/chat/completions", self.endpoint),
         }
     }
+
+    /// Attach the API key: `api-key` for Azure, a bearer token otherwise
+    fn authorize(&self, builder: RequestBuilder) -> RequestBuilder {
+        match (&self.api_key, &self.deployment) {
+            (Some(key), Some(_)) => builder.header("api-key", key),
+            (Some(key), None) => builder.header("Authorization", format!("Bearer {}", key)),
+            (None, _) => builder,
+        }
+    }
+
+    fn build_request(&self, messages: &[ChatMessage], stream: bool) -> OpenAIRequest {
+        let params = &self.params;
+
+        OpenAIRequest {
+            model: self.model.clone(),
+            messages: messages
+                .iter()
+                .map(|message| OpenAIMessage {
+                    role: message.role.to_string(),
+                    content: message.content.clone(),
+                })
+                .collect(),
+            response_format: if self.structured.load(Ordering::Relaxed) {
+                Some(ResponseFormat::JsonSchema {
+                    json_schema: JsonSchemaFormat {
+                        name: refiner::RESPONSE_SCHEMA_NAME.to_string(),
+                        strict: true,
+                        schema: refiner::response_schema(),
+                    },
+                })
+            } else {
+                self.json_mode.then_some(ResponseFormat::JsonObject)
+            },
+            temperature: params.temperature.unwrap_or(0.7),
+            top_p: params.top_p,
+            max_tokens: params.max_tokens.filter(|_| !self.max_completion_tokens),
+            max_completion_tokens: params.max_tokens.filter(|_| self.max_completion_tokens),
+            seed: params.seed,
+            stop: params.stop.clone(),
+            stream_options: (stream && self.stream_usage).then_some(StreamOptions {
+                include_usage: true,
+            }),
+            stream,
+        }
+    }
+
+    /// Turn a transport failure into a user-facing error
+    fn request_error(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_connect() => {
+                anyhow::Error::new(ProviderError::Connection(format!(
+                    "Could not connect to {} API at {}",
+                    self.name, self.endpoint
+                )))
+            }
+            Some(err) if err.is_timeout() => anyhow::anyhow!("Request timed out"),
+            Some(err) => anyhow::anyhow!("HTTP request failed: {}", err),
+            None => e,
+        }
+    }
+
+    /// The error for a non-success response
+    fn status_error(&self, status: reqwest::StatusCode, body: &str) -> anyhow::Error {
+        if status.as_u16() == 401 {
+            ProviderError::Auth(format!(
+                "Authentication failed. Check your {} environment variable.",
+                self.api_key_env
+            ))
+            .into()
+        } else if status.as_u16() == 429 {
+            ProviderError::RateLimited(format!(
+                "Rate limited by {}. Please wait and try again.",
+                self.name
+            ))
+            .into()
+        } else {
+            anyhow::anyhow!("{} returned error {}: {}", self.name, status, body)
+        }
+    }
+
+    async fn send(&self, request: &mut OpenAIRequest) -> Result<Response> {
+        let mut rejection = None;
+        loop {
+            let payload: &OpenAIRequest = request;
+            let response = self
+                .transport
+                .send(|| {
+                    let builder = self
+                        .client
+                        .post(self.chat_url())
+                        .query(&self.query)
+                        .header("Content-Type", "application/json");
+                    self.authorize(builder).json(payload)
+                })
+                .await
+                .map_err(|e| after_rejection(self.request_error(e), rejection.take()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+
+                if structured_output_rejected(status, &body) && request.drop_schema(self.json_mode)
+                {
+                    tracing::debug!(
+                        "{} rejected the response schema ({}), retrying without it",
+                        self.name,
+                        status
+                    );
+                    rejection = Some(format!("{} returned error {}: {}", self.name, status, body));
+                    continue;
+                }
+
+                return Err(after_rejection(self.status_error(status, &body), rejection));
+            }
+
+            if rejection.is_some() {
+                self.structured.store(false, Ordering::Relaxed);
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Send a non-streaming request and return the model's reply text
+    async fn complete(&self, request: &mut OpenAIRequest) -> Result<Completion> {
+        let response = self.send(request).await?;
+
+        let openai_response: OpenAIResponse =
+            serde_json::from_slice(&read_body(response).await?)
+                .with_context(|| format!("Failed to parse {} response", self.name))?;
+
+        let choice = openai_response
+            .choices
+            .into_iter()
+            .next()
+            .with_context(|| format!("No response from {}", self.name))?;
+
+        Ok(Completion {
+            content: choice.message.content,
+            usage: openai_response.usage.map(Usage::from),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModelList {
+    data: Vec<OpenAIModel>,
+}
+
+/// An entry from `/models`. Context length fields are server extensions
+/// (vLLM, LM Studio, gateways); OpenAI itself only sends the id.
+#[derive(Debug, Deserialize)]
+struct OpenAIModel {
+    id: String,
+    #[serde(alias = "context_length", alias = "context_window")]
+    max_model_len: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 struct OpenAIRequest {
     model: String,
     messages: Vec<OpenAIMessage>,
-    response_format: ResponseFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
     temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
 }
 
 #[derive(Debug, Serialize)]
-struct ResponseFormat {
-    #[serde(rename = "type")]
-    format_type: String,
+struct StreamOptions {
+    include_usage: bool,
+}
+
+impl OpenAIRequest {
+    /// Fall back from a response schema to JSON mode (or no response format).
+    /// Returns `false` if no schema was being sent.
+    fn drop_schema(&mut self, json_mode: bool) -> bool {
+        if !matches!(
+            self.response_format,
+            Some(ResponseFormat::JsonSchema { .. })
+        ) {
+            return false;
+        }
+        self.response_format = json_mode.then_some(ResponseFormat::JsonObject);
+        true
+    }
+
+    /// Append the rejected reply and a corrective instruction as a follow-up turn
+    fn push_repair(&mut self, previous: String, correction: String) {
+        self.stream = false;
+        self.stream_options = None;
+        self.messages.push(OpenAIMessage {
+            role: "assistant".to_string(),
+            content: previous,
+        });
+        self.messages.push(OpenAIMessage {
+            role: "user".to_string(),
+            content: correction,
+        });
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseFormat {
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Serialize)]
+struct JsonSchemaFormat {
+    name: String,
+    strict: bool,
+    schema: Value,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,6 +375,22 @@ struct OpenAIMessage {
 #[derive(Debug, Deserialize)]
 struct OpenAIResponse {
     choices: Vec<OpenAIChoice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl From<OpenAIUsage> for Usage {
+    fn from(usage: OpenAIUsage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -58,97 +398,152 @@ struct OpenAIChoice {
     message: OpenAIMessage,
 }
 
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamChunk {
+    #[serde(default)]
+    choices: Vec<OpenAIStreamChoice>,
+    error: Option<OpenAIStreamError>,
+    /// Sent in a final chunk with no choices when `include_usage` is set
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamChoice {
+    delta: OpenAIDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIDelta {
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamError {
+    message: String,
+}
+
 #[async_trait]
 impl Provider for OpenAIProvider {
-    fn name(&self) -> &'static str {
-        "OpenAI"
+    fn name(&self) -> &str {
+        &self.name
     }
 
     fn model(&self) -> &str {
         &self.model
     }
 
-    async fn refine(
+    async fn refine(&self, messages: &[ChatMessage]) -> Result<RefinerResponse> {
+        let mut request = self.build_request(messages, false);
+        let completion = self.complete(&mut request).await?;
+
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn chat(&self, messages: &[ChatMessage]) -> Result<Completion> {
+        let mut request = self.build_request(messages, false);
+        request.drop_schema(self.json_mode);
+        self.complete(&mut request).await
+    }
+
+    async fn refine_stream(
         &self,
-        prompt: &str,
-        context: Option<&str>,
-        clarification: Option<&str>,
+        messages: &[ChatMessage],
+        on_token: TokenSink<'_>,
     ) -> Result<RefinerResponse> {
-        let user_message = build_user_message(prompt, context, clarification);
+        let mut request = self.build_request(messages, true);
+        let response = self.send(&mut request).await?;
 
-        let request = OpenAIRequest {
-            model: self.model.clone(),
-            messages: vec![
-                OpenAIMessage {
-                    role: "system".to_string(),
-                    content: SYSTEM_PROMPT.to_string(),
-                },
-                OpenAIMessage {
-                    role: "user".to_string(),
-                    content: user_message,
-                },
-            ],
-            response_format: ResponseFormat {
-                format_type: "json_object".to_string(),
-            },
-            temperature: 0.7,
+        let mut extractor = RefinedPromptExtractor::new();
+        let mut usage = None;
+        for_each_line(response, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(true);
+            };
+            if data == "[DONE]" {
+                return Ok(false);
+            }
+            let chunk: OpenAIStreamChunk = serde_json::from_str(data)
+                .with_context(|| format!("Failed to parse {} stream chunk: {}", self.name, data))?;
+            if let Some(error) = chunk.error {
+                anyhow::bail!("{} returned error: {}", self.name, error.message);
+            }
+            if let Some(chunk_usage) = chunk.usage {
+                usage = Some(Usage::from(chunk_usage));
+            }
+            if let Some(content) = chunk.choices.first().and_then(|c| c.delta.content.as_ref()) {
+                let text = extractor.push(content);
+                if !text.is_empty() {
+                    on_token(&text);
+                }
+            }
+            Ok(true)
+        })
+        .await?;
+
+        if extractor.raw().is_empty() {
+            anyhow::bail!("No response from {}", self.name);
+        }
+
+        let completion = Completion {
+            content: extractor.raw().to_string(),
+            usage,
         };
+        refiner::parse_or_repair(completion, |previous, correction| async move {
+            request.push_repair(previous, correction);
+            self.complete(&mut request).await
+        })
+        .await
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        if self.deployment.is_some() {
+            anyhow::bail!(
+                "{} does not support listing deployments; see your resource in the Azure portal",
+                self.name
+            );
+        }
 
         let response = self
-            .client
-            .post(format!("{}/chat/completions", self.endpoint))
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
+            .transport
+            .send(|| {
+                let builder = self
+                    .client
+                    .get(format!("{}/models", self.endpoint))
+                    .query(&self.query);
+                self.authorize(builder)
+            })
             .await
-            .map_err(|e| {
-                if e.is_connect() {
-                    anyhow::anyhow!("Could not connect to OpenAI API at {}", self.endpoint)
-                } else if e.is_timeout() {
-                    anyhow::anyhow!("Request timed out")
-                } else {
-                    anyhow::anyhow!("HTTP request failed: {}", e)
-                }
-            })?;
+            .map_err(|e| self.request_error(e))?;
 
         if !response.status().is_success() {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
 
             if status.as_u16() == 401 {
-                anyhow::bail!(
-                    "Authentication failed. Check your OPENAI_API_KEY environment variable."
-                );
-            } else if status.as_u16() == 429 {
-                anyhow::bail!("Rate limited by OpenAI. Please wait and try again.");
+                return Err(ProviderError::Auth(format!(
+                    "Authentication failed. Check your {} environment variable.",
+                    self.api_key_env
+                ))
+                .into());
             }
 
-            anyhow::bail!("OpenAI returned error {}: {}", status, body);
+            anyhow::bail!("{} returned error {}: {}", self.name, status, body);
         }
 
-        let openai_response: OpenAIResponse = response
-            .json()
-            .await
-            .context("Failed to parse OpenAI response")?;
-
-        let choice = openai_response
-            .choices
-            .first()
-            .context("No response from OpenAI")?;
-
-        let refiner_response: RefinerResponse = serde_json::from_str(&choice.message.content)
-            .with_context(|| {
-                format!(
-                    "Failed to parse refiner response as JSON. Raw content:\n{}",
-                    choice.message.content
-                )
-            })?;
-
-        if refiner_response.refined_prompt.is_empty() {
-            anyhow::bail!("Refiner returned an empty refined_prompt");
-        }
+        let list: OpenAIModelList = serde_json::from_slice(&read_body(response).await?)
+            .with_context(|| format!("Failed to parse {} model list", self.name))?;
 
-        Ok(refiner_response)
+        Ok(list
+            .data
+            .into_iter()
+            .map(|model| ModelInfo {
+                context_length: model.max_model_len,
+                ..ModelInfo::new(model.id)
+            })
+            .collect())
     }
 }
@@ -0,0 +1,401 @@
+//! Sampling and length settings, and how each backend spells them
+
+use super::ProviderKind;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Generation settings from a `[providers.<name>]` table or the command line.
+/// Unset fields leave the backend's default in place.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    /// Sampling temperature, 0.0 to 2.0 (0.0 to 1.0 for Anthropic)
+    pub temperature: Option<f32>,
+    /// Nucleus sampling probability mass, 0.0 to 1.0
+    pub top_p: Option<f32>,
+    /// Upper bound on generated tokens (`n_predict` for llama.cpp)
+    #[serde(alias = "n_predict")]
+    pub max_tokens: Option<u32>,
+    /// Seed for reproducible sampling (not supported by Anthropic or Bedrock)
+    pub seed: Option<i64>,
+    /// Sequences that end generation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// Context window size in tokens (Ollama only)
+    pub num_ctx: Option<u32>,
+    /// How long the model stays loaded after a request, e.g. `5m` or `-1`
+    /// (Ollama only)
+    pub keep_alive: Option<String>,
+}
+
+/// Fields accepted by `config set providers.<name>.<field>`
+pub const PARAM_FIELDS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "max_tokens",
+    "seed",
+    "stop",
+    "num_ctx",
+    "keep_alive",
+];
+
+/// OpenAI accepts at most this many stop sequences
+const OPENAI_MAX_STOP: usize = 4;
+
+/// Gemini accepts at most this many stop sequences
+const GEMINI_MAX_STOP: usize = 5;
+
+impl GenerationParams {
+    /// Fill unset fields from `base`
+    pub fn or(self, base: &GenerationParams) -> Self {
+        Self {
+            temperature: self.temperature.or(base.temperature),
+            top_p: self.top_p.or(base.top_p),
+            max_tokens: self.max_tokens.or(base.max_tokens),
+            seed: self.seed.or(base.seed),
+            stop: if self.stop.is_empty() {
+                base.stop.clone()
+            } else {
+                self.stop
+            },
+            num_ctx: self.num_ctx.or(base.num_ctx),
+            keep_alive: self.keep_alive.or_else(|| base.keep_alive.clone()),
+        }
+    }
+
+    /// Check that every set value is in range for `kind`
+    pub fn validate(&self, kind: ProviderKind) -> Result<()> {
+        let max_temperature = match kind {
+            ProviderKind::Anthropic => 1.0,
+            _ => 2.0,
+        };
+        if let Some(t) = self.temperature {
+            if !(0.0..=max_temperature).contains(&t) {
+                anyhow::bail!(
+                    "temperature must be between 0.0 and {:.1} for {}, got {}",
+                    max_temperature,
+                    kind,
+                    t
+                );
+            }
+        }
+        if let Some(p) = self.top_p {
+            if !(0.0..=1.0).contains(&p) {
+                anyhow::bail!("top_p must be between 0.0 and 1.0, got {}", p);
+            }
+        }
+        if self.max_tokens == Some(0) {
+            anyhow::bail!("max_tokens must be at least 1");
+        }
+        if self.num_ctx == Some(0) {
+            anyhow::bail!("num_ctx must be at least 1");
+        }
+        if self.stop.iter().any(String::is_empty) {
+            anyhow::bail!("stop sequences must not be empty");
+        }
+        let max_stop = match kind {
+            ProviderKind::OpenAI | ProviderKind::AzureOpenAI => Some(OPENAI_MAX_STOP),
+            ProviderKind::Gemini => Some(GEMINI_MAX_STOP),
+            _ => None,
+        };
+        if let Some(max_stop) = max_stop.filter(|max| self.stop.len() > *max) {
+            anyhow::bail!(
+                "{} accepts at most {} stop sequences, got {}",
+                kind,
+                max_stop,
+                self.stop.len()
+            );
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            if !valid_keep_alive(keep_alive) {
+                anyhow::bail!(
+                    "keep_alive must be a number of seconds or a duration like 30s, 5m or 1h, got '{}'",
+                    keep_alive
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of set fields that `kind` has no equivalent for
+    pub fn unsupported(&self, kind: ProviderKind) -> Vec<&'static str> {
+        let ollama = matches!(kind, ProviderKind::Ollama | ProviderKind::OllamaCloud);
+        let mut fields = Vec::new();
+        if kind == ProviderKind::Mock {
+            return fields;
+        }
+        if self.seed.is_some() && matches!(kind, ProviderKind::Anthropic | ProviderKind::Bedrock) {
+            fields.push("seed");
+        }
+        if self.num_ctx.is_some() && !ollama {
+            fields.push("num_ctx");
+        }
+        if self.keep_alive.is_some() && !ollama {
+            fields.push("keep_alive");
+        }
+        fields
+    }
+
+    /// The Ollama `options` object, if any option is set
+    pub(super) fn ollama_options(&self) -> Option<OllamaOptions> {
+        let options = OllamaOptions {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            num_predict: self.max_tokens,
+            seed: self.seed,
+            stop: self.stop.clone(),
+            num_ctx: self.num_ctx,
+        };
+        (options != OllamaOptions::default()).then_some(options)
+    }
+
+    /// `keep_alive` as Ollama expects it: a bare number means seconds
+    pub(super) fn ollama_keep_alive(&self) -> Option<Value> {
+        let keep_alive = self.keep_alive.as_deref()?;
+        Some(match keep_alive.parse::<i64>() {
+            Ok(seconds) => Value::from(seconds),
+            Err(_) => Value::from(keep_alive),
+        })
+    }
+}
+
+impl std::fmt::Display for GenerationParams {
+    /// Set fields as `name=value`, comma-separated
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(t) = self.temperature {
+            fields.push(format!("temperature={}", t));
+        }
+        if let Some(p) = self.top_p {
+            fields.push(format!("top_p={}", p));
+        }
+        if let Some(n) = self.max_tokens {
+            fields.push(format!("max_tokens={}", n));
+        }
+        if let Some(seed) = self.seed {
+            fields.push(format!("seed={}", seed));
+        }
+        if !self.stop.is_empty() {
+            fields.push(format!("stop={:?}", self.stop));
+        }
+        if let Some(n) = self.num_ctx {
+            fields.push(format!("num_ctx={}", n));
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            fields.push(format!("keep_alive={}", keep_alive));
+        }
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+/// Ollama's per-request model options
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(super) struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+}
+
+/// Whether `value` is an integer or a Go-style duration such as `1h30m`
+fn valid_keep_alive(value: &str) -> bool {
+    if value.parse::<i64>().is_ok() {
+        return true;
+    }
+    let mut rest = value.strip_prefix('-').unwrap_or(value);
+    if rest.is_empty() {
+        return false;
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        if digits == 0 || rest[..digits].parse::<f64>().is_err() {
+            return false;
+        }
+        rest = &rest[digits..];
+        let unit = ["ms", "h", "m", "s"]
+            .into_iter()
+            .find(|unit| rest.starts_with(unit));
+        match unit {
+            Some(unit) => rest = &rest[unit.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_keep_alive_table() {
+        let cases = [
+            ("0", true),
+            ("300", true),
+            ("-1", true),
+            ("30s", true),
+            ("5m", true),
+            ("1h30m", true),
+            ("1.5h", true),
+            ("250ms", true),
+            ("-1m", true),
+            ("", false),
+            ("-", false),
+            ("5", true),
+            ("m", false),
+            ("5x", false),
+            ("5 m", false),
+            ("1h30", false),
+            ("1..5h", false),
+            ("forever", false),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(valid_keep_alive(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn validate_table() {
+        let stop = |n: usize| (0..n).map(|i| format!("STOP{}", i)).collect::<Vec<_>>();
+        let cases = [
+            (ProviderKind::Ollama, GenerationParams::default(), None),
+            (
+                ProviderKind::OpenAI,
+                GenerationParams {
+                    temperature: Some(2.0),
+                    top_p: Some(1.0),
+                    max_tokens: Some(1),
+                    stop: stop(4),
+                    ..GenerationParams::default()
+                },
+                None,
+            ),
+            (
+                ProviderKind::Anthropic,
+                GenerationParams {
+                    temperature: Some(1.5),
+                    ..GenerationParams::default()
+                },
+                Some("between 0.0 and 1.0 for anthropic"),
+            ),
+            (
+                ProviderKind::Ollama,
+                GenerationParams {
+                    temperature: Some(-0.1),
+                    ..GenerationParams::default()
+                },
+                Some("between 0.0 and 2.0"),
+            ),
+            (
+                ProviderKind::Gemini,
+                GenerationParams {
+                    top_p: Some(1.2),
+                    ..GenerationParams::default()
+                },
+                Some("top_p must be between"),
+            ),
+            (
+                ProviderKind::Ollama,
+                GenerationParams {
+                    max_tokens: Some(0),
+                    ..GenerationParams::default()
+                },
+                Some("max_tokens must be at least 1"),
+            ),
+            (
+                ProviderKind::Ollama,
+                GenerationParams {
+                    num_ctx: Some(0),
+                    ..GenerationParams::default()
+                },
+                Some("num_ctx must be at least 1"),
+            ),
+            (
+                ProviderKind::Ollama,
+                GenerationParams {
+                    stop: vec![String::new()],
+                    ..GenerationParams::default()
+                },
+                Some("must not be empty"),
+            ),
+            (
+                ProviderKind::OpenAI,
+                GenerationParams {
+                    stop: stop(5),
+                    ..GenerationParams::default()
+                },
+                Some("at most 4 stop sequences, got 5"),
+            ),
+            (
+                ProviderKind::Gemini,
+                GenerationParams {
+                    stop: stop(5),
+                    ..GenerationParams::default()
+                },
+                None,
+            ),
+            (
+                ProviderKind::Gemini,
+                GenerationParams {
+                    stop: stop(6),
+                    ..GenerationParams::default()
+                },
+                Some("at most 5 stop sequences"),
+            ),
+            (
+                ProviderKind::Ollama,
+                GenerationParams {
+                    stop: stop(10),
+                    keep_alive: Some("10m".to_string()),
+                    ..GenerationParams::default()
+                },
+                None,
+            ),
+            (
+                ProviderKind::Ollama,
+                GenerationParams {
+                    keep_alive: Some("soon".to_string()),
+                    ..GenerationParams::default()
+                },
+                Some("got 'soon'"),
+            ),
+        ];
+        for (kind, params, expected) in cases {
+            let result = params.validate(kind);
+            match expected {
+                None => assert!(result.is_ok(), "{} {}: {:?}", kind, params, result),
+                Some(message) => {
+                    let error = result.unwrap_err().to_string();
+                    assert!(error.contains(message), "{} {}: {}", kind, params, error);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ollama_keep_alive_sends_bare_numbers_as_seconds() {
+        let cases = [
+            ("300", Value::from(300)),
+            ("-1", Value::from(-1)),
+            ("5m", Value::from("5m")),
+        ];
+        for (keep_alive, expected) in cases {
+            let params = GenerationParams {
+                keep_alive: Some(keep_alive.to_string()),
+                ..GenerationParams::default()
+            };
+            assert_eq!(params.ollama_keep_alive(), Some(expected), "{}", keep_alive);
+        }
+        assert_eq!(GenerationParams::default().ollama_keep_alive(), None);
+    }
+}
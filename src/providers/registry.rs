@@ -0,0 +1,316 @@
+//! Provider kinds and construction from resolved configuration
+
+use super::{
+    build_client, AnthropicProvider, AwsCredentials, BedrockProvider, ChatTemplate, GeminiProvider,
+    GrammarMode, LlamaCppProvider, MockProvider, OllamaCloudProvider, OllamaLocalProvider,
+    OpenAIProvider, Provider, ProviderError,
+};
+use crate::config::ResolvedProvider;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// The backend implementation a configured provider uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProviderKind {
+    /// Local Ollama instance
+    #[serde(rename = "ollama", alias = "ollama-local")]
+    Ollama,
+    /// Ollama Cloud API
+    #[serde(rename = "ollama-cloud")]
+    OllamaCloud,
+    /// OpenAI API
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// Anthropic API
+    #[serde(rename = "anthropic")]
+    Anthropic,
+    /// Amazon Bedrock Converse API
+    #[serde(rename = "bedrock")]
+    Bedrock,
+    /// Google Gemini API
+    #[serde(rename = "gemini")]
+    Gemini,
+    /// llama.cpp server's native `/completion` API
+    #[serde(rename = "llamacpp", alias = "llama.cpp")]
+    LlamaCpp,
+    /// Any OpenAI-compatible server (LM Studio, vLLM, llama.cpp, gateways)
+    #[serde(rename = "openai-compatible")]
+    OpenAICompatible,
+    /// Azure OpenAI deployment
+    #[serde(rename = "azure-openai")]
+    AzureOpenAI,
+    /// Scripted offline responses for testing
+    #[serde(rename = "mock")]
+    Mock,
+}
+
+impl ProviderKind {
+    /// All known kinds
+    pub const ALL: &'static [ProviderKind] = &[
+        ProviderKind::Ollama,
+        ProviderKind::OllamaCloud,
+        ProviderKind::OpenAI,
+        ProviderKind::Anthropic,
+        ProviderKind::Bedrock,
+        ProviderKind::Gemini,
+        ProviderKind::LlamaCpp,
+        ProviderKind::OpenAICompatible,
+        ProviderKind::AzureOpenAI,
+        ProviderKind::Mock,
+    ];
+
+    /// Parse a kind from its config name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ollama" | "ollama-local" => Some(Self::Ollama),
+            "ollama-cloud" => Some(Self::OllamaCloud),
+            "openai" => Some(Self::OpenAI),
+            "anthropic" => Some(Self::Anthropic),
+            "bedrock" => Some(Self::Bedrock),
+            "gemini" => Some(Self::Gemini),
+            "llamacpp" | "llama.cpp" => Some(Self::LlamaCpp),
+            "openai-compatible" => Some(Self::OpenAICompatible),
+            "azure-openai" => Some(Self::AzureOpenAI),
+            "mock" => Some(Self::Mock),
+            _ => None,
+        }
+    }
+
+    /// Endpoint used when the config does not set one. `{resource}` and
+    /// `{region}` are replaced with the provider's `resource` and `region`
+    /// settings.
+    pub fn default_endpoint(&self) -> &'static str {
+        match self {
+            Self::Ollama => "http://localhost:11434",
+            Self::OllamaCloud => "https://api.ollama.com",
+            Self::OpenAI => "https://api.openai.com/v1",
+            Self::Anthropic => "https://api.anthropic.com/v1",
+            Self::Bedrock => "https://bedrock-runtime.{region}.amazonaws.com",
+            Self::Gemini => "https://generativelanguage.googleapis.com/v1beta",
+            Self::LlamaCpp => "http://localhost:8080",
+            Self::OpenAICompatible => "http://localhost:8000/v1",
+            Self::AzureOpenAI => "https://{resource}.openai.azure.com",
+            Self::Mock => "mock://",
+        }
+    }
+
+    /// Model used when neither the CLI nor the provider config sets one.
+    /// `None` means fall back to `default.model`; llama.cpp serves whatever
+    /// model it was started with and only records the name.
+    pub fn default_model(&self) -> Option<&'static str> {
+        match self {
+            Self::OpenAI => Some("gpt-4o"),
+            Self::Anthropic => Some("claude-3-5-sonnet"),
+            Self::Bedrock => Some("anthropic.claude-3-5-sonnet-20240620-v1:0"),
+            Self::Gemini => Some("gemini-2.5-flash"),
+            Self::Mock => Some("mock"),
+            Self::Ollama
+            | Self::OllamaCloud
+            | Self::LlamaCpp
+            | Self::OpenAICompatible
+            | Self::AzureOpenAI => None,
+        }
+    }
+
+    /// Environment variable the API key is read from by default. Bedrock
+    /// signs requests with AWS credentials instead.
+    pub fn default_api_key_env(&self) -> Option<&'static str> {
+        match self {
+            Self::Ollama | Self::Bedrock | Self::Mock => None,
+            Self::OllamaCloud => Some("OLLAMA_API_KEY"),
+            Self::OpenAI => Some("OPENAI_API_KEY"),
+            Self::Anthropic => Some("ANTHROPIC_API_KEY"),
+            Self::Gemini => Some("GEMINI_API_KEY"),
+            Self::LlamaCpp => Some("LLAMA_API_KEY"),
+            Self::OpenAICompatible => Some("OPENAI_COMPATIBLE_API_KEY"),
+            Self::AzureOpenAI => Some("AZURE_OPENAI_API_KEY"),
+        }
+    }
+
+    /// Whether requests fail without an API key
+    pub fn requires_api_key(&self) -> bool {
+        matches!(
+            self,
+            Self::OllamaCloud | Self::OpenAI | Self::Anthropic | Self::Gemini | Self::AzureOpenAI
+        )
+    }
+}
+
+impl std::fmt::Display for ProviderKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ollama => write!(f, "ollama"),
+            Self::OllamaCloud => write!(f, "ollama-cloud"),
+            Self::OpenAI => write!(f, "openai"),
+            Self::Anthropic => write!(f, "anthropic"),
+            Self::Bedrock => write!(f, "bedrock"),
+            Self::Gemini => write!(f, "gemini"),
+            Self::LlamaCpp => write!(f, "llamacpp"),
+            Self::OpenAICompatible => write!(f, "openai-compatible"),
+            Self::AzureOpenAI => write!(f, "azure-openai"),
+            Self::Mock => write!(f, "mock"),
+        }
+    }
+}
+
+/// Azure OpenAI `api-version` used when the config does not set one
+const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
+
+/// Construct a provider from a resolved registry entry
+pub fn build_provider(resolved: &ResolvedProvider) -> Result<Box<dyn Provider>> {
+    let endpoint = resolved.endpoint.clone();
+    let model = resolved.model.clone();
+    let transport = resolved.transport.clone();
+    let client = || {
+        build_client(&resolved.http)
+            .with_context(|| format!("Invalid HTTP settings for '{}'", resolved.name))
+    };
+    let params = resolved.params.clone();
+    params
+        .validate(resolved.kind)
+        .with_context(|| format!("Invalid generation settings for '{}'", resolved.name))?;
+    for field in params.unsupported(resolved.kind) {
+        tracing::debug!("{} does not support {}; ignoring it", resolved.kind, field);
+    }
+    let structured = resolved
+        .settings
+        .structured_output
+        .unwrap_or(resolved.kind != ProviderKind::OpenAICompatible);
+
+    let provider: Box<dyn Provider> = match resolved.kind {
+        ProviderKind::Ollama => Box::new(
+            OllamaLocalProvider::new(client()?, endpoint, model)
+                .with_structured_output(structured)
+                .with_params(params)
+                .with_transport(transport),
+        ),
+        ProviderKind::OllamaCloud => Box::new(
+            OllamaCloudProvider::new(client()?, endpoint, model, required_api_key(resolved)?)
+                .with_structured_output(structured)
+                .with_params(params)
+                .with_transport(transport),
+        ),
+        ProviderKind::OpenAI => Box::new(
+            OpenAIProvider::new(client()?, endpoint, model, required_api_key(resolved)?)
+                .with_structured_output(structured)
+                .with_params(params)
+                .with_transport(transport),
+        ),
+        ProviderKind::Anthropic => Box::new(
+            AnthropicProvider::new(client()?, endpoint, model, required_api_key(resolved)?)
+                .with_structured_output(structured)
+                .with_params(params)
+                .with_transport(transport),
+        ),
+        ProviderKind::Bedrock => {
+            let region = resolved.settings.aws_region().with_context(|| {
+                format!(
+                    "Provider '{}' needs an AWS region. Set `region` in [providers.{}] or AWS_REGION",
+                    resolved.name, resolved.name
+                )
+            })?;
+            let credentials = AwsCredentials::load(resolved.settings.profile.as_deref())?;
+            Box::new(
+                BedrockProvider::new(client()?, endpoint, model, region, credentials)
+                    .with_structured_output(structured)
+                    .with_params(params)
+                    .with_transport(transport),
+            )
+        }
+        ProviderKind::Gemini => Box::new(
+            GeminiProvider::new(client()?, endpoint, model, required_api_key(resolved)?)
+                .with_structured_output(structured)
+                .with_params(params)
+                .with_transport(transport),
+        ),
+        ProviderKind::LlamaCpp => {
+            let settings = &resolved.settings;
+            Box::new(
+                LlamaCppProvider::new(client()?, endpoint, model, resolved.api_key.clone())
+                    .with_structured_output(structured)
+                    .with_grammar(settings.grammar.unwrap_or(GrammarMode::Gbnf))
+                    .with_chat_template(settings.chat_template.unwrap_or(ChatTemplate::Server))
+                    .with_params(params)
+                    .with_cache_prompt(settings.cache_prompt.unwrap_or(true))
+                    .with_slot(settings.slot_id)
+                    .with_transport(transport),
+            )
+        }
+        ProviderKind::OpenAICompatible => {
+            let settings = &resolved.settings;
+            let name = settings
+                .name
+                .clone()
+                .unwrap_or_else(|| "OpenAI-compatible".to_string());
+            Box::new(
+                OpenAIProvider::compatible(
+                    client()?,
+                    endpoint,
+                    model,
+                    resolved.api_key.clone(),
+                    name,
+                )
+                .with_json_mode(settings.json_mode.unwrap_or(false))
+                .with_structured_output(structured)
+                .with_params(params)
+                .with_query(
+                    settings
+                        .query
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                )
+                .with_transport(transport),
+            )
+        }
+        ProviderKind::AzureOpenAI => {
+            let settings = &resolved.settings;
+            let api_version = settings
+                .api_version
+                .clone()
+                .unwrap_or_else(|| DEFAULT_AZURE_API_VERSION.to_string());
+            Box::new(
+                OpenAIProvider::azure(
+                    client()?,
+                    endpoint,
+                    model,
+                    api_version,
+                    required_api_key(resolved)?,
+                )
+                .with_json_mode(settings.json_mode.unwrap_or(true))
+                .with_structured_output(structured)
+                .with_params(params)
+                .with_query(
+                    settings
+                        .query
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                )
+                .with_transport(transport),
+            )
+        }
+        ProviderKind::Mock => match &resolved.settings.fixture {
+            Some(path) => Box::new(MockProvider::from_fixture(model, path)?),
+            None => Box::new(MockProvider::new(model)),
+        },
+    };
+
+    Ok(provider)
+}
+
+fn required_api_key(resolved: &ResolvedProvider) -> Result<String> {
+    let source = resolved
+        .api_key_env
+        .as_deref()
+        .map(|var| format!("Set {} environment variable", var))
+        .unwrap_or_else(|| format!("Set api_key in [providers.{}]", resolved.name));
+
+    resolved.api_key.clone().ok_or_else(|| {
+        ProviderError::Auth(format!(
+            "Provider '{}' requires an API key. {} or use --api-key.",
+            resolved.name, source
+        ))
+        .into()
+    })
+}
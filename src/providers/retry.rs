@@ -0,0 +1,406 @@
+//! Retry with exponential backoff for transient provider failures
+
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Anthropic rate limit buckets, each announced with
+/// `anthropic-ratelimit-<bucket>-remaining` and `-reset` headers
+const ANTHROPIC_RATE_LIMITS: &[&str] = &["requests", "tokens", "input-tokens", "output-tokens"];
+
+/// Details about an upcoming retry, passed to the retry observer
+#[derive(Debug, Clone)]
+pub struct RetryNotice {
+    /// The attempt that just failed (1-based)
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// How long we wait before the next attempt
+    pub delay: Duration,
+    /// Why the attempt failed
+    pub reason: String,
+}
+
+impl std::fmt::Display for RetryNotice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}; retrying in {:.1}s (attempt {}/{})",
+            self.reason,
+            self.delay.as_secs_f32(),
+            self.attempt + 1,
+            self.max_attempts
+        )
+    }
+}
+
+/// Callback notified before each retry
+pub type RetryObserver = Arc<dyn Fn(&RetryNotice) + Send + Sync>;
+
+/// How often and how long to retry a provider request
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one; 1 disables retries
+    pub max_attempts: u32,
+    /// Give up once this much time has passed since the first attempt
+    pub deadline: Option<Duration>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub observer: Option<RetryObserver>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            deadline: Some(Duration::from_secs(120)),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            observer: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("deadline", &self.deadline)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Attach a callback notified before each retry
+    pub fn with_observer(mut self, observer: RetryObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// How long to wait after the given failed attempt: the server's requested
+    /// delay if longer than our own backoff. Without a deadline to bound it,
+    /// the server's delay is capped at `max_backoff`.
+    pub(super) fn delay(&self, attempt: u32, server_delay: Option<Duration>) -> Duration {
+        let backoff = self.backoff(attempt);
+        match server_delay {
+            Some(delay) if self.deadline.is_none() => delay.min(self.max_backoff).max(backoff),
+            Some(delay) => delay.max(backoff),
+            None => backoff,
+        }
+    }
+
+    /// Exponential backoff with jitter for the given failed attempt
+    pub(super) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        // Equal jitter: half fixed, half random
+        let half = exp / 2;
+        let jitter_ms = half.as_millis() as u64;
+        let random = if jitter_ms == 0 {
+            0
+        } else {
+            random_u64() % (jitter_ms + 1)
+        };
+        half + Duration::from_millis(random)
+    }
+}
+
+pub(super) fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+/// Failures to reach the server or hear back from it in time. Other errors,
+/// such as a malformed request, would fail the same way again.
+pub(super) fn is_retryable_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout()
+}
+
+pub(super) fn describe_error(e: &reqwest::Error) -> String {
+    if e.is_connect() {
+        "Connection failed".to_string()
+    } else if e.is_timeout() {
+        "Request timed out".to_string()
+    } else {
+        "Connection interrupted".to_string()
+    }
+}
+
+/// Server-provided wait time from `retry-after-ms`, `Retry-After` or, on a
+/// 429, the reset time of each exhausted Anthropic rate limit
+pub(super) fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+
+    if let Some(value) = header("retry-after") {
+        let value = value.trim();
+        if let Ok(secs) = value.parse::<f64>() {
+            return Some(Duration::from_secs_f64(secs.max(0.0)));
+        }
+        if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+            return Some(until(date.with_timezone(&Utc)));
+        }
+    }
+
+    if status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    ANTHROPIC_RATE_LIMITS
+        .iter()
+        .filter(|bucket| {
+            header(&format!("anthropic-ratelimit-{}-remaining", bucket))
+                .is_some_and(|v| v.trim() == "0")
+        })
+        .filter_map(|bucket| header(&format!("anthropic-ratelimit-{}-reset", bucket)))
+        .filter_map(|value| DateTime::parse_from_rfc3339(value.trim()).ok())
+        .map(|date| until(date.with_timezone(&Utc)))
+        .max()
+}
+
+fn until(date: DateTime<Utc>) -> Duration {
+    (date - Utc::now()).to_std().unwrap_or(Duration::ZERO)
+}
+
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(String, String)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn header(name: &str, value: impl Into<String>) -> (String, String) {
+        (name.to_string(), value.into())
+    }
+
+    /// The headers Anthropic sends for one rate limit bucket
+    fn bucket(name: &str, remaining: &str, reset_secs: i64) -> [(String, String); 2] {
+        [
+            header(
+                &format!("anthropic-ratelimit-{}-remaining", name),
+                remaining,
+            ),
+            header(
+                &format!("anthropic-ratelimit-{}-reset", name),
+                in_secs(reset_secs).to_rfc3339(),
+            ),
+        ]
+    }
+
+    fn policy(deadline: Option<Duration>) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            deadline,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            observer: None,
+        }
+    }
+
+    fn in_secs(secs: i64) -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::seconds(secs)
+    }
+
+    #[test]
+    fn retry_after_table() {
+        let rate_limited = StatusCode::TOO_MANY_REQUESTS;
+        let unavailable = StatusCode::SERVICE_UNAVAILABLE;
+        // (status, headers, expected range in whole seconds)
+        let cases = vec![
+            (rate_limited, vec![], None),
+            (rate_limited, vec![header("retry-after", "7")], Some((7, 7))),
+            (
+                unavailable,
+                vec![header("retry-after", " 2.5 ")],
+                Some((2, 2)),
+            ),
+            (
+                rate_limited,
+                vec![header("retry-after", "-3")],
+                Some((0, 0)),
+            ),
+            (rate_limited, vec![header("retry-after", "soon")], None),
+            (
+                unavailable,
+                vec![header("retry-after", in_secs(20).to_rfc2822())],
+                Some((18, 20)),
+            ),
+            (
+                rate_limited,
+                vec![header("retry-after-ms", "1500"), header("retry-after", "9")],
+                Some((1, 1)),
+            ),
+            (
+                rate_limited,
+                bucket("tokens", "0", 20).to_vec(),
+                Some((18, 20)),
+            ),
+            // The longest wait among exhausted limits
+            (
+                rate_limited,
+                [
+                    bucket("tokens", "0", 20),
+                    bucket("requests", "0", 40),
+                    bucket("output-tokens", "12", 90),
+                ]
+                .concat(),
+                Some((38, 40)),
+            ),
+            (rate_limited, bucket("requests", "3", 20).to_vec(), None),
+            (unavailable, bucket("tokens", "0", 20).to_vec(), None),
+        ];
+
+        for (status, pairs, expected) in cases {
+            let got = retry_after(status, &headers(&pairs)).map(|d| d.as_secs());
+            match expected {
+                Some((low, high)) => assert!(
+                    got.is_some_and(|secs| (low..=high).contains(&secs)),
+                    "{} {:?}: got {:?}",
+                    status,
+                    pairs,
+                    got
+                ),
+                None => assert_eq!(got, None, "{} {:?}", status, pairs),
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_equal_jitter_up_to_the_cap() {
+        let policy = policy(None);
+        for (attempt, full) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 16), (6, 30), (40, 30)] {
+            let full = Duration::from_secs(full);
+            for _ in 0..20 {
+                let backoff = policy.backoff(attempt);
+                assert!(
+                    backoff >= full / 2 && backoff <= full,
+                    "attempt {}: {:?} outside {:?}..={:?}",
+                    attempt,
+                    backoff,
+                    full / 2,
+                    full
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn delay_table() {
+        let secs = Duration::from_secs;
+        // (deadline, attempt, server delay, expected range)
+        let cases = [
+            (None, 1, None, (Duration::from_millis(500), secs(1))),
+            (None, 1, Some(secs(10)), (secs(10), secs(10))),
+            // Without a deadline the server's delay is capped
+            (None, 1, Some(secs(600)), (secs(30), secs(30))),
+            (Some(secs(120)), 1, Some(secs(600)), (secs(600), secs(600))),
+            // Our own backoff wins when it is longer
+            (Some(secs(120)), 4, Some(secs(1)), (secs(4), secs(8))),
+            (None, 4, Some(Duration::ZERO), (secs(4), secs(8))),
+        ];
+        for (deadline, attempt, server, (low, high)) in cases {
+            let delay = policy(deadline).delay(attempt, server);
+            assert!(
+                delay >= low && delay <= high,
+                "deadline {:?}, attempt {}, server {:?}: got {:?}",
+                deadline,
+                attempt,
+                server,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn retryable_status_table() {
+        let cases = [
+            (200, false),
+            (400, false),
+            (401, false),
+            (404, false),
+            (408, true),
+            (422, false),
+            (429, true),
+            (500, true),
+            (501, false),
+            (502, true),
+            (503, true),
+            (504, true),
+            (529, true),
+        ];
+        for (code, expected) in cases {
+            let status = StatusCode::from_u16(code).unwrap();
+            assert_eq!(is_retryable_status(status), expected, "{}", code);
+        }
+    }
+
+    #[tokio::test]
+    async fn retryable_error_table() {
+        // Bound to an ephemeral port, then dropped so nothing listens on it
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // Accepts connections but never answers
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = silent.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let cases = [
+            ("connection refused", format!("http://{}/", closed), true),
+            ("timed out", format!("http://{}/", silent_addr), true),
+            ("invalid URL", "http://".to_string(), false),
+            ("unsupported scheme", format!("ftp://{}/", closed), false),
+        ];
+        for (name, url, expected) in cases {
+            let error = client.get(&url).send().await.unwrap_err();
+            assert_eq!(is_retryable_error(&error), expected, "{}: {}", name, error);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Helpers for streaming provider responses (NDJSON and SSE)
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use reqwest::Response;
+
+const REFINED_PROMPT_KEY: &str = "refined_prompt";
+
+/// Callback invoked with each newly decoded piece of the refined prompt
+pub type TokenSink<'a> = &'a (dyn Fn(&str) + Send + Sync);
+
+/// Read a response body line by line, calling `f` for each complete line.
+///
+/// Stops early when `f` returns `Ok(false)`.
+pub async fn for_each_line<F>(response: Response, mut f: F) -> Result<()>
+where
+    F: FnMut(&str) -> Result<bool>,
+{
+    let mut stream = response.bytes_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| anyhow::anyhow!("Stream interrupted: {}", e))?;
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(idx) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=idx).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if !f(line)? {
+                return Ok(());
+            }
+        }
+    }
+
+    if !buffer.is_empty() {
+        let line = String::from_utf8_lossy(&buffer);
+        f(line.trim_end_matches('\r'))?;
+    }
+
+    Ok(())
+}
+
+/// Read a whole response body, e.g. to decode it as JSON
+pub async fn read_body(response: Response) -> Result<Vec<u8>> {
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read response: {}", e))?;
+    Ok(body.to_vec())
+}
+
+/// Extract the payload of an SSE `data:` line
+pub fn sse_data(line: &str) -> Option<&str> {
+    line.strip_prefix("data:").map(str::trim_start)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtractState {
+    SeekingKey,
+    SeekingValue,
+    InString,
+    Done,
+}
+
+/// Incrementally decodes the `refined_prompt` string value out of a
+/// partially received JSON document.
+///
+/// Text is emitted before the document is complete, so it may not match the
+/// final response once it has been validated, repaired or re-requested;
+/// callers compare the two before trusting what was shown.
+#[derive(Debug)]
+pub struct RefinedPromptExtractor {
+    raw: String,
+    pos: usize,
+    state: ExtractState,
+    /// Nesting of objects and arrays at `pos`, once the document has started
+    depth: Option<usize>,
+    /// Start of the string being scanned, if `pos` is inside one
+    string_start: Option<usize>,
+    escaped: bool,
+    /// The last token was the `refined_prompt` key of the top-level object
+    at_key: bool,
+}
+
+impl RefinedPromptExtractor {
+    pub fn new() -> Self {
+        Self {
+            raw: String::new(),
+            pos: 0,
+            state: ExtractState::SeekingKey,
+            depth: None,
+            string_start: None,
+            escaped: false,
+            at_key: false,
+        }
+    }
+
+    /// Feed more raw model output, returning any newly decoded prompt text
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.raw.push_str(chunk);
+        let mut out = String::new();
+
+        loop {
+            match self.state {
+                ExtractState::SeekingKey => {
+                    if !self.seek_key() {
+                        break;
+                    }
+                }
+                ExtractState::SeekingValue => {
+                    let rest = &self.raw[self.pos..];
+                    let skipped =
+                        rest.len() - rest.trim_start_matches([' ', '\t', '\r', '\n', ':']).len();
+                    self.pos += skipped;
+                    match self.raw[self.pos..].chars().next() {
+                        Some('"') => {
+                            self.pos += 1;
+                            self.state = ExtractState::InString;
+                        }
+                        Some(_) => {
+                            // Not a string value; nothing to stream
+                            self.state = ExtractState::Done;
+                        }
+                        None => break,
+                    }
+                }
+                ExtractState::InString => {
+                    self.decode_string(&mut out);
+                    break;
+                }
+                ExtractState::Done => break,
+            }
+        }
+
+        out
+    }
+
+    /// The full raw output received so far
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    /// Scan the document for the top-level `refined_prompt` key, stopping
+    /// just after its colon. Returns false if more input is needed.
+    ///
+    /// Strings are skipped as a whole, so the key name appearing inside
+    /// another value is not mistaken for the key itself.
+    fn seek_key(&mut self) -> bool {
+        let bytes = self.raw.as_bytes();
+        while self.pos < bytes.len() {
+            let b = bytes[self.pos];
+            self.pos += 1;
+
+            if let Some(start) = self.string_start {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.string_start = None;
+                    self.at_key = self.depth == Some(1)
+                        && &self.raw[start..self.pos - 1] == REFINED_PROMPT_KEY;
+                }
+                continue;
+            }
+
+            // Anything before the first brace is prose or a code fence
+            let Some(depth) = self.depth.as_mut() else {
+                if b == b'{' {
+                    self.depth = Some(1);
+                }
+                continue;
+            };
+            match b {
+                b'{' | b'[' => *depth += 1,
+                b'}' | b']' => *depth = depth.saturating_sub(1),
+                b'"' => self.string_start = Some(self.pos),
+                b':' if self.at_key => {
+                    self.state = ExtractState::SeekingValue;
+                    return true;
+                }
+                b' ' | b'\t' | b'\r' | b'\n' => continue,
+                _ => {}
+            }
+            self.at_key = false;
+        }
+        false
+    }
+
+    fn decode_string(&mut self, out: &mut String) {
+        loop {
+            let rest = &self.raw[self.pos..];
+            let Some(c) = rest.chars().next() else {
+                return;
+            };
+
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    self.state = ExtractState::Done;
+                    return;
+                }
+                '\\' => match decode_escape(rest) {
+                    Some((decoded, consumed)) => {
+                        out.push_str(&decoded);
+                        self.pos += consumed;
+                    }
+                    // Escape sequence not fully received yet
+                    None => return,
+                },
+                _ => {
+                    out.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+    }
+}
+
+impl Default for RefinedPromptExtractor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode a JSON escape sequence at the start of `s` (which begins with `\`).
+///
+/// Returns the decoded text and the number of bytes consumed, or `None` if
+/// more input is needed.
+fn decode_escape(s: &str) -> Option<(String, usize)> {
+    let bytes = s.as_bytes();
+    let kind = *bytes.get(1)?;
+    let simple = match kind {
+        b'"' => Some('"'),
+        b'\\' => Some('\\'),
+        b'/' => Some('/'),
+        b'b' => Some('\u{8}'),
+        b'f' => Some('\u{c}'),
+        b'n' => Some('\n'),
+        b'r' => Some('\r'),
+        b't' => Some('\t'),
+        b'u' => None,
+        // Invalid escape; pass it through untouched
+        _ => return Some((String::from_utf8_lossy(&bytes[..2]).into_owned(), 2)),
+    };
+    if let Some(c) = simple {
+        return Some((c.to_string(), 2));
+    }
+
+    if s.len() < 6 {
+        return None;
+    }
+    let Some(high) = s.get(2..6).and_then(parse_hex4) else {
+        return Some(("\\u".to_string(), 2));
+    };
+    if (0xD800..0xDC00).contains(&high) {
+        // Surrogate pair: wait for the low half
+        if s.len() < 12 {
+            return None;
+        }
+        if s.get(6..8) == Some("\\u") {
+            if let Some(low) = s.get(8..12).and_then(parse_hex4) {
+                if (0xDC00..0xE000).contains(&low) {
+                    let code = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+                    let c = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                    return Some((c.to_string(), 12));
+                }
+            }
+        }
+        return Some((char::REPLACEMENT_CHARACTER.to_string(), 6));
+    }
+
+    let c = char::from_u32(high).unwrap_or(char::REPLACEMENT_CHARACTER);
+    Some((c.to_string(), 6))
+}
+
+fn parse_hex4(s: &str) -> Option<u32> {
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(s, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `chunks` one at a time, returning what each push emitted
+    fn extract(chunks: &[&str]) -> Vec<String> {
+        let mut extractor = RefinedPromptExtractor::new();
+        chunks.iter().map(|chunk| extractor.push(chunk)).collect()
+    }
+
+    #[test]
+    fn streams_the_refined_prompt_value() {
+        let out = extract(&[
+            "```json\n{\"refined_prompt\": \"Write ",
+            "a haiku\", \"questions\": [\"Why?\"]}",
+        ]);
+        assert_eq!(out, ["Write ", "a haiku"]);
+    }
+
+    #[test]
+    fn key_split_across_chunks_is_found() {
+        let out = extract(&["{\"refined_", "prompt\"", ":", " \"Hi\"}"]);
+        assert_eq!(out.concat(), "Hi");
+    }
+
+    #[test]
+    fn key_inside_another_string_value_is_ignored() {
+        let cases = [
+            r#"{"summary": "the \"refined_prompt\": \"x\" field", "refined_prompt": "Real"}"#,
+            r#"{"summary": "refined_prompt", "refined_prompt": "Real"}"#,
+            r#"{"questions": ["refined_prompt"], "refined_prompt": "Real"}"#,
+            r#"{"nested": {"refined_prompt": "Inner"}, "refined_prompt": "Real"}"#,
+            r#"Use "refined_prompt": "Prose" {"refined_prompt": "Real"}"#,
+        ];
+        for case in cases {
+            assert_eq!(extract(&[case]).concat(), "Real", "{}", case);
+        }
+    }
+
+    #[test]
+    fn escapes_split_across_chunks_are_decoded_once_complete() {
+        let cases: [(&[&str], &[&str]); 4] = [
+            (&["{\"refined_prompt\":\"a\\", "nb\"}"], &["a", "\nb"]),
+            (&["{\"refined_prompt\":\"\\u00", "e9!\"}"], &["", "é!"]),
+            (
+                &["{\"refined_prompt\":\"say \\", "\"hi\\", "\"\"}"],
+                &["say ", "\"hi", "\""],
+            ),
+            (
+                &["{\"refined_prompt\":\"\\ud83d", "\\ude00\"}"],
+                &["", "😀"],
+            ),
+        ];
+        for (chunks, expected) in cases {
+            assert_eq!(extract(chunks), expected, "{:?}", chunks);
+        }
+    }
+
+    #[test]
+    fn text_after_the_value_is_not_emitted() {
+        let out = extract(&["{\"refined_prompt\":\"Done\"", ", \"summary\": \"more\"}"]);
+        assert_eq!(out, ["Done", ""]);
+    }
+
+    #[test]
+    fn non_string_value_emits_nothing() {
+        assert_eq!(extract(&["{\"refined_prompt\": null}"]).concat(), "");
+    }
+
+    #[test]
+    fn decode_escape_table() {
+        let cases: [(&str, Option<(&str, usize)>); 10] = [
+            ("\\n rest", Some(("\n", 2))),
+            ("\\\"", Some(("\"", 2))),
+            ("\\/", Some(("/", 2))),
+            ("\\q", Some(("\\q", 2))),
+            ("\\", None),
+            ("\\u00e", None),
+            ("\\u00e9", Some(("é", 6))),
+            ("\\uzzzz", Some(("\\u", 2))),
+            ("\\ud83d\\ude00", Some(("😀", 12))),
+            ("\\ud83dabcdef", Some(("\u{FFFD}", 6))),
+        ];
+        for (input, expected) in cases {
+            let got = decode_escape(input);
+            assert_eq!(
+                got.as_ref().map(|(s, n)| (s.as_str(), *n)),
+                expected,
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn high_surrogate_waits_for_its_low_half() {
+        assert_eq!(decode_escape("\\ud83d"), None);
+        assert_eq!(decode_escape("\\ud83d\\ude0"), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Shared HTTP sending for providers: retries and record/replay
+
+use super::cassette::{Cassette, CassetteMode};
+use super::retry::{describe_error, is_retryable_error, is_retryable_status, retry_after};
+use super::retry::{RetryNotice, RetryPolicy};
+use anyhow::Result;
+use futures_util::StreamExt;
+use reqwest::{RequestBuilder, Response};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How a provider sends its HTTP requests
+#[derive(Debug, Clone, Default)]
+pub struct Transport {
+    pub retry: RetryPolicy,
+    pub cassette: Option<Arc<Cassette>>,
+    /// How long to wait for the response, and then for each chunk of its
+    /// body, streamed or not
+    pub read_timeout: Option<Duration>,
+}
+
+impl Transport {
+    /// Send a request, retrying connection failures, timeouts, 429 and 5xx
+    /// responses according to the retry policy. Each attempt fails if no
+    /// response arrives within the read timeout.
+    ///
+    /// `build` is called once per attempt. The last response or error is
+    /// returned once retries are exhausted so callers can report it as usual.
+    /// Transport errors are returned as `reqwest::Error` inside the `anyhow`
+    /// error so callers can inspect them.
+    pub async fn send<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let policy = &self.retry;
+        let start = Instant::now();
+        let mut attempt = 1;
+
+        loop {
+            let result = self.send_once(build()).await;
+
+            let (reason, server_delay) = match &result {
+                Ok(response) if is_retryable_status(response.status()) => (
+                    format!("Server returned {}", response.status()),
+                    retry_after(response.status(), response.headers()),
+                ),
+                Err(e) if is_read_timeout(e) => ("Request timed out".to_string(), None),
+                Err(e) => match e.downcast_ref::<reqwest::Error>() {
+                    Some(err) if is_retryable_error(err) => (describe_error(err), None),
+                    _ => return result,
+                },
+                _ => return result,
+            };
+
+            if attempt >= policy.max_attempts {
+                return result;
+            }
+
+            let delay = policy.delay(attempt, server_delay);
+            if let Some(deadline) = policy.deadline {
+                if start.elapsed() + delay > deadline {
+                    tracing::debug!("Retry deadline of {:?} reached", deadline);
+                    return result;
+                }
+            }
+
+            let notice = RetryNotice {
+                attempt,
+                max_attempts: policy.max_attempts,
+                delay,
+                reason,
+            };
+            tracing::debug!("{}", notice);
+            if let Some(observer) = &policy.observer {
+                observer(&notice);
+            }
+
+            drop(result);
+            // Replayed exchanges don't need to wait for a real server
+            if !self.is_replaying() {
+                tokio::time::sleep(delay).await;
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn send_once(&self, builder: RequestBuilder) -> Result<Response> {
+        let Some(limit) = self.read_timeout else {
+            return self.send_uncapped(builder).await;
+        };
+        let response = tokio::time::timeout(limit, self.send_uncapped(builder))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("Request timed out: no response within {}s", limit.as_secs()),
+                )
+            })??;
+        // Recorded and replayed bodies are already in memory
+        Ok(match self.cassette {
+            Some(_) => response,
+            None => with_read_timeout(response, limit),
+        })
+    }
+
+    async fn send_uncapped(&self, builder: RequestBuilder) -> Result<Response> {
+        match &self.cassette {
+            Some(cassette) => cassette.send(builder).await,
+            None => Ok(builder.send().await?),
+        }
+    }
+
+    fn is_replaying(&self) -> bool {
+        self.cassette
+            .as_ref()
+            .is_some_and(|c| c.mode() == CassetteMode::Replay)
+    }
+}
+
+/// Whether `e` is the read timeout expiring before a response arrived
+fn is_read_timeout(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+}
+
+/// Make reading `response`'s body, whole or streamed, fail once no data has
+/// arrived for `limit`
+fn with_read_timeout(response: Response, limit: Duration) -> Response {
+    let status = response.status();
+    let version = response.version();
+    let headers = response.headers().clone();
+
+    let chunks =
+        futures_util::stream::unfold(Some(response.bytes_stream()), move |stream| async move {
+            let mut stream = stream?;
+            match tokio::time::timeout(limit, stream.next()).await {
+                Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(stream))),
+                Ok(Some(Err(e))) => Some((Err(std::io::Error::other(e)), None)),
+                Ok(None) => None,
+                Err(_) => Some((
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("no data received for {}s", limit.as_secs()),
+                    )),
+                    None,
+                )),
+            }
+        });
+
+    let mut capped = http::Response::new(reqwest::Body::wrap_stream(chunks));
+    *capped.status_mut() = status;
+    *capped.version_mut() = version;
+    *capped.headers_mut() = headers;
+    Response::from(capped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A server that sends the headers and the start of a body, then stalls
+    async fn stalling_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n{\"partial\":")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+        format!("http://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn stalled_body_fails_after_read_timeout() {
+        let url = stalling_server().await;
+        let transport = Transport {
+            retry: RetryPolicy::none(),
+            cassette: None,
+            read_timeout: Some(Duration::from_millis(200)),
+        };
+        let client = reqwest::Client::new();
+
+        let response = transport.send(|| client.get(&url)).await.unwrap();
+        let body = tokio::time::timeout(Duration::from_secs(5), response.bytes())
+            .await
+            .expect("body read was not bounded by the read timeout");
+        let error = body.unwrap_err();
+        assert!(
+            format!("{}", error).contains("no data received"),
+            "unexpected error: {}",
+            error
+        );
+    }
+}
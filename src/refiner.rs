@@ -1,6 +1,24 @@
 //! Core refiner logic
 
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cache::{cache_key, is_cacheable, ResponseCache};
+use crate::clarify::{AutoSkip, ClarificationHandler, ClarificationReply};
+use crate::config::{Config, ResolvedProvider};
+use crate::history::History;
+use crate::providers::{
+    build_provider, clarification_message, new_conversation, ChatMessage, GenerationParams,
+    Provider, ProviderError, RetryObserver,
+};
+use crate::system_prompt::SystemPrompt;
+use crate::templates;
+use crate::ui::{ctrl_c, Interrupted};
 
 /// Response from the prompt refiner
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,28 +28,1259 @@ pub struct RefinerResponse {
     pub needs_clarification: bool,
     #[serde(default)]
     pub questions: Vec<String>,
+    /// Tokens used to produce this response, if the provider reported them
+    #[serde(default, skip_serializing)]
+    pub usage: Option<Usage>,
+}
+
+/// Token counts reported by a provider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    /// Build usage from optional counts, or `None` if neither was reported
+    pub fn from_counts(prompt_tokens: Option<u64>, completion_tokens: Option<u64>) -> Option<Self> {
+        if prompt_tokens.is_none() && completion_tokens.is_none() {
+            return None;
+        }
+        Some(Self {
+            prompt_tokens: prompt_tokens.unwrap_or(0),
+            completion_tokens: completion_tokens.unwrap_or(0),
+        })
+    }
+
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Add two optional usages, keeping whichever is known
+    pub fn sum(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(Self {
+                prompt_tokens: a.prompt_tokens + b.prompt_tokens,
+                completion_tokens: a.completion_tokens + b.completion_tokens,
+            }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// A raw model reply and the tokens it used
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub content: String,
+    pub usage: Option<Usage>,
+}
+
+/// Token usage, latency and estimated cost of a refinement
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RefineStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_tokens: Option<u64>,
+    /// Time spent waiting on providers, excluding clarification prompts
+    pub latency_ms: u64,
+    /// Estimated from the `[pricing]` table; absent for unpriced models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+}
+
+impl RefineStats {
+    pub fn new(usage: Option<Usage>, latency: Duration, cost_usd: Option<f64>) -> Self {
+        Self {
+            prompt_tokens: usage.map(|u| u.prompt_tokens),
+            completion_tokens: usage.map(|u| u.completion_tokens),
+            total_tokens: usage.map(|u| u.total()),
+            latency_ms: latency.as_millis() as u64,
+            cost_usd,
+        }
+    }
+}
+
+/// Name under which the response schema is sent to providers
+pub const RESPONSE_SCHEMA_NAME: &str = "refiner_response";
+
+/// JSON Schema describing [`RefinerResponse`], for providers with native
+/// structured output.
+///
+/// Every property is required and no others are allowed, as OpenAI's strict
+/// mode demands.
+pub fn response_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "refined_prompt": {
+                "type": "string",
+                "description": "The refined prompt, or the best version so far if clarification is needed"
+            },
+            "needs_clarification": {
+                "type": "boolean",
+                "description": "Whether the user must answer questions before the prompt can be finalized"
+            },
+            "questions": {
+                "type": "array",
+                "items": { "type": "string" },
+                "description": "Clarifying questions for the user; empty unless needs_clarification is true"
+            }
+        },
+        "required": ["refined_prompt", "needs_clarification", "questions"],
+        "additionalProperties": false
+    })
+}
+
+/// Parse a model reply into a [`RefinerResponse`].
+///
+/// Tolerates markdown code fences, prose before or after the JSON object, and
+/// loosely typed fields (`questions` as a string, `needs_clarification` as
+/// `"true"`, and so on).
+pub fn parse_response(content: &str) -> Result<RefinerResponse> {
+    let object = extract_json_object(content).with_context(|| {
+        format!(
+            "Refiner response did not contain a JSON object. Raw content:\n{}",
+            content
+        )
+    })?;
+    let response = coerce_response(object);
+
+    if response.refined_prompt.trim().is_empty() {
+        anyhow::bail!("Refiner returned an empty refined_prompt");
+    }
+
+    Ok(response)
+}
+
+/// Parse a completion, and if it isn't a usable response, ask the model once
+/// to correct itself. Token usage of both turns is added up.
+///
+/// `repair` is called with the rejected reply and a corrective instruction,
+/// and should send both back to the model as a follow-up turn, returning the
+/// new reply.
+pub async fn parse_or_repair<F, Fut>(first: Completion, repair: F) -> Result<RefinerResponse>
+where
+    F: FnOnce(String, String) -> Fut,
+    Fut: Future<Output = Result<Completion>>,
+{
+    let error = match parse_response(&first.content) {
+        Ok(mut response) => {
+            response.usage = first.usage;
+            return Ok(response);
+        }
+        Err(e) => e,
+    };
+    tracing::debug!(
+        "Invalid refiner response, asking for a correction: {:#}",
+        error
+    );
+
+    let corrected = repair(first.content, repair_instruction(&error)).await?;
+    let mut response = parse_response(&corrected.content)
+        .context("Refiner response was still invalid after a correction")?;
+    response.usage = Usage::sum(first.usage, corrected.usage);
+    Ok(response)
+}
+
+/// Follow-up message asking the model to re-emit its answer as valid JSON
+pub fn repair_instruction(error: &anyhow::Error) -> String {
+    // The first sentence is enough; the rest repeats the raw content
+    let reason = error.to_string();
+    let reason = reason.split(". Raw content").next().unwrap_or_default();
+    format!(
+        "Your previous reply could not be used ({}). Respond again with ONLY a valid JSON \
+         object with the fields \"refined_prompt\" (string), \"needs_clarification\" \
+         (boolean) and \"questions\" (array of strings). No markdown code blocks, no \
+         explanation.",
+        reason.trim_end_matches('.')
+    )
+}
+
+/// Find the first JSON object in `text`, skipping code fences and any
+/// surrounding prose
+pub(crate) fn extract_json_object(text: &str) -> Option<Map<String, Value>> {
+    let mut search = 0;
+    while let Some(offset) = text[search..].find('{') {
+        let start = search + offset;
+        if let Some(end) = balanced_object_end(&text[start..]) {
+            if let Ok(Value::Object(object)) = serde_json::from_str(&text[start..start + end]) {
+                return Some(object);
+            }
+        }
+        search = start + 1;
+    }
+    None
+}
+
+/// Length of the balanced `{...}` at the start of `text`, ignoring braces
+/// inside strings
+fn balanced_object_end(text: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Build a response from a JSON object, accepting common type mistakes
+fn coerce_response(mut object: Map<String, Value>) -> RefinerResponse {
+    let refined_prompt = match object
+        .remove("refined_prompt")
+        .or_else(|| object.remove("refinedPrompt"))
+    {
+        Some(Value::String(s)) => s,
+        Some(Value::Array(items)) => items
+            .into_iter()
+            .map(value_to_string)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    };
+
+    let questions = match object.remove("questions") {
+        Some(Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                // Some models send {"question": "..."} objects
+                Value::Object(mut q) => q
+                    .remove("question")
+                    .map_or_else(|| Value::Object(q).to_string(), value_to_string),
+                other => value_to_string(other),
+            })
+            .map(|q| q.trim().to_string())
+            .filter(|q| !q.is_empty())
+            .collect(),
+        Some(Value::String(s)) => s
+            .lines()
+            .map(|line| line.trim().trim_start_matches(['-', '*', '•']).trim())
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let needs_clarification = match object
+        .remove("needs_clarification")
+        .or_else(|| object.remove("needsClarification"))
+    {
+        Some(Value::Bool(b)) => b,
+        Some(Value::String(s)) => matches!(s.trim().to_lowercase().as_str(), "true" | "yes"),
+        Some(Value::Number(n)) => n.as_f64().is_some_and(|n| n != 0.0),
+        Some(_) => false,
+        // Questions without the flag still mean the model wants answers
+        None => !questions.is_empty(),
+    };
+
+    RefinerResponse {
+        refined_prompt,
+        needs_clarification,
+        questions,
+        usage: None,
+    }
+}
+
+fn value_to_string(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
 }
 
-/// Build clarification summary from Q&A pairs
+/// Build clarification summary from Q&A pairs. Empty answers are marked as
+/// skipped.
 pub fn build_clarification_summary(questions: &[String], answers: &[String]) -> String {
     let mut summary = String::new();
     for (i, (q, a)) in questions.iter().zip(answers.iter()).enumerate() {
+        let a = if a.trim().is_empty() {
+            "(skipped, use a sensible default)"
+        } else {
+            a.as_str()
+        };
         summary.push_str(&format!("Q{}: {} → Answer: {}\n", i + 1, q, a));
     }
     summary
 }
 
-/// Refiner struct for managing the refinement flow
-pub struct Refiner;
+/// The prompt with a named template applied, or unchanged without one
+pub fn apply_template(prompt: &str, template: Option<&str>) -> Result<String> {
+    match template {
+        Some(name) => {
+            let template = templates::get_template(name)
+                .with_context(|| format!("Unknown template: {}", name))?;
+            Ok(template.apply(prompt))
+        }
+        None => Ok(prompt.to_string()),
+    }
+}
+
+/// Progress reported while refining, for status displays
+#[derive(Debug)]
+pub enum RefineEvent<'a> {
+    /// A request is about to be sent to the provider with this display name;
+    /// `round` is 0 for the first request, then the clarification round
+    /// being answered
+    Requesting {
+        provider: &'a str,
+        model: &'a str,
+        round: u32,
+    },
+    /// A piece of the refined prompt arrived while streaming
+    Token(&'a str),
+    /// The request finished, successfully or not; `streamed` if any tokens
+    /// were reported, and `replaced` if the final response differs from them,
+    /// e.g. because the streamed reply was invalid and had to be repaired
+    Finished { streamed: bool, replaced: bool },
+    /// `from` was unavailable and `to` is tried next
+    FallingBack {
+        from: &'a str,
+        to: &'a str,
+        error: &'a anyhow::Error,
+    },
+    /// An answer came from the response cache instead of the provider
+    CacheHit {
+        provider: &'a str,
+        stored: DateTime<Utc>,
+    },
+    /// The refinement was interrupted by Ctrl-C or the clarification handler
+    Interrupted,
+    /// An interrupted clarification round was saved to history
+    DraftSaved(i64),
+}
+
+/// Receives [`RefineEvent`]s
+pub type RefineObserver = Arc<dyn Fn(RefineEvent<'_>) + Send + Sync>;
+
+/// Questions the model asked in one round and the answers it got
+#[derive(Debug, Clone, Serialize)]
+pub struct ClarificationRound {
+    pub questions: Vec<String>,
+    /// One per question; empty for a skipped question
+    pub answers: Vec<String>,
+    /// The handler declined to answer, so the refinement so far was kept
+    pub skipped: bool,
+}
+
+/// Result of [`Refiner::refine`]
+#[derive(Debug, Clone)]
+pub struct RefineOutcome {
+    /// The prompt that was refined, after any template
+    pub prompt: String,
+    /// The system prompt sent with it
+    pub system_prompt: SystemPrompt,
+    /// The final response
+    pub response: RefinerResponse,
+    /// Registry name of the provider that answered
+    pub provider: String,
+    pub model: String,
+    pub stats: RefineStats,
+    /// Clarification rounds, in order
+    pub clarifications: Vec<ClarificationRound>,
+    /// Every answer came from the response cache
+    pub cached: bool,
+    /// The final response was reported token by token
+    pub streamed: bool,
+    /// History entry, if the refinement was saved
+    pub history_id: Option<i64>,
+}
+
+/// Refinement engine: resolves providers from the config, applies templates
+/// and context, consults the response cache, falls back along the provider
+/// chain, asks clarifying questions until the model is satisfied and saves the
+/// result to history.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use prep::clarify::ScriptedClarifier;
+/// use prep::{Config, Refiner};
+///
+/// let outcome = Refiner::new(Config::load()?)
+///     .with_provider("openai")
+///     .with_template("code")
+///     .with_clarification_handler(ScriptedClarifier::new(vec!["Rust".into()]))
+///     .refine("write a json parser")
+///     .await?;
+/// println!("{}", outcome.response.refined_prompt);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Refiner {
+    config: Config,
+    provider: Option<String>,
+    model: Option<String>,
+    fallback: Vec<String>,
+    chain: Option<Vec<ResolvedProvider>>,
+    params: GenerationParams,
+    template: Option<String>,
+    context: Option<String>,
+    system_prompt: Option<SystemPrompt>,
+    clarifier: Arc<dyn ClarificationHandler>,
+    max_rounds: u32,
+    history: bool,
+    cache: bool,
+    refresh: bool,
+    stream: bool,
+    ctrl_c: bool,
+    observer: Option<RefineObserver>,
+    retry_observer: Option<RetryObserver>,
+}
 
 impl Refiner {
-    pub fn new() -> Self {
-        Self
+    /// A refiner using the default provider, history and cache settings
+    /// from `config`, that skips clarification
+    pub fn new(config: Config) -> Self {
+        Self {
+            history: config.history.enabled,
+            cache: config.cache.enabled,
+            max_rounds: config.clarification.max_rounds,
+            stream: config.ui.stream,
+            config,
+            provider: None,
+            model: None,
+            fallback: Vec::new(),
+            chain: None,
+            params: GenerationParams::default(),
+            template: None,
+            context: None,
+            system_prompt: None,
+            clarifier: Arc::new(AutoSkip),
+            refresh: false,
+            ctrl_c: false,
+            observer: None,
+            retry_observer: None,
+        }
+    }
+
+    /// Registry name of the provider to use instead of the default
+    pub fn with_provider(mut self, name: impl Into<String>) -> Self {
+        self.provider = Some(name.into());
+        self
+    }
+
+    /// Model to use instead of the provider's configured one
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    /// Providers to try when the primary is unavailable, instead of
+    /// `default.fallback`
+    pub fn with_fallback(mut self, names: Vec<String>) -> Self {
+        self.fallback = names;
+        self
+    }
+
+    /// Use an already resolved provider chain, ignoring the provider, model,
+    /// fallback and generation settings given to the builder
+    pub fn with_chain(mut self, chain: Vec<ResolvedProvider>) -> Self {
+        self.chain = Some(chain);
+        self
+    }
+
+    /// Generation settings layered over each provider's config
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Apply a built-in template to the prompt
+    pub fn with_template(mut self, name: impl Into<String>) -> Self {
+        self.template = Some(name.into());
+        self
+    }
+
+    /// Send `context` (such as a file's content) along with the prompt
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// Use `prompt` instead of the system prompt the config selects
+    pub fn with_system_prompt(mut self, prompt: SystemPrompt) -> Self {
+        self.system_prompt = Some(prompt);
+        self
+    }
+
+    /// Answer clarifying questions with `handler`
+    pub fn with_clarification_handler(
+        mut self,
+        handler: impl ClarificationHandler + 'static,
+    ) -> Self {
+        self.clarifier = Arc::new(handler);
+        self
+    }
+
+    /// Most rounds of clarifying questions, instead of
+    /// `clarification.max_rounds`; 0 never asks
+    pub fn with_max_rounds(mut self, max_rounds: u32) -> Self {
+        self.max_rounds = max_rounds;
+        self
+    }
+
+    /// Save the result to history (and drafts on interruption)
+    pub fn with_history(mut self, enabled: bool) -> Self {
+        self.history = enabled;
+        self
+    }
+
+    /// Read and write the response cache
+    pub fn with_cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled;
+        self
+    }
+
+    /// Ignore cached answers but cache new ones
+    pub fn with_refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Report the refined prompt token by token as [`RefineEvent::Token`]
+    pub fn with_stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Stop with [`Interrupted`] on Ctrl-C, saving a draft if clarification
+    /// had started
+    pub fn with_ctrl_c(mut self, enabled: bool) -> Self {
+        self.ctrl_c = enabled;
+        self
+    }
+
+    /// Receive progress events
+    pub fn with_observer(mut self, observer: RefineObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Be told about retries of transient failures
+    pub fn with_retry_observer(mut self, observer: RetryObserver) -> Self {
+        self.retry_observer = Some(observer);
+        self
+    }
+
+    /// The providers that will be tried, in order
+    pub fn chain(&self) -> Result<Vec<ResolvedProvider>> {
+        if let Some(chain) = &self.chain {
+            return Ok(chain.clone());
+        }
+        let mut chain = self.config.resolve_chain(
+            self.provider.as_deref(),
+            &self.fallback,
+            self.model.as_deref(),
+            None,
+        )?;
+        for resolved in &mut chain {
+            resolved.params = self.params.clone().or(&resolved.params);
+            resolved
+                .params
+                .validate(resolved.kind)
+                .with_context(|| format!("Invalid generation settings for '{}'", resolved.name))?;
+        }
+        Ok(chain)
+    }
+
+    /// Refine `prompt`. History keeps it as given, before any template.
+    pub async fn refine(&self, prompt: &str) -> Result<RefineOutcome> {
+        if prompt.trim().is_empty() {
+            anyhow::bail!("No prompt provided");
+        }
+        let input = prompt;
+        let prompt = apply_template(input, self.template.as_deref())?;
+        let system_prompt = match &self.system_prompt {
+            Some(system_prompt) => {
+                system_prompt.validate()?;
+                system_prompt.clone()
+            }
+            None => self.config.resolve_system_prompt(None)?,
+        };
+        let mut chain = self.chain()?;
+        if let Some(observer) = &self.retry_observer {
+            for resolved in &mut chain {
+                resolved.transport.retry = resolved
+                    .transport
+                    .retry
+                    .clone()
+                    .with_observer(Arc::clone(observer));
+            }
+        }
+        let cache = self.open_cache();
+
+        // First round, from the cache or falling back along the chain
+        let mut conversation =
+            new_conversation(&system_prompt.content, &prompt, self.context.as_deref());
+        let started = Instant::now();
+        let cached = self.cached(&cache, &chain, &conversation);
+        let mut from_cache = cached.is_some();
+        let (resolved, provider, response, mut streamed) = match cached {
+            Some((index, response)) => {
+                let resolved = chain.swap_remove(index);
+                let provider = build_provider(&resolved)?;
+                (resolved, provider, response, false)
+            }
+            None => {
+                let result = self.refine_with_fallback(chain, &conversation).await?;
+                self.store(&cache, &result.0, &conversation, &result.2);
+                result
+            }
+        };
+        let mut latency = started.elapsed();
+        let mut usage = response.usage;
+
+        // Clarification rounds, until the model stops asking or the limit
+        let mut rounds: Vec<ClarificationRound> = Vec::new();
+        let mut response = response;
+        while response.needs_clarification
+            && !response.questions.is_empty()
+            && rounds.len() < self.max_rounds as usize
+        {
+            let round = rounds.len() as u32 + 1;
+            let mut answers = Vec::new();
+            let reply = self
+                .clarifier
+                .answer(round, &response.questions, &mut answers)
+                .await;
+            if reply.as_ref().is_err_and(|e| e.is::<Interrupted>()) {
+                self.emit(RefineEvent::Interrupted);
+                self.save_draft(
+                    input,
+                    &system_prompt,
+                    &resolved,
+                    &response,
+                    &rounds,
+                    &answers,
+                );
+            }
+            let reply = reply?;
+
+            if reply == ClarificationReply::Skipped {
+                rounds.push(ClarificationRound {
+                    questions: response.questions.clone(),
+                    answers,
+                    skipped: true,
+                });
+                break;
+            }
+
+            // Questions left when the handler stopped count as skipped
+            answers.resize(response.questions.len(), String::new());
+            let last = reply == ClarificationReply::Stop || round == self.max_rounds;
+            let summary = build_clarification_summary(&response.questions, &answers);
+            // Send the model its own questions back with the answers
+            conversation.push(ChatMessage::from_response(&response));
+            conversation.push(clarification_message(&summary, last));
+
+            let started = Instant::now();
+            let cached = self.cached(&cache, std::slice::from_ref(&resolved), &conversation);
+            from_cache &= cached.is_some();
+            let result = match cached {
+                Some((_, response)) => Ok((response, false)),
+                None => {
+                    let result = self
+                        .interruptible(self.refine_with_progress(
+                            provider.as_ref(),
+                            round,
+                            &conversation,
+                        ))
+                        .await;
+                    if let Ok((response, _)) = &result {
+                        self.store(&cache, &resolved, &conversation, response);
+                    }
+                    result
+                }
+            };
+            if result.as_ref().is_err_and(|e| e.is::<Interrupted>()) {
+                self.save_draft(
+                    input,
+                    &system_prompt,
+                    &resolved,
+                    &response,
+                    &rounds,
+                    &answers,
+                );
+            }
+            let (next, next_streamed) = result?;
+            streamed = next_streamed;
+            latency += started.elapsed();
+            usage = Usage::sum(usage, next.usage);
+
+            rounds.push(ClarificationRound {
+                questions: std::mem::replace(&mut response, next).questions,
+                answers,
+                skipped: false,
+            });
+            if last {
+                break;
+            }
+        }
+        let final_response = response;
+
+        let cost = usage
+            .zip(self.config.price_for(&resolved.model))
+            .map(|(usage, price)| price.cost(&usage));
+        let stats = RefineStats::new(usage, latency, cost);
+
+        let history_id = self.history.then(|| {
+            self.save(
+                input,
+                &system_prompt,
+                &resolved,
+                &final_response,
+                &stats,
+                &rounds,
+            )
+        });
+
+        Ok(RefineOutcome {
+            prompt,
+            system_prompt,
+            response: final_response,
+            provider: resolved.name,
+            model: resolved.model,
+            stats,
+            clarifications: rounds,
+            cached: from_cache,
+            streamed,
+            history_id: history_id.flatten(),
+        })
+    }
+
+    fn emit(&self, event: RefineEvent<'_>) {
+        if let Some(observer) = &self.observer {
+            observer(event);
+        }
+    }
+
+    /// Run `future` unless Ctrl-C arrives first, when enabled. Dropping the
+    /// future cancels any request in flight.
+    async fn interruptible<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        if !self.ctrl_c {
+            return future.await;
+        }
+        tokio::select! {
+            result = future => result,
+            () = ctrl_c() => {
+                self.emit(RefineEvent::Interrupted);
+                Err(Interrupted.into())
+            }
+        }
+    }
+
+    /// Try each provider in turn, moving on when one is unavailable
+    async fn refine_with_fallback(
+        &self,
+        chain: Vec<ResolvedProvider>,
+        messages: &[ChatMessage],
+    ) -> Result<(ResolvedProvider, Box<dyn Provider>, RefinerResponse, bool)> {
+        let mut remaining = chain.into_iter().peekable();
+
+        while let Some(resolved) = remaining.next() {
+            let result = match build_provider(&resolved) {
+                Ok(provider) => self
+                    .interruptible(self.refine_with_progress(provider.as_ref(), 0, messages))
+                    .await
+                    .map(|(response, streamed)| (provider, response, streamed)),
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok((provider, response, streamed)) => {
+                    return Ok((resolved, provider, response, streamed));
+                }
+                Err(e) if ProviderError::is_fallback_eligible(&e) => {
+                    let Some(next) = remaining.peek() else {
+                        return Err(e);
+                    };
+                    tracing::debug!("{} failed: {:#}", resolved.name, e);
+                    self.emit(RefineEvent::FallingBack {
+                        from: &resolved.name,
+                        to: &next.name,
+                        error: &e,
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        anyhow::bail!("No provider configured")
+    }
+
+    /// One request, streamed if enabled, bracketed by `Requesting` and
+    /// `Finished` events
+    async fn refine_with_progress(
+        &self,
+        provider: &dyn Provider,
+        round: u32,
+        messages: &[ChatMessage],
+    ) -> Result<(RefinerResponse, bool)> {
+        self.emit(RefineEvent::Requesting {
+            provider: provider.name(),
+            model: provider.model(),
+            round,
+        });
+
+        if !self.stream {
+            let response = provider.refine(messages).await;
+            self.emit(RefineEvent::Finished {
+                streamed: false,
+                replaced: false,
+            });
+            return Ok((response?, false));
+        }
+
+        let shown = Mutex::new(String::new());
+        let on_token = |text: &str| {
+            shown.lock().expect("token lock poisoned").push_str(text);
+            self.emit(RefineEvent::Token(text));
+        };
+        let response = provider.refine_stream(messages, &on_token).await;
+
+        // A repaired or otherwise rewritten response no longer matches what
+        // was shown, so it doesn't count as streamed and gets printed in full
+        let shown = shown.into_inner().expect("token lock poisoned");
+        let streamed = !shown.is_empty();
+        let replaced = streamed
+            && response
+                .as_ref()
+                .is_ok_and(|response| response.refined_prompt != shown);
+        self.emit(RefineEvent::Finished { streamed, replaced });
+        Ok((response?, streamed && !replaced))
+    }
+
+    /// The response cache, if enabled. A cache that can't be opened is
+    /// skipped rather than failing the refinement.
+    fn open_cache(&self) -> Option<Mutex<ResponseCache>> {
+        if !(self.cache && self.config.cache.enabled) {
+            return None;
+        }
+        match ResponseCache::open(&self.config.cache) {
+            Ok(cache) => Some(Mutex::new(cache)),
+            Err(e) => {
+                tracing::debug!("Response cache unavailable: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// A cached answer to `messages` from the first provider in `chain` that
+    /// has one, with its index
+    fn cached(
+        &self,
+        cache: &Option<Mutex<ResponseCache>>,
+        chain: &[ResolvedProvider],
+        messages: &[ChatMessage],
+    ) -> Option<(usize, RefinerResponse)> {
+        if self.refresh {
+            return None;
+        }
+        let cache = cache.as_ref()?.lock().ok()?;
+        chain.iter().enumerate().find_map(|(index, resolved)| {
+            if !is_cacheable(resolved) {
+                return None;
+            }
+            let key = cache_key(resolved, self.template.as_deref(), messages);
+            match cache.get(&key) {
+                Ok(Some(hit)) => {
+                    self.emit(RefineEvent::CacheHit {
+                        provider: &resolved.name,
+                        stored: hit.created_at,
+                    });
+                    Some((index, hit.response))
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    tracing::debug!("Cache lookup failed: {:#}", e);
+                    None
+                }
+            }
+        })
+    }
+
+    /// Save a provider's answer to `messages` in the cache
+    fn store(
+        &self,
+        cache: &Option<Mutex<ResponseCache>>,
+        resolved: &ResolvedProvider,
+        messages: &[ChatMessage],
+        response: &RefinerResponse,
+    ) {
+        if !is_cacheable(resolved) {
+            return;
+        }
+        let Some(Ok(cache)) = cache.as_ref().map(Mutex::lock) else {
+            return;
+        };
+        let key = cache_key(resolved, self.template.as_deref(), messages);
+        if let Err(e) = cache.put(&key, resolved, response) {
+            tracing::debug!("Could not cache the response: {:#}", e);
+        }
+    }
+
+    /// Add the result to history with its clarification rounds and prune
+    /// old entries
+    fn save(
+        &self,
+        prompt: &str,
+        system_prompt: &SystemPrompt,
+        resolved: &ResolvedProvider,
+        response: &RefinerResponse,
+        stats: &RefineStats,
+        rounds: &[ClarificationRound],
+    ) -> Option<i64> {
+        let history = History::open().ok()?;
+        let id = history
+            .add(
+                prompt,
+                &response.refined_prompt,
+                &resolved.name,
+                &resolved.model,
+                stats,
+            )
+            .ok();
+        if let Some(id) = id {
+            let _ = history.set_system_prompt(id, system_prompt);
+            if let Some(clarification) = summarize_rounds(rounds) {
+                let _ = history.set_clarification(id, &clarification);
+            }
+        }
+        let _ = history.prune(self.config.history.max_entries);
+        id
+    }
+
+    /// Save an interrupted clarification round to history with the answers
+    /// given so far, including earlier rounds', so they aren't lost
+    fn save_draft(
+        &self,
+        prompt: &str,
+        system_prompt: &SystemPrompt,
+        resolved: &ResolvedProvider,
+        response: &RefinerResponse,
+        rounds: &[ClarificationRound],
+        answers: &[String],
+    ) {
+        if !self.history {
+            return;
+        }
+        let mut rounds = rounds.to_vec();
+        rounds.push(ClarificationRound {
+            questions: response.questions.clone(),
+            answers: answers.to_vec(),
+            skipped: false,
+        });
+        let clarification = summarize_rounds(&rounds);
+        let saved = History::open().and_then(|history| {
+            let id = history.add_draft(
+                prompt,
+                &response.refined_prompt,
+                &resolved.name,
+                &resolved.model,
+                clarification.as_deref(),
+            )?;
+            history.set_system_prompt(id, system_prompt)?;
+            Ok(id)
+        });
+        match saved {
+            Ok(id) => self.emit(RefineEvent::DraftSaved(id)),
+            Err(e) => tracing::warn!("Could not save draft: {:#}", e),
+        }
+    }
+}
+
+/// The answered rounds' questions and answers for history, headed by round
+/// number when there were several. `None` if nothing was answered.
+fn summarize_rounds(rounds: &[ClarificationRound]) -> Option<String> {
+    let summaries: Vec<String> = rounds
+        .iter()
+        .filter(|round| !round.skipped)
+        .map(|round| build_clarification_summary(&round.questions, &round.answers))
+        .filter(|summary| !summary.is_empty())
+        .collect();
+    match summaries.as_slice() {
+        [] => None,
+        [only] => Some(only.clone()),
+        _ => Some(
+            summaries
+                .iter()
+                .enumerate()
+                .map(|(i, summary)| format!("Round {}:\n{}", i + 1, summary))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
     }
 }
 
-impl Default for Refiner {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::TokenSink;
+    use async_trait::async_trait;
+
+    /// Streams `first` as a provider would, showing the refined prompt
+    /// extracted so far, and answers a repair request with `repaired`
+    struct StreamingStub {
+        first: &'static str,
+        shown: &'static str,
+        repaired: &'static str,
+    }
+
+    #[async_trait]
+    impl Provider for StreamingStub {
+        fn name(&self) -> &str {
+            "stub"
+        }
+
+        fn model(&self) -> &str {
+            "stub"
+        }
+
+        async fn refine(&self, _messages: &[ChatMessage]) -> Result<RefinerResponse> {
+            parse_response(self.first)
+        }
+
+        async fn refine_stream(
+            &self,
+            _messages: &[ChatMessage],
+            on_token: TokenSink<'_>,
+        ) -> Result<RefinerResponse> {
+            on_token(self.shown);
+            let first = Completion {
+                content: self.first.to_string(),
+                usage: None,
+            };
+            parse_or_repair(first, |_, _| async {
+                Ok(Completion {
+                    content: self.repaired.to_string(),
+                    usage: None,
+                })
+            })
+            .await
+        }
+
+        async fn chat(&self, _messages: &[ChatMessage]) -> Result<Completion> {
+            anyhow::bail!("not used")
+        }
+    }
+
+    fn parsed(content: &str) -> (String, bool, Vec<String>) {
+        let response = parse_response(content).unwrap();
+        (
+            response.refined_prompt,
+            response.needs_clarification,
+            response.questions,
+        )
+    }
+
+    #[test]
+    fn parse_response_tolerates_wrapping() {
+        let cases = [
+            (
+                "fenced",
+                "```json\n{\"refined_prompt\": \"A\", \"needs_clarification\": false, \"questions\": []}\n```",
+            ),
+            (
+                "leading prose",
+                "Here is the refined prompt:\n{\"refined_prompt\": \"A\", \"needs_clarification\": false}",
+            ),
+            (
+                "trailing prose",
+                "{\"refined_prompt\": \"A\", \"questions\": []} Let me know if you need more.",
+            ),
+            (
+                "braces before the object",
+                "Use {name} placeholders: {\"refined_prompt\": \"A\"}",
+            ),
+            ("camelCase keys", "{\"refinedPrompt\": \"A\", \"needsClarification\": false}"),
+        ];
+        for (name, content) in cases {
+            assert_eq!(
+                parsed(content),
+                ("A".to_string(), false, vec![]),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            parsed(r#"{"refined_prompt": "Say \"}{\" and {stop}"}"#).0,
+            "Say \"}{\" and {stop}"
+        );
+    }
+
+    #[test]
+    fn parse_response_fills_missing_fields() {
+        let cases = [
+            (r#"{"refined_prompt": "A"}"#, false, vec![]),
+            (
+                r#"{"refined_prompt": "A", "questions": ["Why?"]}"#,
+                true,
+                vec!["Why?"],
+            ),
+            (
+                r#"{"refined_prompt": "A", "needs_clarification": false, "questions": ["Why?"]}"#,
+                false,
+                vec!["Why?"],
+            ),
+            (
+                r#"{"refined_prompt": "A", "needs_clarification": "yes"}"#,
+                true,
+                vec![],
+            ),
+            (
+                r#"{"refined_prompt": "A", "needs_clarification": 1}"#,
+                true,
+                vec![],
+            ),
+            (
+                r#"{"refined_prompt": "A", "needs_clarification": null}"#,
+                false,
+                vec![],
+            ),
+            (
+                r#"{"refined_prompt": "A", "questions": "- Why?\n\n* How?"}"#,
+                true,
+                vec!["Why?", "How?"],
+            ),
+            (
+                r#"{"refined_prompt": "A", "questions": [{"question": "Why?"}, " ", 3]}"#,
+                true,
+                vec!["Why?", "3"],
+            ),
+        ];
+        for (content, needs_clarification, questions) in cases {
+            let questions: Vec<String> = questions.into_iter().map(String::from).collect();
+            assert_eq!(
+                parsed(content),
+                ("A".to_string(), needs_clarification, questions),
+                "{}",
+                content
+            );
+        }
+
+        assert_eq!(parsed(r#"{"refined_prompt": ["A", "B"]}"#).0, "A\nB");
+        assert_eq!(parsed(r#"{"refined_prompt": 42}"#).0, "42");
+    }
+
+    #[test]
+    fn parse_response_rejects_unusable_replies() {
+        let cases = [
+            (
+                "no JSON",
+                "Sure! Here is a better prompt.",
+                "did not contain a JSON object",
+            ),
+            (
+                "array",
+                r#"["refined_prompt", "A"]"#,
+                "did not contain a JSON object",
+            ),
+            ("string", r#""A""#, "did not contain a JSON object"),
+            ("number", "42", "did not contain a JSON object"),
+            (
+                "truncated",
+                r#"{"refined_prompt": "A", "#,
+                "did not contain a JSON object",
+            ),
+            (
+                "missing prompt",
+                r#"{"questions": []}"#,
+                "empty refined_prompt",
+            ),
+            (
+                "null prompt",
+                r#"{"refined_prompt": null}"#,
+                "empty refined_prompt",
+            ),
+            (
+                "blank prompt",
+                r#"{"refined_prompt": "  "}"#,
+                "empty refined_prompt",
+            ),
+        ];
+        for (name, content, expected) in cases {
+            let error = parse_response(content).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn extract_json_object_finds_the_first_valid_object() {
+        let cases = [
+            ("{\"a\": 1}", Some(json!({"a": 1}))),
+            (
+                "{not json} then {\"a\": 1} and {\"b\": 2}",
+                Some(json!({"a": 1})),
+            ),
+            ("{\"a\": {\"b\": \"}\"}}", Some(json!({"a": {"b": "}"}}))),
+            ("[{\"a\": 1}]", Some(json!({"a": 1}))),
+            ("[1, 2]", None),
+            ("{\"a\": 1", None),
+            ("", None),
+        ];
+        for (text, expected) in cases {
+            let expected = expected.map(|value| match value {
+                Value::Object(object) => object,
+                _ => unreachable!(),
+            });
+            assert_eq!(extract_json_object(text), expected, "{}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn repaired_stream_is_not_reported_as_streamed() {
+        let provider = StreamingStub {
+            first: r#"{"refined_prompt": "Write a haiku", "needs_clar"#,
+            shown: "Write a haiku",
+            repaired: r#"{"refined_prompt": "Write a haiku about rain", "needs_clarification": false, "questions": []}"#,
+        };
+        let refiner = Refiner::new(Config::default()).with_stream(true);
+
+        let (response, streamed) = refiner
+            .refine_with_progress(&provider, 0, &[])
+            .await
+            .unwrap();
+        assert_eq!(response.refined_prompt, "Write a haiku about rain");
+        assert!(!streamed);
+    }
+
+    #[tokio::test]
+    async fn valid_stream_is_reported_as_streamed() {
+        let provider = StreamingStub {
+            first: r#"{"refined_prompt": "Write a haiku", "needs_clarification": false, "questions": []}"#,
+            shown: "Write a haiku",
+            repaired: "",
+        };
+        let refiner = Refiner::new(Config::default()).with_stream(true);
+
+        let (response, streamed) = refiner
+            .refine_with_progress(&provider, 0, &[])
+            .await
+            .unwrap();
+        assert_eq!(response.refined_prompt, "Write a haiku");
+        assert!(streamed);
     }
 }
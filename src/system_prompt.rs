@@ -0,0 +1,127 @@
+//! System prompts: the built-in one, `[system_prompts.<name>]` entries and
+//! files (`--system-prompt`)
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::cache::sha256_hex;
+use crate::config::Config;
+use crate::providers::SYSTEM_PROMPT;
+
+/// Name of the built-in system prompt
+pub const BUILTIN_NAME: &str = "default";
+
+/// Fields of the `RefinerResponse` JSON a system prompt must ask for, since
+/// the response parser needs them
+const CONTRACT_FIELDS: &[&str] = &["refined_prompt", "needs_clarification", "questions"];
+
+/// Hex digits of the content hash shown to users
+const SHORT_HASH_LEN: usize = 12;
+
+/// A `[system_prompts.<name>]` table: the prompt inline, or a file holding it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SystemPromptConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// Read from this file; relative paths start at the config directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+}
+
+/// The system prompt sent to the refiner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemPrompt {
+    /// Config entry name, file path or [`BUILTIN_NAME`]
+    pub name: String,
+    pub content: String,
+}
+
+impl SystemPrompt {
+    /// The prompt compiled into prep
+    pub fn builtin() -> Self {
+        Self {
+            name: BUILTIN_NAME.to_string(),
+            content: SYSTEM_PROMPT.to_string(),
+        }
+    }
+
+    /// Load a prompt from a file, named after its path
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read system prompt: {}", path.display()))?;
+        Ok(Self {
+            name: path.display().to_string(),
+            content,
+        })
+    }
+
+    /// SHA-256 of the content, identifying this version of the prompt
+    pub fn hash(&self) -> String {
+        sha256_hex(self.content.as_bytes())
+    }
+
+    /// The start of [`hash`](Self::hash), for display
+    pub fn short_hash(&self) -> String {
+        self.hash()[..SHORT_HASH_LEN].to_string()
+    }
+
+    /// Check that the prompt still asks for the JSON response the refiner
+    /// parses: JSON output with `refined_prompt`, `needs_clarification` and
+    /// `questions`
+    pub fn validate(&self) -> Result<()> {
+        if self.content.trim().is_empty() {
+            anyhow::bail!("System prompt '{}' is empty", self.name);
+        }
+        let mut missing: Vec<&str> = CONTRACT_FIELDS
+            .iter()
+            .copied()
+            .filter(|field| !self.content.contains(field))
+            .collect();
+        if !self.content.to_lowercase().contains("json") {
+            missing.insert(0, "JSON");
+        }
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "System prompt '{}' must ask for a JSON object with \"refined_prompt\", \
+                 \"needs_clarification\" and \"questions\"; it doesn't mention {}",
+                self.name,
+                missing.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Short display form: the name and short hash
+impl std::fmt::Display for SystemPrompt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.short_hash())
+    }
+}
+
+impl SystemPromptConfig {
+    /// The prompt this `[system_prompts.<name>]` entry holds
+    pub fn load(&self, name: &str) -> Result<SystemPrompt> {
+        let content = match (&self.content, &self.file) {
+            (Some(content), None) => content.clone(),
+            (None, Some(file)) => {
+                let path = match Config::path()?.parent() {
+                    Some(dir) if file.is_relative() => dir.join(file),
+                    _ => file.clone(),
+                };
+                SystemPrompt::from_file(&path)
+                    .with_context(|| format!("In [system_prompts.{}]", name))?
+                    .content
+            }
+            (Some(_), Some(_)) => {
+                anyhow::bail!("[system_prompts.{}] sets both content and file", name)
+            }
+            (None, None) => anyhow::bail!("[system_prompts.{}] needs content or file", name),
+        };
+        Ok(SystemPrompt {
+            name: name.to_string(),
+            content,
+        })
+    }
+}
@@ -1,14 +1,57 @@
 //! Beautiful terminal UI components
 
 use colored::*;
-use dialoguer::{theme::ColorfulTheme, Input};
+use dialoguer::{console::Term, theme::ColorfulTheme, Input, Select};
 use indicatif::{ProgressBar, ProgressStyle};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
 use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// The user pressed Ctrl-C
+#[derive(Debug, thiserror::Error)]
+#[error("Interrupted")]
+pub struct Interrupted;
+
+/// Exit status after Ctrl-C, matching what shells report for SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Resolve when Ctrl-C is pressed.
+///
+/// Once a SIGINT handler is installed it stays installed, so a Ctrl-C that
+/// no caller is waiting for exits with [`EXIT_INTERRUPTED`], as it would
+/// have without the handler, instead of being ignored.
+pub async fn ctrl_c() {
+    static INTERRUPTS: OnceLock<broadcast::Sender<()>> = OnceLock::new();
+    let sender = INTERRUPTS.get_or_init(|| {
+        let (sender, _) = broadcast::channel(1);
+        let waiters = sender.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if waiters.send(()).is_err() {
+                    let term = Term::stderr();
+                    if term.is_term() {
+                        let _ = term.show_cursor();
+                    }
+                    eprintln!();
+                    std::process::exit(EXIT_INTERRUPTED);
+                }
+            }
+        });
+        sender
+    });
+    let mut interrupts = sender.subscribe();
+    let _ = interrupts.recv().await;
+}
+
+/// Answer that stops a clarification round and refines with the answers so far
+pub const STOP_ASKING: &str = "/done";
 
 /// Terminal output helper
 pub struct UI {
     color_enabled: bool,
     spinner_enabled: bool,
+    active_spinner: Mutex<Option<ProgressBar>>,
 }
 
 impl UI {
@@ -16,9 +59,15 @@ impl UI {
         Self {
             color_enabled,
             spinner_enabled,
+            active_spinner: Mutex::new(None),
         }
     }
 
+    /// Whether output is styled
+    pub fn color_enabled(&self) -> bool {
+        self.color_enabled
+    }
+
     /// Create a spinner for long-running operations
     pub fn spinner(&self, message: &str) -> Option<ProgressBar> {
         if !self.spinner_enabled {
@@ -35,9 +84,62 @@ impl UI {
         );
         pb.set_message(message.to_string());
         pb.enable_steady_tick(Duration::from_millis(80));
+        if let Ok(mut active) = self.active_spinner.lock() {
+            *active = Some(pb.clone());
+        }
         Some(pb)
     }
 
+    /// Update the message of the running spinner, or print it as a warning
+    /// when no spinner is active
+    pub fn spinner_status(&self, message: &str) {
+        let active = self
+            .active_spinner
+            .lock()
+            .ok()
+            .and_then(|active| active.clone())
+            .filter(|pb| !pb.is_finished());
+
+        match active {
+            Some(pb) if self.color_enabled => pb.set_message(message.yellow().to_string()),
+            Some(pb) => pb.set_message(message.to_string()),
+            None => self.warning(message),
+        }
+    }
+
+    /// Clear the running spinner, if any
+    pub fn finish_spinner(&self) {
+        let active = self.active_spinner.lock().ok().and_then(|mut a| a.take());
+        if let Some(pb) = active {
+            pb.finish_and_clear();
+        }
+    }
+
+    /// Put the terminal back in order after Ctrl-C: clear the running
+    /// spinner, end any partial line and show the cursor again
+    pub fn reset_after_interrupt(&self) {
+        let active = self.active_spinner.lock().ok().and_then(|mut a| a.take());
+        if let Some(pb) = active {
+            pb.finish_and_clear();
+        }
+        let term = Term::stderr();
+        if term.is_term() {
+            let _ = term.show_cursor();
+        }
+        eprintln!();
+    }
+
+    /// Print a fragment of streamed output without a trailing newline
+    pub fn stream_text(&self, text: &str) {
+        let mut stderr = std::io::stderr();
+        if self.color_enabled {
+            let _ = write!(stderr, "{}", text.bright_white());
+        } else {
+            let _ = write!(stderr, "{}", text);
+        }
+        let _ = stderr.flush();
+    }
+
     /// Print a success message
     pub fn success(&self, message: &str) {
         if self.color_enabled {
@@ -83,6 +185,15 @@ impl UI {
         }
     }
 
+    /// Print an indented suggestion under the previous message
+    pub fn hint(&self, message: &str) {
+        if self.color_enabled {
+            eprintln!("  {} {}", "↳".bright_black(), message.bright_black());
+        } else {
+            eprintln!("  ↳ {}", message);
+        }
+    }
+
     /// Print a header
     pub fn header(&self, title: &str) {
         if self.color_enabled {
@@ -160,12 +271,27 @@ impl UI {
         eprintln!();
     }
 
-    /// Ask for clarification answers
-    pub fn ask_questions(&self, questions: &[String]) -> anyhow::Result<Vec<String>> {
-        self.header("Clarification Needed");
+    /// Ask for clarification answers, pushing each one onto `answers` as it
+    /// is given so a Ctrl-C ([`Interrupted`]) keeps the ones already answered.
+    /// An empty answer skips the question. Returns `false` if the user typed
+    /// [`STOP_ASKING`] to stop answering.
+    pub fn ask_questions(
+        &self,
+        round: u32,
+        questions: &[String],
+        answers: &mut Vec<String>,
+    ) -> anyhow::Result<bool> {
+        if round > 1 {
+            self.header(&format!("More Clarification Needed (round {})", round));
+        } else {
+            self.header("Clarification Needed");
+        }
+        self.hint(&format!(
+            "Press Enter to skip a question, or type {} to refine with the answers so far.",
+            STOP_ASKING
+        ));
         eprintln!();
 
-        let mut answers = Vec::new();
         let theme = ColorfulTheme::default();
 
         for (i, question) in questions.iter().enumerate() {
@@ -179,15 +305,48 @@ impl UI {
                 eprintln!("Q{}: {}", i + 1, question);
             }
 
-            let answer: String = Input::with_theme(&theme)
+            let answer: String = match Input::with_theme(&theme)
                 .with_prompt(format!("A{}", i + 1))
-                .interact_text()?;
+                .allow_empty(true)
+                .interact_text()
+            {
+                Ok(answer) => answer,
+                Err(dialoguer::Error::IO(e)) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    return Err(Interrupted.into());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            eprintln!();
 
+            if answer.trim() == STOP_ASKING {
+                return Ok(false);
+            }
             answers.push(answer);
-            eprintln!();
         }
 
-        Ok(answers)
+        Ok(true)
+    }
+
+    /// Let the user pick one of `items`, or none. Ctrl-C returns
+    /// [`Interrupted`].
+    pub fn choose(&self, prompt: &str, items: &[String]) -> anyhow::Result<Option<usize>> {
+        let mut options = items.to_vec();
+        options.push("None of these".to_string());
+
+        let choice = match Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .items(&options)
+            .default(0)
+            .interact_opt()
+        {
+            Ok(choice) => choice,
+            Err(dialoguer::Error::IO(e)) if e.kind() == std::io::ErrorKind::Interrupted => {
+                return Err(Interrupted.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(choice.filter(|i| *i < items.len()))
     }
 
     /// Print a key-value pair
@@ -0,0 +1,176 @@
+//! End-to-end runs of the `prep` binary against offline providers
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A scratch home for one test: config, data and cache directories that
+/// keep the run away from the user's own files
+struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("prep-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("config/prep")).unwrap();
+        Self { root }
+    }
+
+    fn write_config(&self, contents: &str) {
+        std::fs::write(self.root.join("config/prep/config.toml"), contents).unwrap();
+    }
+
+    fn history_db(&self) -> PathBuf {
+        self.root.join("data/prep/history.db")
+    }
+
+    /// Run prep non-interactively: stdin is not a terminal, so clarifying
+    /// questions are reported rather than asked
+    fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_prep"))
+            .args(args)
+            .env_clear()
+            .env("HOME", &self.root)
+            .env("XDG_CONFIG_HOME", self.root.join("config"))
+            .env("XDG_DATA_HOME", self.root.join("data"))
+            .env("XDG_CACHE_HOME", self.root.join("cache"))
+            .env("NO_COLOR", "1")
+            .stdin(Stdio::null())
+            .output()
+            .expect("failed to run prep")
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn mock_clarification_round_prints_draft_and_records_history() {
+    let sandbox = Sandbox::new("mock");
+    sandbox.write_config(&format!(
+        "[providers.mock]\nkind = \"mock\"\nfixture = {:?}\n",
+        fixture("clarify.toml")
+    ));
+
+    let output = sandbox.run(&["--provider", "mock", "write a poem"]);
+    assert!(output.status.success(), "prep failed: {}", stderr(&output));
+
+    // Unanswered questions leave the draft from the first response
+    assert_eq!(stdout(&output), "Write a haiku.\n");
+    let stderr = stderr(&output);
+    assert!(stderr.contains("Clarification needed but running non-interactively"));
+    assert!(stderr.contains("What season should it be about?"));
+    assert!(stderr.contains("What tone should it have?"));
+
+    let db = rusqlite::Connection::open(sandbox.history_db()).unwrap();
+    let row: (String, String, String, String) = db
+        .query_row(
+            "SELECT original_prompt, refined_prompt, provider, model FROM history",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        row,
+        (
+            "write a poem".to_string(),
+            "Write a haiku.".to_string(),
+            "mock".to_string(),
+            "mock".to_string()
+        )
+    );
+}
+
+#[test]
+fn history_records_the_prompt_before_its_template() {
+    let sandbox = Sandbox::new("template");
+    sandbox.write_config(&format!(
+        "[providers.mock]\nkind = \"mock\"\nfixture = {0:?}\n\
+         [providers.other]\nkind = \"mock\"\nfixture = {0:?}\n",
+        fixture("clarify.toml")
+    ));
+
+    let single = sandbox.run(&["--provider", "mock", "--template", "code", "parse CSV"]);
+    assert!(single.status.success(), "prep failed: {}", stderr(&single));
+    let compare = sandbox.run(&["--compare", "mock,other", "--template", "code", "parse CSV"]);
+    assert!(
+        compare.status.success(),
+        "prep failed: {}",
+        stderr(&compare)
+    );
+
+    let db = rusqlite::Connection::open(sandbox.history_db()).unwrap();
+    let mut query = db
+        .prepare("SELECT provider, original_prompt FROM history ORDER BY id")
+        .unwrap();
+    let rows: Vec<(String, String)> = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        rows,
+        [
+            ("mock".to_string(), "parse CSV".to_string()),
+            ("compare".to_string(), "parse CSV".to_string())
+        ]
+    );
+}
+
+#[test]
+fn replayed_cassette_makes_no_network_requests() {
+    // Every request is routed through this proxy, so a connection to it means
+    // prep went to the network instead of the cassette
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    proxy.set_nonblocking(true).unwrap();
+
+    let sandbox = Sandbox::new("replay");
+    sandbox.write_config(&format!(
+        "[providers.local]\n\
+         kind = \"ollama\"\n\
+         endpoint = \"http://prep-cassette.invalid:11434\"\n\
+         model = \"llama3.2\"\n\
+         max_attempts = 1\n\
+         read_timeout_secs = 5\n\
+         proxy = \"http://{}\"\n",
+        proxy.local_addr().unwrap()
+    ));
+
+    let cassette = fixture("refine.cassette.json");
+    let output = sandbox.run(&[
+        "--provider",
+        "local",
+        "--replay",
+        cassette.to_str().unwrap(),
+        "write a poem about fall",
+    ]);
+    assert!(output.status.success(), "prep failed: {}", stderr(&output));
+
+    assert_eq!(stdout(&output), "Write a haiku about autumn leaves.\n");
+    assert!(stderr(&output).contains("Should it follow the 5-7-5 syllable pattern?"));
+
+    match proxy.accept() {
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        Ok((_, peer)) => panic!("prep connected to the network from {}", peer),
+        Err(e) => panic!("failed to check the proxy: {}", e),
+    }
+}